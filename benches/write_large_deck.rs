@@ -0,0 +1,44 @@
+//! Times writing one deck with many notes and review histories, which is dominated by inserting the rows of
+//! the notes, cards and revlog tables.
+//!
+//! Run with `cargo bench --bench write_large_deck`.
+
+use genanki_rs::{basic_model, Card, Deck, Note, RevlogEntry};
+use std::io::Cursor;
+use std::time::Instant;
+
+const NOTES: usize = 20_000;
+const REVIEWS_PER_CARD: usize = 2;
+
+fn deck() -> Deck {
+    let mut deck = Deck::new(1, "Large deck", "");
+    for i in 0..NOTES {
+        let front = format!("Question {}", i);
+        let mut card = Card::new_with_review_data(0, false, 2, 0, 3, 100, 2500, 2, 2, 0);
+        card.review_history = (0..REVIEWS_PER_CARD)
+            .map(|review| {
+                let id = 1_600_000_000_000 + (i * REVIEWS_PER_CARD + review) as i64;
+                RevlogEntry::review(id, 3, 3, 1, 2500, 4000).unwrap()
+            })
+            .collect();
+        deck.add_note(
+            Note::new(basic_model(), vec![front.as_str(), "Answer"])
+                .unwrap()
+                .with_cards(vec![card]),
+        );
+    }
+    deck
+}
+
+fn main() {
+    let mut package = deck().into_package();
+    let mut apkg = Cursor::new(Vec::new());
+    let start = Instant::now();
+    package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+    println!(
+        "{} notes with {} reviews each: {:?}",
+        NOTES,
+        REVIEWS_PER_CARD,
+        start.elapsed()
+    );
+}
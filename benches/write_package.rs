@@ -0,0 +1,56 @@
+//! Times writing many small packages without media, which skips building the media map, against the
+//! general media path (taken here because of an empty media pool).
+//!
+//! Run with `cargo bench --bench write_package`.
+
+use genanki_rs::{basic_model, Deck, MediaPool, Note};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PACKAGES: usize = 200;
+const NOTES_PER_DECK: usize = 50;
+
+fn deck(id: i64) -> Deck {
+    let mut deck = Deck::new(id, &format!("Deck {}", id), "");
+    for i in 0..NOTES_PER_DECK {
+        let front = format!("Question {} of deck {}", i, id);
+        deck.add_note(Note::new(basic_model(), vec![front.as_str(), "Answer"]).unwrap());
+    }
+    deck
+}
+
+fn write_packages(media_pool: Option<&Arc<MediaPool>>) -> (Duration, Vec<Vec<u8>>) {
+    let mut outputs = Vec::with_capacity(PACKAGES);
+    let mut elapsed = Duration::ZERO;
+    for id in 0..PACKAGES as i64 {
+        let mut package = deck(id).into_package();
+        if let Some(pool) = media_pool {
+            package.set_media_pool(Arc::clone(pool));
+        }
+        let mut apkg = Cursor::new(Vec::new());
+        let start = Instant::now();
+        package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+        elapsed += start.elapsed();
+        outputs.push(apkg.into_inner());
+    }
+    (elapsed, outputs)
+}
+
+fn main() {
+    let pool = Arc::new(MediaPool::new());
+    // Warm up
+    write_packages(None);
+    let (general, general_outputs) = write_packages(Some(&pool));
+    let (fast, fast_outputs) = write_packages(None);
+    assert!(general_outputs == fast_outputs, "both paths have to write the same packages");
+    println!(
+        "{} packages of {} notes: without media {:?} ({:?} each), general media path {:?} ({:?} each)",
+        PACKAGES,
+        NOTES_PER_DECK,
+        fast,
+        fast / PACKAGES as u32,
+        general,
+        general / PACKAGES as u32
+    );
+}
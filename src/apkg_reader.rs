@@ -0,0 +1,479 @@
+use rusqlite::{Connection, MAIN_DB, params};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use zip::ZipArchive;
+
+use crate::collection_conf::SchedulerVersion;
+use crate::error::{database_error, json_error, zip_error};
+use crate::filtered_deck::{FilteredDeck, FilteredDeckTerm};
+use crate::proto::ProtoReader;
+use crate::{Card, ConfigEntry, DeckConfigEntry, Deck, Error, Field, MediaFile, Model, ModelType, Note, Package, RevlogEntry, Template};
+
+/// Collection files in the order they are preferred, `collection.anki2` is only a placeholder in
+/// packages that also contain a `collection.anki21`
+const COLLECTION_NAMES: [&str; 2] = ["collection.anki21", "collection.anki2"];
+
+/// Reads an `.apkg` archive into a `Package`, see `Package::read_from_reader`
+pub(crate) fn read_package<R: Read + Seek>(reader: R) -> Result<Package, Error> {
+    let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+    let conn = open_collection(&mut archive)?;
+    let media_files = read_media(&mut archive)?;
+
+    let (crt, ver, conf_json, models, decks, dconf_json): (i64, i64, String, String, String, String) = conn
+        .query_row("SELECT crt, ver, conf, models, decks, dconf FROM col", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        })
+        .map_err(database_error)?;
+    // Since version 16 note types, decks and collection settings are stored in their own tables
+    let modern = ver >= 16;
+    let (conf, models, decks, configs, deck_configs) = if modern {
+        let configs = read_config_entries(&conn)?;
+        let conf = configs
+            .iter()
+            .map(|config| (config.key.clone(), serde_json::from_slice(&config.val).unwrap_or(Value::Null)))
+            .collect();
+        (conf, read_notetypes(&conn)?, read_decks(&conn)?, configs, read_deck_configs(&conn)?)
+    } else {
+        (
+            serde_json::from_str(&conf_json).map_err(json_error)?,
+            serde_json::from_str(&models).map_err(json_error)?,
+            serde_json::from_str(&decks).map_err(json_error)?,
+            vec![],
+            vec![],
+        )
+    };
+    let models = parse_models(&models)?;
+
+    let mut regular_decks = Vec::new();
+    let mut filtered_decks = Vec::new();
+    for deck in decks.as_object().into_iter().flat_map(|decks| decks.values()) {
+        let id = deck["id"].as_i64().unwrap_or_default();
+        let name = deck["name"].as_str().unwrap_or_default();
+        if deck["dyn"].as_i64().unwrap_or_default() != 0 {
+            filtered_decks.push(parse_filtered_deck(id, name, deck));
+        } else {
+            let mut regular_deck = Deck::new(id, name, deck["desc"].as_str().unwrap_or_default());
+            regular_deck.set_config_id(deck["conf"].as_i64().unwrap_or(1));
+            regular_decks.push(regular_deck);
+        }
+    }
+    regular_decks.sort_by_key(|deck| deck.id);
+
+    let mut cards = read_cards(&conn)?;
+    let mut stmt = conn
+        .prepare("SELECT id, guid, mid, mod, usn, tags, flds, flags, sfld, csum, data FROM notes ORDER BY id")
+        .map_err(database_error)?;
+    let mut rows = stmt.query([]).map_err(database_error)?;
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let id: i64 = row.get(0).map_err(database_error)?;
+        let guid: String = row.get(1).map_err(database_error)?;
+        let mid: i64 = row.get(2).map_err(database_error)?;
+        let mod_time: i64 = row.get(3).map_err(database_error)?;
+        let usn: i32 = row.get(4).map_err(database_error)?;
+        let tags: String = row.get(5).map_err(database_error)?;
+        let fields: String = row.get(6).map_err(database_error)?;
+        let flags: i32 = row.get(7).map_err(database_error)?;
+        // The column has integer affinity, so numeric sort fields are stored as numbers
+        let sfld = match row.get_ref(8).map_err(database_error)? {
+            rusqlite::types::ValueRef::Integer(i) => i.to_string(),
+            rusqlite::types::ValueRef::Real(f) => f.to_string(),
+            rusqlite::types::ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+            _ => String::new(),
+        };
+        let csum: i64 = row.get(9).map_err(database_error)?;
+        let data: String = row.get(10).map_err(database_error)?;
+
+        let model = models
+            .get(&mid)
+            .ok_or_else(|| Error::InvalidPackage(format!("note {} uses the unknown note type {}", id, mid)))?;
+        let (deck_id, note_cards) = cards.remove(&id).unwrap_or_default();
+        let note = Note::new_with_cards(
+            model.clone(),
+            fields.split('\x1f').collect(),
+            note_cards,
+            Some(&guid),
+            Some(tags.split_whitespace().collect()),
+        )
+        .map_err(|e| e.context(format!("reading note {}", id)))?
+        .set_id(id)
+        .set_mod_time(mod_time)
+        .set_usn(usn)
+        .set_flags(flags)
+        .set_sfld(sfld)
+        .set_csum(csum)
+        .set_data(data)
+        // The collection may hold notes Anki would not import anymore, keep them as they are
+        .allow_empty_first_field();
+
+        let deck_index = match regular_decks.iter().position(|deck| deck.id == deck_id) {
+            Some(deck_index) => deck_index,
+            None => {
+                regular_decks.push(Deck::new(deck_id, &format!("Deck {}", deck_id), ""));
+                regular_decks.len() - 1
+            }
+        };
+        regular_decks[deck_index].add_note(note);
+    }
+
+    // The writer adds the default deck to every collection, so an empty one is not part of the package
+    regular_decks.retain(|deck| deck.id != 1 || !deck.notes().is_empty());
+
+    let mut package = Package::new_from_memory(regular_decks, media_files)?;
+    // The collection and deck options are kept as they are, settings set on the package are applied on top
+    if modern {
+        package.set_col_data(Some(crt), Some(ver), None, None, None, None, None, None, None);
+    } else {
+        package.set_col_data(Some(crt), Some(ver), None, None, None, Some(conf_json), None, None, Some(dconf_json));
+    }
+    for config in configs {
+        package.add_config_entry(config);
+    }
+    for deck_config in deck_configs {
+        package.add_deck_config_entry(deck_config);
+    }
+    if let Some(scheduler_version) = parse_scheduler_version(&conf) {
+        package.set_scheduler_version(scheduler_version);
+    }
+    for filtered_deck in filtered_decks {
+        package.add_filtered_deck(filtered_deck);
+    }
+    Ok(package)
+}
+
+/// Opens the collection of the archive as an in-memory database
+fn open_collection<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Connection, Error> {
+    let name = COLLECTION_NAMES
+        .iter()
+        .find(|&&name| archive.by_name(name).is_ok())
+        .ok_or_else(|| Error::InvalidPackage("the archive contains no collection".to_string()))?;
+    let collection = archive.by_name(name).map_err(zip_error)?;
+    let size = collection.size() as usize;
+    let mut conn = Connection::open_in_memory().map_err(database_error)?;
+    conn.deserialize_read_exact(MAIN_DB, collection, size, true)
+        .map_err(database_error)?;
+    Ok(conn)
+}
+
+/// Reads the media files listed in the `media` entry of the archive, if it has one
+fn read_media<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Vec<MediaFile>, Error> {
+    let media_map: HashMap<String, String> = match archive.by_name("media") {
+        Ok(media) => serde_json::from_reader(media).map_err(json_error)?,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut media_map: Vec<(String, String)> = media_map.into_iter().collect();
+    media_map.sort_by_key(|(idx, _)| idx.parse::<usize>().unwrap_or(usize::MAX));
+    let mut media_files = Vec::with_capacity(media_map.len());
+    for (idx, name) in media_map {
+        let mut bytes = Vec::new();
+        archive
+            .by_name(&idx)
+            .map_err(zip_error)?
+            .read_to_end(&mut bytes)?;
+        media_files.push(MediaFile::new_from_bytes(&bytes, &name));
+    }
+    Ok(media_files)
+}
+
+/// Returns the note types of the `notetypes`, `fields` and `templates` tables in the format of `col.models`
+fn read_notetypes(conn: &Connection) -> Result<Value, Error> {
+    let mut fields: HashMap<i64, Vec<Value>> = HashMap::new();
+    let mut stmt = conn
+        .prepare("SELECT ntid, ord, name, config FROM fields ORDER BY ntid, ord")
+        .map_err(database_error)?;
+    let mut rows = stmt.query([]).map_err(database_error)?;
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let config: Vec<u8> = row.get(3).map_err(database_error)?;
+        let config = ProtoReader::new(&config)?;
+        fields.entry(row.get(0).map_err(database_error)?).or_default().push(json!({
+            "name": row.get::<_, String>(2).map_err(database_error)?,
+            "ord": row.get::<_, i64>(1).map_err(database_error)?,
+            "sticky": config.bool(1),
+            "rtl": config.bool(2),
+            "font": config.string(3),
+            "size": config.int(4),
+            "plainText": config.bool(6),
+        }));
+    }
+
+    let mut templates: HashMap<i64, Vec<Value>> = HashMap::new();
+    let mut stmt = conn
+        .prepare("SELECT ntid, ord, name, config FROM templates ORDER BY ntid, ord")
+        .map_err(database_error)?;
+    let mut rows = stmt.query([]).map_err(database_error)?;
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let config: Vec<u8> = row.get(3).map_err(database_error)?;
+        let config = ProtoReader::new(&config)?;
+        let did = config.int(5);
+        templates.entry(row.get(0).map_err(database_error)?).or_default().push(json!({
+            "name": row.get::<_, String>(2).map_err(database_error)?,
+            "ord": row.get::<_, i64>(1).map_err(database_error)?,
+            "qfmt": config.string(1),
+            "afmt": config.string(2),
+            "bqfmt": config.string(3),
+            "bafmt": config.string(4),
+            "did": (did != 0).then_some(did),
+        }));
+    }
+
+    let mut notetypes = serde_json::Map::new();
+    let mut stmt = conn.prepare("SELECT id, name, config FROM notetypes").map_err(database_error)?;
+    let mut rows = stmt.query([]).map_err(database_error)?;
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let id: i64 = row.get(0).map_err(database_error)?;
+        let config: Vec<u8> = row.get(2).map_err(database_error)?;
+        let config = ProtoReader::new(&config)?;
+        notetypes.insert(id.to_string(), json!({
+            "id": id,
+            "name": row.get::<_, String>(1).map_err(database_error)?,
+            "type": config.int(1),
+            "sortf": config.int(2),
+            "css": config.string(3),
+            "latexPre": config.string(5),
+            "latexPost": config.string(6),
+            "latexsvg": config.bool(7),
+            "flds": fields.remove(&id).unwrap_or_default(),
+            "tmpls": templates.remove(&id).unwrap_or_default(),
+        }));
+    }
+    Ok(Value::Object(notetypes))
+}
+
+/// Returns the decks of the `decks` table in the format of `col.decks`
+fn read_decks(conn: &Connection) -> Result<Value, Error> {
+    let mut decks = serde_json::Map::new();
+    let mut stmt = conn.prepare("SELECT id, name, kind FROM decks").map_err(database_error)?;
+    let mut rows = stmt.query([]).map_err(database_error)?;
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let id: i64 = row.get(0).map_err(database_error)?;
+        let name = row.get::<_, String>(1).map_err(database_error)?.replace('\x1f', "::");
+        let kind: Vec<u8> = row.get(2).map_err(database_error)?;
+        let kind = ProtoReader::new(&kind)?;
+        let deck = match (kind.message(1), kind.message(2)) {
+            (Some(normal), _) => {
+                let normal = ProtoReader::new(normal)?;
+                json!({ "id": id, "name": name, "desc": normal.string(4), "conf": normal.int(1), "dyn": 0 })
+            }
+            (None, Some(filtered)) => {
+                let filtered = ProtoReader::new(filtered)?;
+                let mut terms = vec![];
+                for term in filtered.messages(2) {
+                    let term = ProtoReader::new(term)?;
+                    terms.push(json!([term.string(1), term.int(2), term.int(3)]));
+                }
+                json!({ "id": id, "name": name, "dyn": 1, "resched": filtered.bool(1), "terms": terms })
+            }
+            (None, None) => return Err(Error::InvalidPackage(format!("deck {} is neither regular nor filtered", id))),
+        };
+        decks.insert(id.to_string(), deck);
+    }
+    Ok(Value::Object(decks))
+}
+
+/// Returns the rows of the `config` table, which holds the collection settings since version 16
+fn read_config_entries(conn: &Connection) -> Result<Vec<ConfigEntry>, Error> {
+    let mut stmt = conn
+        .prepare("SELECT key, usn, mtime_secs, val FROM config ORDER BY key")
+        .map_err(database_error)?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok(ConfigEntry {
+                key: row.get(0)?,
+                usn: row.get(1)?,
+                mtime_secs: row.get(2)?,
+                val: row.get(3)?,
+            })
+        })
+        .map_err(database_error)?;
+    entries.collect::<Result<_, _>>().map_err(database_error)
+}
+
+/// Returns the rows of the `deck_config` table, which holds the deck options presets since version 16
+fn read_deck_configs(conn: &Connection) -> Result<Vec<DeckConfigEntry>, Error> {
+    let mut stmt = conn
+        .prepare("SELECT id, name, mtime_secs, usn, config FROM deck_config ORDER BY id")
+        .map_err(database_error)?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok(DeckConfigEntry {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                mtime_secs: row.get(2)?,
+                usn: row.get(3)?,
+                config_blob: row.get(4)?,
+            })
+        })
+        .map_err(database_error)?;
+    entries.collect::<Result<_, _>>().map_err(database_error)
+}
+
+/// Returns the models of `col.models` by id
+fn parse_models(models: &Value) -> Result<HashMap<i64, Model>, Error> {
+    let mut parsed = HashMap::new();
+    for model in models.as_object().into_iter().flat_map(|models| models.values()) {
+        let str_of = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        let id = model["id"]
+            .as_i64()
+            .or_else(|| model["id"].as_str().and_then(|id| id.parse().ok()))
+            .ok_or_else(|| Error::InvalidPackage(format!("note type {} has no id", model["name"])))?;
+        let fields = model["flds"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|field| {
+                let mut builder = Field::new(&str_of(&field["name"]))
+                    .sticky(field["sticky"].as_bool().unwrap_or_default())
+                    .rtl(field["rtl"].as_bool().unwrap_or_default())
+                    .plain_text(field["plainText"].as_bool().unwrap_or_default());
+                if let Some(font) = field["font"].as_str() {
+                    builder = builder.font(font);
+                }
+                if let Some(size) = field["size"].as_i64() {
+                    builder = builder.size(size);
+                }
+                builder
+            })
+            .collect();
+        let templates = model["tmpls"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|template| {
+                let builder = Template::new(&str_of(&template["name"]))
+                    .qfmt(&str_of(&template["qfmt"]))
+                    .afmt(&str_of(&template["afmt"]))
+                    .bqfmt(&str_of(&template["bqfmt"]))
+                    .bafmt(&str_of(&template["bafmt"]));
+                match template["did"].as_u64() {
+                    Some(did) => builder.did(did as usize),
+                    None => builder,
+                }
+            })
+            .collect();
+        let model_type = match model["type"].as_i64() {
+            Some(1) => ModelType::Cloze,
+            _ => ModelType::FrontBack,
+        };
+        parsed.insert(
+            id,
+            Model::new_with_options(
+                id,
+                &str_of(&model["name"]),
+                fields,
+                templates,
+                model["css"].as_str(),
+                Some(model_type),
+                model["latexPre"].as_str(),
+                model["latexPost"].as_str(),
+                model["sortf"].as_i64(),
+                model["latexsvg"].as_bool(),
+            ),
+        );
+    }
+    Ok(parsed)
+}
+
+/// Returns the cards and their review history by note id, together with the home deck of the note
+///
+/// Cards of a note that sit in a filtered deck keep it as their original deck, the note is put
+/// into their home deck.
+fn read_cards(conn: &Connection) -> Result<HashMap<i64, (i64, Vec<Card>)>, Error> {
+    let mut revlog: HashMap<i64, Vec<RevlogEntry>> = HashMap::new();
+    let mut stmt = conn
+        .prepare("SELECT id, cid, usn, ease, ivl, lastIvl, factor, time, type FROM revlog ORDER BY id")
+        .map_err(database_error)?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(1)?,
+                RevlogEntry {
+                    id: row.get(0)?,
+                    usn: row.get(2)?,
+                    ease: row.get(3)?,
+                    ivl: row.get(4)?,
+                    last_ivl: row.get(5)?,
+                    factor: row.get(6)?,
+                    time: row.get(7)?,
+                    review_type: row.get(8)?,
+                },
+            ))
+        })
+        .map_err(database_error)?;
+    for entry in entries {
+        let (cid, entry) = entry.map_err(database_error)?;
+        revlog.entry(cid).or_default().push(entry);
+    }
+
+    let mut cards: HashMap<i64, (i64, Vec<Card>)> = HashMap::new();
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, nid, did, ord, mod, usn, type, queue, due, ivl, factor, reps, lapses, left, odue, odid, data, flags \
+             FROM cards ORDER BY nid, ord",
+        )
+        .map_err(database_error)?;
+    let mut rows = stmt.query(params![]).map_err(database_error)?;
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let get_i32 = |idx| row.get::<_, i32>(idx).map_err(database_error);
+        let id: i64 = row.get(0).map_err(database_error)?;
+        let nid: i64 = row.get(1).map_err(database_error)?;
+        let did: i64 = row.get(2).map_err(database_error)?;
+        let original_due: i64 = row.get(14).map_err(database_error)?;
+        let original_deck_id: i64 = row.get(15).map_err(database_error)?;
+        let data: String = row.get(16).map_err(database_error)?;
+        let queue = get_i32(7)?;
+        let mut card = Card::new_with_review_history(
+            row.get(3).map_err(database_error)?,
+            queue == -1,
+            get_i32(11)?,
+            get_i32(12)?,
+            get_i32(9)?,
+            row.get(8).map_err(database_error)?,
+            get_i32(10)?,
+            get_i32(6)?,
+            queue,
+            get_i32(13)?,
+            revlog.remove(&id).unwrap_or_default(),
+            (!data.is_empty()).then_some(data),
+        )
+        .set_usn(get_i32(5)?)
+        .set_mod_time(row.get(4).map_err(database_error)?);
+        card.custom_card_id = Some(id);
+        card.flags = get_i32(17)?;
+        let home_deck_id = if original_deck_id != 0 {
+            card = card.set_original_deck(original_deck_id, original_due).set_deck(did);
+            original_deck_id
+        } else {
+            did
+        };
+        cards
+            .entry(nid)
+            .or_insert_with(|| (home_deck_id, Vec::new()))
+            .1
+            .push(card);
+    }
+    Ok(cards)
+}
+
+fn parse_filtered_deck(id: i64, name: &str, deck: &Value) -> FilteredDeck {
+    let mut filtered_deck = FilteredDeck::new(id, name)
+        .reschedule(deck["resched"].as_bool().unwrap_or(true));
+    filtered_deck.description = deck["desc"].as_str().unwrap_or_default().to_string();
+    for term in deck["terms"].as_array().into_iter().flatten() {
+        filtered_deck = filtered_deck.term(FilteredDeckTerm::new(
+            term[0].as_str().unwrap_or_default(),
+            term[1].as_u64().unwrap_or_default() as u32,
+            term[2].as_i64().unwrap_or_default(),
+        ));
+    }
+    filtered_deck
+}
+
+/// Returns the scheduler version selected in `col.conf`, if it selects one
+fn parse_scheduler_version(conf: &Value) -> Option<SchedulerVersion> {
+    match (conf["schedVer"].as_i64()?, conf["sched2021"].as_bool().unwrap_or(false)) {
+        (1, _) => Some(SchedulerVersion::V1),
+        (2, false) => Some(SchedulerVersion::V2),
+        (2, true) => Some(SchedulerVersion::V3),
+        _ => None,
+    }
+}
@@ -18,6 +18,7 @@ use crate::db_entries::Fld;
 /// * `rtl` - `false`
 /// * `font` - `Liberation Sans`
 /// * `size` - `20`
+/// * `plain_text` - `false`
 #[derive(Clone)]
 pub struct Field {
     name: String,
@@ -25,6 +26,7 @@ pub struct Field {
     rtl: Option<bool>,
     font: Option<String>,
     size: Option<i64>,
+    plain_text: Option<bool>,
 }
 
 impl Field {
@@ -36,6 +38,7 @@ impl Field {
             rtl: None,
             font: None,
             size: None,
+            plain_text: None,
         }
     }
 
@@ -63,6 +66,13 @@ impl Field {
         self
     }
 
+    /// Sets whether the editor of the currently created `Field` is a plain text editor instead of the rich
+    /// text editor, which keeps e.g. code or JSON as it was typed
+    pub fn plain_text(mut self, value: bool) -> Self {
+        self.plain_text = Some(value);
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -78,6 +88,7 @@ impl Into<Fld> for Field {
             ord: 0,
             font: self.font.unwrap_or("Liberation Sans".to_string()),
             size: self.size.unwrap_or(20),
+            plain_text: self.plain_text.unwrap_or(false),
         }
     }
 }
@@ -1,4 +1,5 @@
 use crate::db_entries::Tmpl;
+use crate::Error;
 
 /// Template to be fed into a `Model`.
 /// A Template represents the structure of `Notes` (Flashcards) in the deck and can be created using
@@ -64,6 +65,56 @@ impl Template {
         self.bqfmt = Some(bqfmt.to_string());
         self
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the question format of the `Template`, empty if it wasn't set
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::Template;
+    ///
+    /// let template = Template::new("Card 1").qfmt("{{Question}}");
+    /// assert_eq!((template.get_qfmt(), template.get_afmt()), ("{{Question}}", ""));
+    /// ```
+    pub fn get_qfmt(&self) -> &str {
+        self.qfmt.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the answer format of the `Template`, empty if it wasn't set
+    pub fn get_afmt(&self) -> &str {
+        self.afmt.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the browser question format of the `Template`, empty if it wasn't set
+    pub fn get_bqfmt(&self) -> &str {
+        self.bqfmt.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the browser answer format of the `Template`, empty if it wasn't set
+    pub fn get_bafmt(&self) -> &str {
+        self.bafmt.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the deck id set for the `Template`, if any
+    pub fn get_did(&self) -> Option<usize> {
+        self.did
+    }
+
+    /// Changes the name of the `Template`
+    ///
+    /// Names have to be unique within a model, which is checked when the model is written.
+    ///
+    /// Returns `Err` if `name` is empty
+    pub fn rename(&mut self, name: &str) -> Result<(), Error> {
+        if name.trim().is_empty() {
+            return Err(Error::EmptyTemplateName);
+        }
+        self.name = name.to_string();
+        Ok(())
+    }
 }
 
 impl Into<Tmpl> for Template {
@@ -289,11 +289,102 @@ pub fn cloze_model() -> Model {
     )
 }
 
+const IMAGE_OCCLUSION_QFMT: &str = r#"{{#Header}}<div>{{Header}}</div>{{/Header}}
+<div style="display: none">{{cloze:Occlusion}}</div>
+<div id="err"></div>
+<div id="image-occlusion-container">
+    {{Image}}
+    <canvas id="image-occlusion-canvas"></canvas>
+</div>
+<script>
+try {
+    anki.imageOcclusion.setup();
+} catch (exc) {
+    document.getElementById("err").innerHTML = `Error loading image occlusion. Is your Anki version up to date?<br><br>${exc}`;
+}
+</script>
+"#;
+
+/// Returns a `Model` for image occlusion cards, matching Anki's built-in "Image Occlusion" note type.
+///
+/// The `Occlusion` field holds the cloze-style masks (e.g. `{{c1::image-occlusion:rect:left=.1:top=.2:width=.3:height=.1}}`)
+/// and the `Image` field the `<img>` tag of the occluded image.
+///
+/// ```rust
+/// use genanki_rs::image_occlusion_model;
+/// let my_model = image_occlusion_model();
+/// ```
+pub fn image_occlusion_model() -> Model {
+    Model::new_with_options(
+        1706023153,
+        "Image Occlusion (genanki)",
+        vec![
+            Field::new("Occlusion"),
+            Field::new("Image"),
+            Field::new("Header"),
+            Field::new("Back Extra"),
+            Field::new("Comments"),
+        ],
+        vec![
+            Template::new("Image Occlusion")
+                .qfmt(IMAGE_OCCLUSION_QFMT)
+                .afmt(&format!(
+                    "{}\n<div><button id=\"toggle\">Toggle Masks</button></div>\n{{{{#Back Extra}}}}<div>{{{{Back Extra}}}}</div>{{{{/Back Extra}}}}\n",
+                    IMAGE_OCCLUSION_QFMT
+                )),
+        ],
+        Some(
+            "#image-occlusion-canvas {\n --inactive-shape-color: #ffeba2;\n --active-shape-color: #ff8e8e;\n --inactive-shape-border: 1px #212121;\n --active-shape-border: 1px #212121;\n --highlight-shape-color: #ff8e8e00;\n --highlight-shape-border: 1px #ff8e8e;\n}\n\n.card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
+        ),
+        Some(ModelType::Cloze),
+        None,
+        None,
+        None,
+        None,
+    )
+}
+
+/// The note types that ship with Anki, see [`Model::from_anki_builtin`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuiltinModel {
+    /// Front/Back, same as [`basic_model`]
+    Basic,
+    /// Front/Back and Back/Front, same as [`basic_and_reversed_card_model`]
+    BasicReversed,
+    /// Front/Back and optionally Back/Front, same as [`basic_optional_reversed_card_model`]
+    BasicOptionalReversed,
+    /// Type in the answer, same as [`basic_type_in_the_answer_model`]
+    BasicTypeIn,
+    /// Clozes, same as [`cloze_model`]
+    Cloze,
+    /// Image occlusion, same as [`image_occlusion_model`]
+    ImageOcclusion,
+}
+
+impl Model {
+    /// Returns the `Model` for one of Anki's built-in note types
+    ///
+    /// ```rust
+    /// use genanki_rs::{basic_model, BuiltinModel, Model};
+    /// let my_model = Model::from_anki_builtin(BuiltinModel::Basic);
+    /// assert_eq!(my_model.id, basic_model().id);
+    /// ```
+    pub fn from_anki_builtin(builtin: BuiltinModel) -> Model {
+        match builtin {
+            BuiltinModel::Basic => basic_model(),
+            BuiltinModel::BasicReversed => basic_and_reversed_card_model(),
+            BuiltinModel::BasicOptionalReversed => basic_optional_reversed_card_model(),
+            BuiltinModel::BasicTypeIn => basic_type_in_the_answer_model(),
+            BuiltinModel::Cloze => cloze_model(),
+            BuiltinModel::ImageOcclusion => image_occlusion_model(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::{Deck, Note};
     use super::*;
-    use tempfile::NamedTempFile;
 
     #[test]
     fn builtin_models() {
@@ -327,7 +418,34 @@ mod tests {
             .unwrap(),
         );
 
-        let out_file = NamedTempFile::new().unwrap().into_temp_path();
-        my_deck.write_to_file(out_file.to_str().unwrap()).unwrap();
+        #[cfg(feature = "fs")]
+        {
+            let out_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+            my_deck.write_to_file(out_file.to_str().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn field_names_with_spaces_in_sections() {
+        let model = Model::from_anki_builtin(BuiltinModel::BasicOptionalReversed);
+        assert!(model.req().is_ok());
+        let note = Note::new(model, vec!["France", "Paris", "y"]).unwrap();
+        assert_eq!(note.cards().len(), 2);
+    }
+
+    #[test]
+    fn image_occlusion_cards() {
+        let note = Note::new(
+            Model::from_anki_builtin(BuiltinModel::ImageOcclusion),
+            vec![
+                "{{c1::image-occlusion:rect:left=.1:top=.1:width=.2:height=.2}}{{c2::image-occlusion:rect:left=.5:top=.5:width=.2:height=.2}}",
+                r#"<img src="map.png">"#,
+                "Capitals",
+                "",
+                "",
+            ],
+        )
+        .unwrap();
+        assert_eq!(note.cards().len(), 2);
     }
 }
@@ -1,6 +1,9 @@
 use rusqlite::{params, Transaction};
+use serde_json::{Map, Value};
 use std::ops::RangeFrom;
+use std::sync::{Arc, Mutex};
 
+use crate::util::{days_from_civil, parse_iso_date};
 use crate::{error::database_error, Error};
 
 /// Represents a single review log entry from Anki's revlog table
@@ -16,6 +19,185 @@ pub struct RevlogEntry {
     pub usn: i32,          // Update sequence number
 }
 
+impl RevlogEntry {
+    /// An answered review of type `review_type` (0=learn, 1=review, 2=relearn, 3=cram), see
+    /// [`RevlogEntry::learn`] for the other arguments
+    ///
+    /// Returns `Err` if `ease` isn't a button (1 to 4), `review_type` isn't one of the types above or `time`
+    /// is negative. Manual changes of the schedule are created with [`RevlogEntry::manual`], and the fields
+    /// can still be set directly for entries this doesn't cover.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{Error, RevlogEntry};
+    ///
+    /// let entry = RevlogEntry::new(1_700_000_000_000, 3, 30, 12, 2500, 6000, 1).unwrap();
+    /// assert_eq!((entry.ease, entry.review_type), (3, 1));
+    /// assert!(matches!(RevlogEntry::new(1_700_000_000_000, 5, 30, 12, 2500, 6000, 1), Err(Error::InvalidEase(5))));
+    /// assert!(matches!(
+    ///     RevlogEntry::new(1_700_000_000_000, 3, 30, 12, 2500, 6000, 4),
+    ///     Err(Error::InvalidReviewType(4))
+    /// ));
+    /// assert!(matches!(
+    ///     RevlogEntry::new(1_700_000_000_000, 3, 30, 12, 2500, -1, 1),
+    ///     Err(Error::NegativeReviewTime(-1))
+    /// ));
+    /// ```
+    pub fn new(
+        id: i64,
+        ease: i32,
+        ivl: i32,
+        last_ivl: i32,
+        factor: i32,
+        time: i32,
+        review_type: i32,
+    ) -> Result<Self, Error> {
+        if !(0..=3).contains(&review_type) {
+            return Err(Error::InvalidReviewType(review_type));
+        }
+        Self::answered(review_type, id, ease, ivl, last_ivl, factor, time)
+    }
+
+    /// A review of a card in learning (`review_type` 0)
+    ///
+    /// `id` is the time of the review in milliseconds, `ease` the button pressed (1 to 4) and `time` the
+    /// time taken to answer in milliseconds. Returns `Err` if `ease` isn't a button or `time` is negative.
+    pub fn learn(id: i64, ease: i32, ivl: i32, last_ivl: i32, factor: i32, time: i32) -> Result<Self, Error> {
+        Self::answered(0, id, ease, ivl, last_ivl, factor, time)
+    }
+
+    /// A review of a card in review (`review_type` 1), see [`RevlogEntry::learn`]
+    pub fn review(id: i64, ease: i32, ivl: i32, last_ivl: i32, factor: i32, time: i32) -> Result<Self, Error> {
+        Self::answered(1, id, ease, ivl, last_ivl, factor, time)
+    }
+
+    /// A review of a card relearning after a lapse (`review_type` 2), see [`RevlogEntry::learn`]
+    pub fn relearn(id: i64, ease: i32, ivl: i32, last_ivl: i32, factor: i32, time: i32) -> Result<Self, Error> {
+        Self::answered(2, id, ease, ivl, last_ivl, factor, time)
+    }
+
+    /// A review in a filtered deck (`review_type` 3), see [`RevlogEntry::learn`]
+    pub fn cram(id: i64, ease: i32, ivl: i32, last_ivl: i32, factor: i32, time: i32) -> Result<Self, Error> {
+        Self::answered(3, id, ease, ivl, last_ivl, factor, time)
+    }
+
+    /// A manual change of the card's schedule (`review_type` 4), e.g. with "Set Due Date", which has no
+    /// button pressed and no answer time
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::RevlogEntry;
+    ///
+    /// let entry = RevlogEntry::manual(1_700_000_000_000, 30, 12, 2500);
+    /// assert_eq!((entry.review_type, entry.ease, entry.time), (4, 0, 0));
+    /// assert!(RevlogEntry::review(1_700_000_000_000, 0, 30, 12, 2500, 6000).is_err());
+    /// ```
+    pub fn manual(id: i64, ivl: i32, last_ivl: i32, factor: i32) -> Self {
+        Self {
+            id,
+            ease: 0,
+            ivl,
+            last_ivl,
+            factor,
+            time: 0,
+            review_type: 4,
+            usn: -1,
+        }
+    }
+
+    fn answered(
+        review_type: i32,
+        id: i64,
+        ease: i32,
+        ivl: i32,
+        last_ivl: i32,
+        factor: i32,
+        time: i32,
+    ) -> Result<Self, Error> {
+        if !(1..=4).contains(&ease) {
+            return Err(Error::InvalidEase(ease));
+        }
+        if time < 0 {
+            return Err(Error::NegativeReviewTime(time));
+        }
+        Ok(Self {
+            id,
+            ease,
+            ivl,
+            last_ivl,
+            factor,
+            time,
+            review_type,
+            usn: -1,
+        })
+    }
+}
+
+/// FSRS memory state of a card, stored in the card's `data` JSON
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FsrsState {
+    /// Days until the recall probability drops to 90% (`s`)
+    pub stability: f32,
+    /// Difficulty between 1 and 10 (`d`)
+    pub difficulty: f32,
+    /// Retention the card was scheduled for, if it was recorded (`dr`)
+    pub desired_retention: Option<f32>,
+}
+
+/// Lazily produces the review log entries of a card, see [`Card::with_review_source`]
+pub type ReviewSource = Box<dyn FnMut() -> Option<RevlogEntry> + Send>;
+
+/// Colored flag of a card, as shown in Anki's browser
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Flag {
+    #[default]
+    None,
+    Red,
+    Orange,
+    Green,
+    Blue,
+    Pink,
+    Turquoise,
+    Purple,
+}
+
+impl Flag {
+    /// Returns the flag stored in the lowest 3 bits of a `flags` column value
+    pub fn from_flags(flags: i32) -> Self {
+        match flags & 0b111 {
+            1 => Flag::Red,
+            2 => Flag::Orange,
+            3 => Flag::Green,
+            4 => Flag::Blue,
+            5 => Flag::Pink,
+            6 => Flag::Turquoise,
+            7 => Flag::Purple,
+            _ => Flag::None,
+        }
+    }
+}
+
+impl From<Flag> for i32 {
+    fn from(flag: Flag) -> Self {
+        flag as i32
+    }
+}
+
+/// Whether a card is suspended, buried or in one of the normal queues, stored as a negative `queue`
+/// for the former
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CardQueueState {
+    /// In the queue of its card type
+    #[default]
+    Normal,
+    /// Suspended by the user (queue -1)
+    Suspended,
+    /// Buried automatically because a sibling was studied (queue -2)
+    SchedBuried,
+    /// Buried by the user (queue -3)
+    UserBuried,
+}
+
 #[derive(Clone)]
 pub struct Card {
     pub ord: i64,
@@ -34,6 +216,11 @@ pub struct Card {
     pub custom_card_id: Option<i64>, // Custom card ID to use instead of generated one
     pub usn: i32,              // Update sequence number (default: -1)
     pub mod_time: Option<i64>, // Original modification timestamp (preserves roundtrip)
+    pub deck_id: Option<i64>,          // Deck (did) of the card if it isn't the deck of its note, e.g. a filtered deck
+    pub original_deck_id: Option<i64>, // Home deck (odid) of a card that sits in a filtered deck
+    pub original_due: Option<i64>,     // Due date (odue) in the home deck of a card in a filtered deck
+    pub flags: i32,            // Flags, the lowest 3 bits are the colored flag (default: 0)
+    pub(crate) review_source: Option<Arc<Mutex<ReviewSource>>>, // Arc<_> so this can be clone and Sync
 }
 
 impl Card {
@@ -54,6 +241,11 @@ impl Card {
             custom_card_id: None,
             usn: -1,
             mod_time: None,
+            deck_id: None,
+            original_deck_id: None,
+            original_due: None,
+            flags: 0,
+            review_source: None,
         }
     }
 
@@ -86,6 +278,11 @@ impl Card {
             custom_card_id: None,
             usn: -1,
             mod_time: None,
+            deck_id: None,
+            original_deck_id: None,
+            original_due: None,
+            flags: 0,
+            review_source: None,
         }
     }
 
@@ -120,6 +317,11 @@ impl Card {
             custom_card_id: None,
             usn: -1,
             mod_time: None,
+            deck_id: None,
+            original_deck_id: None,
+            original_due: None,
+            flags: 0,
+            review_source: None,
         }
     }
 
@@ -141,6 +343,255 @@ impl Card {
         self
     }
 
+    /// Sets the home deck and the due date there for a card that sits in a filtered deck
+    ///
+    /// By default, odid and odue are 0 (the card is in its home deck).
+    /// Use this method to preserve cards of a filtered deck from imported Anki decks, so they can be
+    /// returned to their home deck.
+    pub fn set_original_deck(mut self, original_deck_id: i64, original_due: i64) -> Self {
+        self.original_deck_id = Some(original_deck_id);
+        self.original_due = Some(original_due);
+        self
+    }
+
+    /// Puts the card into the deck with id `deck_id` instead of the deck of its note, e.g. into the filtered
+    /// deck it was pulled into together with [`Card::set_original_deck`]
+    pub fn set_deck(mut self, deck_id: i64) -> Self {
+        self.deck_id = Some(deck_id);
+        self
+    }
+
+    /// Schedules the card as a review card due on `iso_date` (`YYYY-MM-DD`, UTC), for a collection
+    /// created at `crt` (seconds since the Unix epoch, see `Package::set_col_data`)
+    ///
+    /// Review cards without an interval or ease factor get an interval of 1 day and an ease of 250%.
+    /// Returns `Err` if `iso_date` is not a valid date.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::Card;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// // collection created 2025-01-01
+    /// let card = Card::new(0, false).due_on("2025-01-15", 1_735_689_600)?;
+    /// assert_eq!((card.card_type, card.due), (Some(2), Some(14)));
+    /// assert!(Card::new(0, false).due_on("2025-02-30", 1_735_689_600).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn due_on(mut self, iso_date: &str, crt: i64) -> Result<Self, Error> {
+        let due = day_offset(iso_date, crt)?;
+        self.card_type = Some(2);
+        self.queue = Some(2);
+        self.due = Some(due);
+        self.ivl = Some(self.ivl.unwrap_or(1).max(1));
+        self.factor = Some(self.factor.filter(|&factor| factor > 0).unwrap_or(2500));
+        Ok(self)
+    }
+
+    /// Sets when a card due on a day is due, to `date` (year, month and day, UTC) for a collection created at
+    /// `crt` (seconds since the Unix epoch, see `Package::set_col_data`)
+    ///
+    /// Unlike [`Card::due_on`] this keeps the card type and queue, which have to be those of a card whose
+    /// `due` counts days since the collection was created: a review card (type 2) in the review queue (2)
+    /// or suspended or buried (-1 to -3), or a (re)learning card (type 1 or 3) in the day learning queue
+    /// (3). Returns `Err` for other cards or if `date` doesn't exist. `due` can still be set directly.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::Card;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// // review card, collection created 2024-01-01
+    /// let card = Card::new_with_review_data(0, false, 5, 0, 30, 0, 2500, 2, 2, 0);
+    /// let card = card.set_due_date((2024, 6, 1), 1_704_067_200)?;
+    /// assert_eq!(card.due, Some(152));
+    /// assert!(Card::new(0, false).set_due_date((2024, 6, 1), 1_704_067_200).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_due_date(mut self, date: (i32, u32, u32), crt: i64) -> Result<Self, Error> {
+        let (card_type, queue) = (self.card_type.unwrap_or(0), self.queue.unwrap_or(0));
+        if !matches!((card_type, queue), (2, 2 | -1 | -2 | -3) | (1 | 3, 3)) {
+            return Err(Error::DueKindMismatch(card_type, queue, "days since the collection was created"));
+        }
+        let (year, month, day) = date;
+        let day = days_from_civil(year.into(), month.into(), day.into())
+            .ok_or_else(|| Error::InvalidDate(format!("{year:04}-{month:02}-{day:02}")))?;
+        self.due = Some(day - crt.div_euclid(86_400));
+        Ok(self)
+    }
+
+    /// Sets when a card in (re)learning is due, to `timestamp` in seconds since the Unix epoch
+    ///
+    /// Cards in the learning queue (1) are due at a time of the day instead of on a day, so the card has to
+    /// be a learning or relearning card (type 1 or 3) that isn't in the day learning queue (3). Returns `Err`
+    /// otherwise.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::Card;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let card = Card::new_with_review_data(0, false, 1, 0, 0, 0, 2500, 1, 1, 1001);
+    /// let card = card.set_due_timestamp(1_717_243_200)?;
+    /// assert_eq!(card.due, Some(1_717_243_200));
+    /// let review = Card::new_with_review_data(0, false, 5, 0, 30, 0, 2500, 2, 2, 0);
+    /// assert!(review.set_due_timestamp(1_717_243_200).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_due_timestamp(mut self, timestamp: i64) -> Result<Self, Error> {
+        let (card_type, queue) = (self.card_type.unwrap_or(0), self.queue.unwrap_or(0));
+        if !matches!(card_type, 1 | 3) || queue == 3 {
+            return Err(Error::DueKindMismatch(card_type, queue, "timestamps"));
+        }
+        self.due = Some(timestamp);
+        Ok(self)
+    }
+
+    /// Sets the colored flag of the card, keeping the other bits of `flags`
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{Card, Flag};
+    ///
+    /// let card = Card::new(0, false).flags(Flag::Green);
+    /// assert_eq!((card.flags, card.flag()), (3, Flag::Green));
+    /// ```
+    pub fn flags(mut self, flag: Flag) -> Self {
+        self.flags = (self.flags & !0b111) | i32::from(flag);
+        self
+    }
+
+    /// Returns the colored flag of the card
+    pub fn flag(&self) -> Flag {
+        Flag::from_flags(self.flags)
+    }
+
+    /// Suspends or buries the card, or puts it back into the queue of its card type
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{Card, CardQueueState};
+    ///
+    /// let card = Card::new(0, false).queue_state(CardQueueState::UserBuried);
+    /// assert_eq!((card.queue, card.get_queue_state()), (Some(-3), CardQueueState::UserBuried));
+    /// let card = card.queue_state(CardQueueState::Normal);
+    /// assert_eq!((card.queue, card.get_queue_state()), (Some(0), CardQueueState::Normal));
+    /// ```
+    pub fn queue_state(mut self, state: CardQueueState) -> Self {
+        self.suspend = state == CardQueueState::Suspended;
+        self.queue = match state {
+            CardQueueState::Suspended => Some(-1),
+            CardQueueState::SchedBuried => Some(-2),
+            CardQueueState::UserBuried => Some(-3),
+            CardQueueState::Normal if self.queue.unwrap_or(0) >= 0 => self.queue,
+            // The queue of a card that is no longer suspended or buried follows from its type, cards in
+            // relearning are in the learning queue
+            CardQueueState::Normal => Some(match self.card_type.unwrap_or(0) {
+                3 => 1,
+                card_type => card_type,
+            }),
+        };
+        self
+    }
+
+    /// Returns whether the card is suspended, buried or in a normal queue
+    pub fn get_queue_state(&self) -> CardQueueState {
+        match (self.suspend, self.queue) {
+            (true, _) | (_, Some(-1)) => CardQueueState::Suspended,
+            (_, Some(-2)) => CardQueueState::SchedBuried,
+            (_, Some(-3)) => CardQueueState::UserBuried,
+            _ => CardQueueState::Normal,
+        }
+    }
+
+    /// Sets the FSRS memory state of the card, keeping all other entries of its `data` JSON
+    pub fn with_fsrs_state(mut self, state: FsrsState) -> Self {
+        let mut data = self.data_object().unwrap_or_default();
+        data.insert("s".to_string(), Value::from(state.stability));
+        data.insert("d".to_string(), Value::from(state.difficulty));
+        match state.desired_retention {
+            Some(desired_retention) => data.insert("dr".to_string(), Value::from(desired_retention)),
+            None => data.remove("dr"),
+        };
+        self.data = Some(Value::Object(data).to_string());
+        self
+    }
+
+    /// Returns the FSRS memory state stored in the card's `data`
+    ///
+    /// Returns `None` if there is no data or it doesn't contain FSRS state, e.g. because it was written
+    /// by a custom scheduler.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{Card, FsrsState};
+    ///
+    /// let state = FsrsState { stability: 12.5, difficulty: 5.0, desired_retention: Some(0.9) };
+    /// let card = Card::new(0, false).with_fsrs_state(state);
+    /// assert_eq!(card.fsrs_state(), Some(state));
+    ///
+    /// let mut custom = Card::new(0, false);
+    /// custom.data = Some(r#"{"cd": {"ease": 2.5}}"#.to_string());
+    /// assert_eq!(custom.fsrs_state(), None);
+    /// ```
+    pub fn fsrs_state(&self) -> Option<FsrsState> {
+        let data = self.data_object()?;
+        Some(FsrsState {
+            stability: data.get("s")?.as_f64()? as f32,
+            difficulty: data.get("d")?.as_f64()? as f32,
+            desired_retention: data.get("dr").and_then(Value::as_f64).map(|dr| dr as f32),
+        })
+    }
+
+    /// Returns the `data` JSON object, or `None` if there is no data or it isn't a JSON object
+    fn data_object(&self) -> Option<Map<String, Value>> {
+        match serde_json::from_str(self.data.as_deref()?) {
+            Ok(Value::Object(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Sets a source the review log of this card is pulled from one entry at a time while the card is
+    /// written, after the entries in `review_history`
+    ///
+    /// Unlike `review_history` the entries are never held in memory all at once, which keeps imports of
+    /// very long histories cheap. The source is drained by the first write and shared between clones of
+    /// the card, so a card written again (or a clone of it) gets no more entries from it.
+    ///
+    /// ```rust
+    /// use genanki_rs::{Card, RevlogEntry};
+    ///
+    /// let mut remaining = 3;
+    /// let card = Card::new_with_review_data(0, false, 3, 0, 4, 120, 2500, 2, 2, 0).with_review_source(
+    ///     Box::new(move || {
+    ///         remaining -= 1;
+    ///         (remaining >= 0).then(|| RevlogEntry {
+    ///             id: 1_700_000_000_000 + remaining,
+    ///             ease: 3,
+    ///             ivl: 4,
+    ///             last_ivl: 1,
+    ///             factor: 2500,
+    ///             time: 6000,
+    ///             review_type: 1,
+    ///             usn: -1,
+    ///         })
+    ///     }),
+    /// );
+    /// assert!(card.has_review_source());
+    /// ```
+    pub fn with_review_source(mut self, source: ReviewSource) -> Self {
+        self.review_source = Some(Arc::new(Mutex::new(source)));
+        self
+    }
+
+    /// Whether a review source was set with [`Card::with_review_source`]
+    pub fn has_review_source(&self) -> bool {
+        self.review_source.is_some()
+    }
+
     #[allow(dead_code)]
     pub fn ord(&self) -> i64 {
         self.ord
@@ -168,12 +619,13 @@ impl Card {
         };
         
         transaction
+            .prepare_cached("INSERT INTO cards VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?);")
+            .map_err(database_error)?
             .execute(
-                "INSERT INTO cards VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?);",
                 params![
                     card_id,                             // id (idx 0)
                     note_id,                             // nid (idx 1)
-                    deck_id,                             // did (idx 2)
+                    self.deck_id.unwrap_or(deck_id),     // did (idx 2)
                     self.ord,                            // ord (idx 3)
                     self.mod_time.unwrap_or(timestamp as i64), // mod (idx 4)
                     self.usn,                            // usn (idx 5)
@@ -185,19 +637,26 @@ impl Card {
                     self.reps.unwrap_or(0),              // reps (idx 11)
                     self.lapses.unwrap_or(0),            // lapses (idx 12)
                     self.left.unwrap_or(0),              // left (idx 13)
-                    0,                                   // odue (idx 14)
-                    0,                                   // odid (idx 15)
-                    0,                                   // flags (idx 16)
+                    self.original_due.unwrap_or(0),      // odue (idx 14)
+                    self.original_deck_id.unwrap_or(0),  // odid (idx 15)
+                    self.flags,                          // flags (idx 16)
                     self.data.as_deref().unwrap_or(""),    // data (idx 17)
                 ],
             )
             .map_err(database_error)?;
 
-        // Write review history to revlog table
-        for revlog_entry in &self.review_history {
-            transaction
+        // Write review history to revlog table, followed by the entries of the review source
+        let mut source = self
+            .review_source
+            .as_ref()
+            .map(|source| source.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        let streamed = std::iter::from_fn(|| source.as_mut().and_then(|source| source()));
+        let mut insert_revlog = transaction
+            .prepare_cached("INSERT INTO revlog VALUES(?,?,?,?,?,?,?,?,?);")
+            .map_err(database_error)?;
+        for revlog_entry in self.review_history.iter().cloned().chain(streamed) {
+            insert_revlog
                 .execute(
-                    "INSERT INTO revlog VALUES(?,?,?,?,?,?,?,?,?);",
                     params![
                         revlog_entry.id,                 // id (timestamp)
                         card_id,                         // cid (card id)
@@ -216,3 +675,62 @@ impl Card {
         Ok(())
     }
 }
+
+/// Days from the creation of a collection created at `crt` (seconds since the Unix epoch) to `iso_date`
+fn day_offset(iso_date: &str, crt: i64) -> Result<i64, Error> {
+    let day = parse_iso_date(iso_date).ok_or_else(|| Error::InvalidDate(iso_date.to_string()))?;
+    Ok(day - crt.div_euclid(86_400))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_due_date_counts_days_since_the_collection_was_created() {
+        // 2024-01-01 00:00 and 10:00 UTC
+        for crt in [1_704_067_200, 1_704_103_200] {
+            let review = Card::new_with_review_data(0, false, 5, 0, 30, 0, 2500, 2, 2, 0);
+            assert_eq!(review.clone().set_due_date((2024, 6, 1), crt).unwrap().due, Some(152));
+            assert_eq!(review.clone().set_due_date((2024, 1, 1), crt).unwrap().due, Some(0));
+            assert_eq!(review.set_due_date((2023, 12, 31), crt).unwrap().due, Some(-1));
+        }
+
+        let card = |card_type, queue| Card::new_with_review_data(0, false, 5, 0, 30, 0, 2500, card_type, queue, 0);
+        for (card_type, queue) in [(2, 2), (2, -1), (2, -2), (2, -3), (1, 3), (3, 3)] {
+            assert!(card(card_type, queue).set_due_date((2024, 6, 1), 1_704_067_200).is_ok());
+        }
+        for (card_type, queue) in [(2, 1), (0, 3), (0, 0), (1, 1), (3, 1), (2, 3)] {
+            assert!(matches!(
+                card(card_type, queue).set_due_date((2024, 6, 1), 1_704_067_200),
+                Err(Error::DueKindMismatch(t, q, _)) if (t, q) == (card_type, queue)
+            ));
+        }
+        assert!(matches!(
+            card(2, 2).set_due_date((2023, 2, 29), 1_704_067_200),
+            Err(Error::InvalidDate(date)) if date == "2023-02-29"
+        ));
+    }
+
+    #[test]
+    fn revlog_entry_rejects_invalid_values() {
+        for ease in [0, 5, -1] {
+            assert!(matches!(RevlogEntry::new(1, ease, 1, 0, 2500, 1000, 1), Err(Error::InvalidEase(e)) if e == ease));
+            assert!(matches!(RevlogEntry::learn(1, ease, 1, 0, 2500, 1000), Err(Error::InvalidEase(e)) if e == ease));
+        }
+        for review_type in [-1, 4, 5] {
+            assert!(matches!(
+                RevlogEntry::new(1, 3, 1, 0, 2500, 1000, review_type),
+                Err(Error::InvalidReviewType(t)) if t == review_type
+            ));
+        }
+        for constructor in [RevlogEntry::learn, RevlogEntry::review, RevlogEntry::relearn, RevlogEntry::cram] {
+            assert!(matches!(constructor(1, 3, 1, 0, 2500, -1), Err(Error::NegativeReviewTime(-1))));
+            assert_eq!(constructor(1, 3, 1, 0, 2500, 0).unwrap().time, 0);
+        }
+        assert!(matches!(RevlogEntry::new(1, 3, 1, 0, 2500, i32::MIN, 0), Err(Error::NegativeReviewTime(i32::MIN))));
+        for review_type in 0..=3 {
+            assert_eq!(RevlogEntry::new(1, 3, 1, 0, 2500, 1000, review_type).unwrap().review_type, review_type);
+        }
+    }
+}
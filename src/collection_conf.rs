@@ -0,0 +1,129 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Collection-wide settings that Anki reads from `col.conf` (or from the `config` table for
+/// collection versions 16 and newer)
+///
+/// ```rust
+/// use genanki_rs::{CollectionConf, Package};
+/// use std::collections::BTreeMap;
+///
+/// # fn main() -> Result<(), genanki_rs::Error> {
+/// let mut package = Package::new(vec![], vec![])?;
+/// package.set_collection_conf(CollectionConf {
+///     bury_new_siblings: false,
+///     bury_review_siblings: false,
+///     extra: BTreeMap::from([("myAddonSetting".to_string(), serde_json::json!({"enabled": true}))]),
+///     ..Default::default()
+/// });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CollectionConf {
+    /// Whether notes added in Anki go into the current deck instead of the note type's last deck (`addToCur`)
+    pub add_to_current_deck: bool,
+    /// Whether new siblings of a reviewed card are buried until the next day (`newBury`)
+    pub bury_new_siblings: bool,
+    /// Whether review siblings of a reviewed card are buried until the next day (`revBury`)
+    pub bury_review_siblings: bool,
+    /// Time limit of a study session in seconds, 0 for no limit (`timeLim`)
+    pub time_limit_secs: u32,
+    /// Whether the answer buttons show the time until the card is due next (`estTimes`)
+    pub show_estimated_times: bool,
+    /// Whether the number of due cards is shown in the deck list and while studying (`dueCounts`)
+    pub show_due_counts: bool,
+    /// Further keys written as they are in the order of their names, e.g. for settings of add-ons. The
+    /// settings above and the ones set on the package take precedence over keys with the same name. Keys
+    /// given to [`Package::set_collection_conf_json`](crate::Package::set_collection_conf_json) end up here.
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Default for CollectionConf {
+    fn default() -> Self {
+        Self {
+            add_to_current_deck: true,
+            bury_new_siblings: true,
+            bury_review_siblings: true,
+            time_limit_secs: 0,
+            show_estimated_times: true,
+            show_due_counts: true,
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+impl CollectionConf {
+    /// Returns the `col.conf` keys and values of these settings, the extra keys first so that later
+    /// entries take precedence
+    pub(crate) fn entries(&self) -> Vec<(&str, Value)> {
+        let mut entries: Vec<(&str, Value)> =
+            self.extra.iter().map(|(key, val)| (key.as_str(), val.clone())).collect();
+        entries.extend([
+            ("addToCur", Value::from(self.add_to_current_deck)),
+            ("newBury", Value::from(self.bury_new_siblings)),
+            ("revBury", Value::from(self.bury_review_siblings)),
+            ("timeLim", Value::from(self.time_limit_secs)),
+            ("estTimes", Value::from(self.show_estimated_times)),
+            ("dueCounts", Value::from(self.show_due_counts)),
+        ]);
+        entries
+    }
+}
+
+/// Version of Anki's scheduler a collection uses, see `Package::set_scheduler_version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerVersion {
+    /// The original scheduler, used by collections that never set a version
+    V1,
+    /// The v2 scheduler (`schedVer` 2)
+    V2,
+    /// The v3 scheduler (`schedVer` 2 with `sched2021`), the default since Anki 2.1.55
+    V3,
+}
+
+impl SchedulerVersion {
+    /// Returns the `col.conf` keys and values selecting this scheduler version
+    pub(crate) fn entries(&self) -> Vec<(&'static str, Value)> {
+        match self {
+            SchedulerVersion::V1 => vec![("schedVer", Value::from(1)), ("sched2021", Value::from(false))],
+            SchedulerVersion::V2 => vec![("schedVer", Value::from(2)), ("sched2021", Value::from(false))],
+            SchedulerVersion::V3 => vec![("schedVer", Value::from(2)), ("sched2021", Value::from(true))],
+        }
+    }
+}
+
+/// Anki versions a package can be written for, see `Package::target_anki_version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnkiVersion {
+    /// Collection version 11 in `collection.anki2`, with note types and decks as JSON in the `col`
+    /// table. Every Anki version imports this.
+    Legacy,
+    /// Collection version 18 in `collection.anki21` with a `meta` entry, with note types, fields,
+    /// templates and decks in their own tables. Needs Anki 2.1.50 or newer.
+    Modern,
+}
+
+impl AnkiVersion {
+    /// Returns the collection version (`col.ver`) written for this Anki version
+    pub(crate) fn col_ver(&self) -> i64 {
+        match self {
+            AnkiVersion::Legacy => 11,
+            AnkiVersion::Modern => 18,
+        }
+    }
+}
+
+/// Archive format a package is written in, see `Package::write_with_format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PackageFormat {
+    /// The uncompressed collection in `collection.anki2` (or `collection.anki21`, see [`AnkiVersion`])
+    /// and a JSON media map, which every Anki version imports
+    #[default]
+    Legacy,
+    /// The format of Anki 2.1.50 and newer: a zstd-compressed collection version 18 in
+    /// `collection.anki21b`, zstd-compressed media files and a protobuf media map. Requires the `zstd`
+    /// feature.
+    #[cfg(feature = "zstd")]
+    LatestZstd,
+}
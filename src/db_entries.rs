@@ -65,6 +65,8 @@ pub struct Fld {
     pub ord: i64,
     pub font: String,
     pub size: i64,
+    #[serde(rename = "plainText", default)]
+    pub plain_text: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -2,11 +2,32 @@ use super::Package;
 use crate::db_entries::{DeckDbEntry};
 use crate::model::Model;
 use crate::note::Note;
+use crate::package::DeckInfoEntry;
+use crate::proto::ProtoWriter;
+use crate::util::SplitMix64;
 use crate::Error;
 use rusqlite::{Transaction};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::ops::RangeFrom;
 
+/// Totals over the review history of all cards in a deck, see [`Deck::review_stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReviewStats {
+    /// Number of reviews
+    pub total_reviews: u64,
+    /// Number of reviews that failed a card in review (pressed "Again" on a review card)
+    pub total_lapses: u64,
+    /// Total time spent answering, in milliseconds
+    pub total_time_ms: u64,
+}
+
+/// Returns a deck name in the form the `decks` table stores it, with `\x1f` instead of `::` between
+/// the names of parent and child decks
+pub(crate) fn native_deck_name(name: &str) -> String {
+    name.split("::").map(str::trim).collect::<Vec<_>>().join("\x1f")
+}
+
 /// A flashcard deck which can be written into an .apkg file.
 #[derive(Clone)]
 pub struct Deck {
@@ -15,6 +36,8 @@ pub struct Deck {
     pub description: String,
     notes: Vec<Note>,
     models: HashMap<i64, Model>,
+    new_card_shuffle_seed: Option<u64>,
+    config_id: i64,
 }
 
 impl Deck {
@@ -28,10 +51,59 @@ impl Deck {
             description: description.to_string(),
             notes: vec![],
             models: HashMap::new(),
+            new_card_shuffle_seed: None,
+            config_id: 1,
+        }
+    }
+
+    /// Renders `markdown` to HTML and uses it as the description of the deck
+    ///
+    /// Anki versions differ in whether they render deck descriptions as Markdown, the pre-rendered HTML
+    /// displays the same in all of them. Requires the `markdown` feature.
+    #[cfg(feature = "markdown")]
+    pub fn set_description_markdown(&mut self, markdown: &str) {
+        let parser = pulldown_cmark::Parser::new(markdown);
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, parser);
+        self.description = html;
+    }
+
+    /// Creates a new deck with an `id`, `name` and `description` and adds `notes` to it, see
+    /// [`Deck::add_note`]
+    ///
+    /// Returns `Err` for the first note whose number of fields doesn't match the model registered by an
+    /// earlier note with the same model id
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let deck = Deck::with_notes(
+    ///     1234,
+    ///     "Capitals",
+    ///     "",
+    ///     vec![
+    ///         Note::new(basic_model(), vec!["Capital of France?", "Paris"])?,
+    ///         Note::new(basic_model(), vec!["Capital of Peru?", "Lima"])?,
+    ///     ],
+    /// )?;
+    /// assert_eq!(deck.notes().len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_notes(id: i64, name: &str, description: &str, notes: Vec<Note>) -> Result<Self, Error> {
+        let mut deck = Self::new(id, name, description);
+        for (i, note) in notes.into_iter().enumerate() {
+            deck.add_note(note);
+            let note = &deck.notes[i];
+            deck.check_note_model(note)
+                .map_err(|e| e.context(deck.note_context("adding", i, note)))?;
         }
+        Ok(deck)
     }
 
-    /// Adds a `note` (Flashcard) to the deck.
+    /// Adds a `note` (Flashcard) to the deck and registers its model.
     ///
     /// Example:
     ///
@@ -42,25 +114,263 @@ impl Deck {
     /// my_deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
     /// ```
     pub fn add_note(&mut self, note: Note) {
+        self.models.entry(note.model_id()).or_insert_with(|| note.model().clone());
         self.notes.push(note);
     }
 
+    /// Adds multiple `notes` to the deck.
+    pub fn add_notes(&mut self, notes: impl IntoIterator<Item = Note>) {
+        notes.into_iter().for_each(|note| self.add_note(note));
+    }
+
+    /// Removes all notes matching `pred` from the deck and returns them in their original order.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut capitals = Deck::new(1234, "Capitals", "");
+    /// capitals.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// capitals.add_note(Note::new(basic_model(), vec!["Capital of Peru?", "Lima"])?.with_tag("americas"));
+    ///
+    /// let mut americas = Deck::new(1235, "Capitals::Americas", "");
+    /// americas.add_notes(capitals.extract_notes(|note| note.get_tags().iter().any(|tag| tag == "americas")));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extract_notes<F: FnMut(&Note) -> bool>(&mut self, mut pred: F) -> Vec<Note> {
+        let (extracted, kept) = std::mem::take(&mut self.notes)
+            .into_iter()
+            .partition(|note| pred(note));
+        self.notes = kept;
+        extracted
+    }
+
+    /// Moves all notes matching `pred` from this deck to `other`, returning how many were moved.
+    pub fn move_notes_to<F: FnMut(&Note) -> bool>(&mut self, other: &mut Deck, pred: F) -> usize {
+        let extracted = self.extract_notes(pred);
+        let moved = extracted.len();
+        other.add_notes(extracted);
+        moved
+    }
+
+    /// Sorts the notes of this deck with the comparator `cmp`, which determines the order new cards are studied in
+    ///
+    /// The sort is stable: notes that compare equal keep their relative order.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "Capitals", "");
+    /// my_deck.add_note(Note::new(basic_model(), vec!["Capital of Peru?", "Lima"])?);
+    /// my_deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// my_deck.sort_notes_by(|a, b| a.fields()[0].cmp(&b.fields()[0]));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sort_notes_by<F: FnMut(&Note, &Note) -> Ordering>(&mut self, cmp: F) {
+        self.notes.sort_by(cmp);
+    }
+
+    /// Shuffles the order new cards are studied in, instead of the order the notes were added in
+    ///
+    /// New cards without an explicit `due` get a random position when the deck is written. The
+    /// positions only depend on `seed` and the number of notes, so writing the same deck with the
+    /// same seed gives the same order. Cards of the same note share their position.
+    pub fn shuffle_new_cards(&mut self, seed: u64) {
+        self.new_card_shuffle_seed = Some(seed);
+    }
+
+    /// Replaces every field of every note with the result of `f`, which gets the index of the field
+    /// in its note and its current value
+    ///
+    /// The cards of notes are generated again from the new fields (unless they were set explicitly),
+    /// and the sort field and checksum written for a note are computed from the new fields.
+    ///
+    /// Returns `Err` if cards can't be generated for a note
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["  What is   the capital of France? ", "Paris"])?);
+    /// deck.transform_fields(|_, field| field.split_whitespace().collect::<Vec<_>>().join(" "))?;
+    /// assert_eq!(deck.notes()[0].fields()[0], "What is the capital of France?");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn transform_fields<F: FnMut(usize, &str) -> String>(&mut self, mut f: F) -> Result<(), Error> {
+        for note in &mut self.notes {
+            note.transform_fields(&mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Sums up the review history of all cards of the notes in this deck
+    pub fn review_stats(&self) -> ReviewStats {
+        self.notes
+            .iter()
+            .flat_map(|note| note.cards())
+            .flat_map(|card| &card.review_history)
+            .fold(ReviewStats::default(), |mut stats, entry| {
+                stats.total_reviews += 1;
+                if entry.review_type == 1 && entry.ease == 1 {
+                    stats.total_lapses += 1;
+                }
+                stats.total_time_ms += entry.time.max(0) as u64;
+                stats
+            })
+    }
+
     pub(crate) fn add_model(&mut self, model: Model) {
         self.models.insert(model.id, model);
     }
 
-    pub(crate) fn notes(&self) -> &Vec<Note> {
+    /// Registers the model `old_id` as `new_id`, for `Package::remap_model`
+    pub(crate) fn remap_model(&mut self, old_id: i64, new_id: i64) {
+        if let Some(mut model) = self.models.remove(&old_id) {
+            model.id = new_id;
+            self.models.insert(new_id, model);
+        }
+    }
+
+    /// Sets the id of the deck options preset (deck config) the deck uses, 1 (the default preset) unless
+    /// set
+    ///
+    /// Writing a package returns `Err` if the preset is neither the default one nor one added with
+    /// [`Package::add_deck_config_entry`](crate::Package::add_deck_config_entry).
+    pub fn set_config_id(&mut self, config_id: i64) {
+        self.config_id = config_id;
+    }
+
+    /// Returns the id of the deck options preset the deck uses
+    pub fn config_id(&self) -> i64 {
+        self.config_id
+    }
+
+    /// Returns the notes of this deck
+    pub fn notes(&self) -> &Vec<Note> {
         &self.notes
     }
 
+    /// Returns the notes of this deck for in-place editing, reordering or removal
+    pub fn notes_mut(&mut self) -> &mut Vec<Note> {
+        &mut self.notes
+    }
+
     pub(crate) fn models(&self) -> &HashMap<i64, Model> {
         &self.models
     }
 
+    /// Checks that the model of every note is registered in the deck and that the number of fields of
+    /// the note matches the registered model
+    ///
+    /// Notes added with [`Deck::add_note`] and [`Deck::add_notes`] register their model, notes pushed
+    /// with [`Deck::notes_mut`] don't.
+    ///
+    /// Returns `Err` for the first note that violates this
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
+    /// deck.validate()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<(), Error> {
+        for note in &self.notes {
+            self.check_note_model(note)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `Err` if the model of `note` isn't registered or has another number of fields than the note
+    fn check_note_model(&self, note: &Note) -> Result<(), Error> {
+        let model = self
+            .models
+            .get(&note.model_id())
+            .ok_or(Error::UnregisteredModel(note.model_id(), self.id))?;
+        if model.fields().len() != note.fields().len() {
+            return Err(Error::ModelFieldCountMismatch(model.fields().len(), note.fields().len()));
+        }
+        Ok(())
+    }
+
+    /// Returns the groups of notes Anki considers duplicates: notes of the same model with the same first
+    /// field after stripping HTML, where media tags count as their file names, see [`Note::checksum`]
+    ///
+    /// Groups are in the order of their first note, notes in the order they were added.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Capitals", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// deck.add_note(Note::new(basic_model(), vec!["Capital of Peru?", "Lima"])?);
+    /// deck.add_note(Note::new(basic_model(), vec!["<i>Capital of France?</i>", "Paris"])?);
+    /// assert_eq!(deck.find_duplicates().len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn find_duplicates(&self) -> Vec<Vec<&Note>> {
+        group_duplicates(&self.notes)
+    }
+
+    /// Removes the notes [`Deck::find_duplicates`] reports, keeping the first note of every group
+    ///
+    /// Returns the number of removed notes.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Capitals", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// deck.add_note(Note::new(basic_model(), vec!["<i>Capital of France?</i>", "Paris"])?);
+    /// assert_eq!(deck.dedup_notes(), 1);
+    /// assert_eq!(deck.notes()[0].fields()[0], "Capital of France?");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dedup_notes(&mut self) -> usize {
+        let before = self.notes.len();
+        let mut seen = HashSet::new();
+        self.notes
+            .retain(|note| seen.insert(duplicate_key(note)));
+        before - self.notes.len()
+    }
+
+    /// Returns the ids of the models the notes of this deck use
+    ///
+    /// Models are written into the package because a note uses them, so these are the models the deck
+    /// needs.
+    pub fn used_models(&self) -> HashSet<i64> {
+        self.notes.iter().map(|note| note.model().id).collect()
+    }
+
+    /// Whether any note of this deck uses the model with the given `id`
+    pub fn contains_model(&self, id: i64) -> bool {
+        self.notes.iter().any(|note| note.model().id == id)
+    }
+
     pub(crate) fn to_deck_db_entry(&self) -> DeckDbEntry {
         DeckDbEntry {
             collapsed: false,
-            conf: 1,
+            conf: self.config_id,
             desc: self.description.clone(),
             deck_db_entry_dyn: 0,
             extend_new: 10,
@@ -76,24 +386,132 @@ impl Deck {
         }
     }
 
+    /// Returns the row of the `decks` table describing this deck, used from collection version 16 on
+    pub(crate) fn to_deck_info_entry(&self, timestamp: f64) -> DeckInfoEntry {
+        let normal = ProtoWriter::new()
+            .int(1, self.config_id)
+            .int(2, 10)
+            .int(3, 50)
+            .string(4, &self.description);
+        DeckInfoEntry {
+            id: self.id,
+            name: native_deck_name(&self.name),
+            mtime_secs: timestamp as i64,
+            usn: -1,
+            common: vec![],
+            kind: ProtoWriter::new().message(1, &normal.finish()).finish(),
+        }
+    }
+
     #[allow(dead_code)]
     fn to_json(&self) -> String {
         let db_entry: DeckDbEntry = self.to_deck_db_entry();
         serde_json::to_string(&db_entry).expect("Should always serialize")
     }
 
+    /// Writes the notes of the deck and their cards, except for the notes at the indices in `skipped`
     pub(crate) fn write_notes_and_cards_to_db(
         &self,
         transaction: &Transaction,
         timestamp: f64,
         id_gen: &mut RangeFrom<usize>,
+        skipped: &HashSet<usize>,
     ) -> Result<(), Error> {
-        for note in &self.notes {
-            note.write_to_db(&transaction, timestamp, self.id, id_gen)?;
+        let new_card_dues: Option<Vec<i64>> = self.new_card_shuffle_seed.map(|seed| {
+            let mut dues: Vec<i64> = (1..=self.notes.len() as i64).collect();
+            SplitMix64::new(seed).shuffle(&mut dues);
+            dues
+        });
+        for (i, note) in self.notes.iter().enumerate() {
+            if skipped.contains(&i) {
+                continue;
+            }
+            let new_card_due = new_card_dues.as_ref().map(|dues| dues[i]);
+            note.write_to_db(transaction, timestamp, self.id, id_gen, new_card_due)
+                .map_err(|e| e.context(self.note_context("writing", i, note)))?;
         }
         Ok(())
     }
 
+    /// Describes what was done (`action`) with the `i`th note of the deck for error messages
+    fn note_context(&self, action: &str, i: usize, note: &Note) -> String {
+        match note.fields().first() {
+            Some(first_field) => format!(
+                "{} note {} ({:?}) of deck \"{}\"",
+                action, i, first_field, self.name
+            ),
+            None => format!("{} note {} of deck \"{}\"", action, i, self.name),
+        }
+    }
+
+    /// Turns the deck into a [`Package`] with only this deck and no media files, e.g. to add media or
+    /// settings before writing it
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
+    /// my_deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
+    ///
+    /// let mut package = my_deck.into_package();
+    /// package.set_media_index_base(100);
+    /// package.write(std::io::Cursor::new(Vec::new()))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_package(self) -> Package {
+        Package::new_from_memory(vec![self], vec![]).expect("creating a package without media files can't fail")
+    }
+
+    /// Returns a [`Package`] with a copy of this deck and no media files, see [`Deck::into_package`]
+    pub fn to_package(&self) -> Package {
+        self.clone().into_package()
+    }
+
+    /// Returns a copy of this deck with only the notes matching `pred`
+    ///
+    /// Notes keep their complete model, so note types import intact even if the cards of some of their
+    /// templates aren't in the subset.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut capitals = Deck::new(1234, "Capitals", "");
+    /// capitals.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?.with_tag("sample"));
+    /// capitals.add_note(Note::new(basic_model(), vec!["Capital of Peru?", "Lima"])?);
+    ///
+    /// let sample = capitals.subset(|note| note.get_tags().iter().any(|tag| tag == "sample"));
+    /// assert_eq!(sample.notes().len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn subset<F: FnMut(&Note) -> bool>(&self, mut pred: F) -> Deck {
+        let mut subset = Deck {
+            id: self.id,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            notes: vec![],
+            models: HashMap::new(),
+            new_card_shuffle_seed: self.new_card_shuffle_seed,
+            config_id: self.config_id,
+        };
+        subset.add_notes(self.notes.iter().filter(|note| pred(note)).cloned());
+        subset
+    }
+
+    /// Writes a copy of this deck with only the notes matching `pred` to a new `.apkg` file, see
+    /// [`Deck::subset`]
+    ///
+    /// Returns `Err` if the file can not be created.
+    #[cfg(feature = "fs")]
+    pub fn export_subset<F: FnMut(&Note) -> bool>(&self, pred: F, file: &str) -> Result<(), Error> {
+        self.subset(pred).into_package().write_to_file(file)
+    }
+
     /// Packages a deck and writes it to a new `.apkg` file. This file can then be imported in Anki.
     ///
     /// Returns `Err` if the file can not be created.
@@ -117,8 +535,300 @@ impl Deck {
     ///
     /// Package::new(vec![my_deck], vec![])?.write_to_file("output.apkg")?;
     /// ```
+    #[cfg(feature = "fs")]
     pub fn write_to_file(&self, file: &str) -> Result<(), Error> {
         Package::new(vec![self.clone()], vec![])?.write_to_file(file)?;
         Ok(())
     }
 }
+
+/// Groups `notes` of the same model with the same first field checksum and text, keeping only groups of
+/// more than one note, see [`Deck::find_duplicates`]
+pub(crate) fn group_duplicates<'a>(notes: impl IntoIterator<Item = &'a Note>) -> Vec<Vec<&'a Note>> {
+    let mut groups: Vec<Vec<&Note>> = Vec::new();
+    let mut group_by_key: HashMap<(i64, u32, String), usize> = HashMap::new();
+    for note in notes {
+        let key = duplicate_key(note);
+        match group_by_key.get(&key) {
+            Some(&group) => groups[group].push(note),
+            None => {
+                group_by_key.insert(key, groups.len());
+                groups.push(vec![note]);
+            }
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// The model, first field checksum and stripped first field that duplicate notes share, with the file
+/// names of media tags kept so that notes with different pictures stay apart
+fn duplicate_key(note: &Note) -> (i64, u32, String) {
+    (note.model_id(), note.checksum(), note.checksum_text())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apkg_schema::APKG_SCHEMA;
+    use crate::{basic_and_reversed_card_model, basic_model, cloze_model, Card, Field, RevlogEntry, Template};
+    use rusqlite::Connection;
+
+    fn deck_with_notes(id: i64, fronts: &[&str]) -> Deck {
+        let mut deck = Deck::new(id, "deck", "");
+        for front in fronts {
+            deck.add_note(Note::new(basic_model(), vec![front, "back"]).unwrap());
+        }
+        deck
+    }
+
+    #[cfg(feature = "markdown")]
+    #[test]
+    fn markdown_description_is_rendered_to_html() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.set_description_markdown("Words from *chapter 1*");
+        assert_eq!(deck.description, "<p>Words from <em>chapter 1</em></p>\n");
+        assert_eq!(deck.to_deck_db_entry().desc, deck.description);
+    }
+
+    fn fronts(deck: &Deck) -> Vec<&str> {
+        deck.notes().iter().map(|note| note.fields()[0].as_str()).collect()
+    }
+
+    #[test]
+    fn used_models_come_from_the_notes() {
+        let mut deck = deck_with_notes(1, &["a", "b"]);
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::c}}"]).unwrap());
+        assert_eq!(deck.used_models(), HashSet::from([basic_model().id, cloze_model().id]));
+        assert!(deck.contains_model(cloze_model().id));
+        assert!(!deck.contains_model(basic_and_reversed_card_model().id));
+        assert!(Deck::new(2, "empty", "").used_models().is_empty());
+    }
+
+    #[test]
+    fn validate_checks_registered_models() {
+        let mut deck = deck_with_notes(1, &["a", "b"]);
+        deck.add_notes(vec![Note::new(cloze_model(), vec!["{{c1::c}}"]).unwrap()]);
+        deck.validate().unwrap();
+
+        let mut unregistered = deck_with_notes(2, &["a"]);
+        unregistered.notes_mut().push(Note::new(cloze_model(), vec!["{{c1::c}}"]).unwrap());
+        assert!(matches!(unregistered.validate(), Err(Error::UnregisteredModel(id, 2)) if id == cloze_model().id));
+
+        let three_fields = Model::new(
+            basic_model().id,
+            "Three fields",
+            vec![Field::new("Front"), Field::new("Back"), Field::new("Extra")],
+            vec![Template::new("Card 1").qfmt("{{Front}}").afmt("{{Back}}")],
+        );
+        deck.notes_mut().push(Note::new(three_fields, vec!["a", "b", "c"]).unwrap());
+        assert!(matches!(deck.validate(), Err(Error::ModelFieldCountMismatch(2, 3))));
+    }
+
+    #[test]
+    fn subset_keeps_complete_models() {
+        let mut deck = deck_with_notes(1, &["a", "b"]);
+        deck.set_config_id(7);
+        let card = Card::new(1, false);
+        deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["c", "d"]).unwrap().with_cards(vec![card]));
+        let subset = deck.subset(|note| note.fields()[0] != "a");
+        assert_eq!(fronts(&subset), ["b", "c"]);
+        assert_eq!((subset.id, subset.config_id()), (1, 7));
+        assert_eq!(subset.notes()[1].model().templates().len(), 2);
+        subset.validate().unwrap();
+    }
+
+    #[test]
+    fn find_duplicates() {
+        let mut deck = deck_with_notes(1, &["Paris", "Lima", "<b>Paris</b>", "Lima&nbsp;"]);
+        deck.add_note(Note::new(cloze_model(), vec!["Paris"]).unwrap());
+        let duplicates: Vec<Vec<&str>> = deck
+            .find_duplicates()
+            .iter()
+            .map(|group| group.iter().map(|note| note.fields()[0].as_str()).collect())
+            .collect();
+        assert_eq!(duplicates, [vec!["Paris", "<b>Paris</b>"]]);
+    }
+
+    #[test]
+    fn dedup_notes_keeps_the_first_note() {
+        let mut deck = deck_with_notes(1, &["Paris", "Lima", "<b>Paris</b>"]);
+        deck.add_note(Note::new(cloze_model(), vec!["Paris"]).unwrap());
+        assert_eq!(deck.dedup_notes(), 1);
+        assert_eq!(fronts(&deck), ["Paris", "Lima", "Paris"]);
+        assert_eq!(deck.dedup_notes(), 0);
+
+        let mut pictures = deck_with_notes(1, &[r#"<img src="paris.jpg">"#, r#"<img src="lima.jpg">"#]);
+        assert!(pictures.find_duplicates().is_empty());
+        assert_eq!(pictures.dedup_notes(), 0);
+        assert_eq!(fronts(&pictures), [r#"<img src="paris.jpg">"#, r#"<img src="lima.jpg">"#]);
+        pictures.add_note(Note::new(basic_model(), vec![r#"<img class="big" src="paris.jpg">"#, "b"]).unwrap());
+        assert_eq!(pictures.dedup_notes(), 1);
+    }
+
+    #[test]
+    fn with_notes_reports_the_first_bad_note() {
+        let deck = Deck::with_notes(1, "deck", "", vec![Note::new(basic_model(), vec!["a", "b"]).unwrap()]).unwrap();
+        assert_eq!(fronts(&deck), ["a"]);
+
+        let three_fields = Model::new(
+            basic_model().id,
+            "Three fields",
+            vec![Field::new("Front"), Field::new("Back"), Field::new("Extra")],
+            vec![Template::new("Card 1").qfmt("{{Front}}").afmt("{{Back}}")],
+        );
+        let notes = vec![
+            Note::new(basic_model(), vec!["a", "b"]).unwrap(),
+            Note::new(three_fields, vec!["c", "d", "e"]).unwrap(),
+        ];
+        let error = Deck::with_notes(1, "deck", "", notes).err().unwrap();
+        assert!(matches!(
+            &error,
+            Error::Context { source, .. } if matches!(**source, Error::ModelFieldCountMismatch(2, 3))
+        ));
+        assert!(error.to_string().contains(r#"adding note 1 ("c")"#), "{}", error);
+    }
+
+    #[test]
+    fn review_stats_sums_review_history() {
+        let review = |ease, review_type, time| RevlogEntry {
+            id: 0,
+            ease,
+            ivl: 1,
+            last_ivl: 0,
+            factor: 2500,
+            time,
+            review_type,
+            usn: -1,
+        };
+        let card = Card::new_with_review_history(
+            0,
+            false,
+            3,
+            1,
+            1,
+            0,
+            2500,
+            2,
+            2,
+            0,
+            vec![review(3, 0, 4000), review(1, 1, 6000), review(3, 2, 2500)],
+            None,
+        );
+        let mut deck = deck_with_notes(1, &["a"]);
+        deck.add_note(
+            Note::new_with_cards(basic_model(), vec!["b", "c"], vec![card], None, None).unwrap(),
+        );
+        assert_eq!(
+            deck.review_stats(),
+            ReviewStats {
+                total_reviews: 3,
+                total_lapses: 1,
+                total_time_ms: 12500,
+            }
+        );
+    }
+
+    #[test]
+    fn sort_notes_by_is_stable() {
+        let mut deck = deck_with_notes(1, &["b2", "a1", "b1", "a2"]);
+        deck.sort_notes_by(|a, b| a.fields()[0][..1].cmp(&b.fields()[0][..1]));
+        assert_eq!(fronts(&deck), ["a1", "a2", "b2", "b1"]);
+    }
+
+    fn new_card_dues(deck: &Deck) -> Vec<i64> {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(APKG_SCHEMA).unwrap();
+        let transaction = conn.unchecked_transaction().unwrap();
+        deck.write_notes_and_cards_to_db(&transaction, 0.0, &mut (1..), &HashSet::new())
+            .unwrap();
+        let mut stmt = transaction.prepare("SELECT due FROM cards ORDER BY nid").unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|due| due.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn shuffle_new_cards_is_deterministic() {
+        let fronts: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let mut deck = deck_with_notes(1, &fronts.iter().map(String::as_str).collect::<Vec<_>>());
+        assert_eq!(new_card_dues(&deck), vec![0; 20]);
+
+        deck.shuffle_new_cards(42);
+        let dues = new_card_dues(&deck);
+        assert_eq!(dues, new_card_dues(&deck));
+        let mut sorted = dues.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (1..=20).collect::<Vec<_>>());
+        assert_ne!(dues, sorted);
+
+        deck.shuffle_new_cards(7);
+        assert_ne!(new_card_dues(&deck), dues);
+    }
+
+    #[test]
+    fn write_errors_name_the_note() {
+        let mut deck = deck_with_notes(1, &["good"]);
+        deck.add_note(
+            Note::new(basic_model(), vec!["bad", "back"])
+                .unwrap()
+                .with_cards(vec![Card::new(3, false)]),
+        );
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(APKG_SCHEMA).unwrap();
+        let transaction = conn.unchecked_transaction().unwrap();
+        let error = deck
+            .write_notes_and_cards_to_db(&transaction, 0.0, &mut (1..), &HashSet::new())
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            Error::Context { source, .. } if matches!(**source, Error::CardOrdOutOfRange(3, 1))
+        ));
+        assert_eq!(
+            error.to_string(),
+            "writing note 1 (\"bad\") of deck \"deck\": card ord 3 is out of range for a model with 1 templates"
+        );
+    }
+
+    #[test]
+    fn transform_fields_regenerates_cards() {
+        let mut deck = deck_with_notes(1, &["a", "b"]);
+        deck.add_note(
+            Note::new(crate::basic_and_reversed_card_model(), vec!["c", "d"])
+                .unwrap()
+                .with_cards(vec![Card::new(1, false)]),
+        );
+        deck.transform_fields(|i, field| match (i, field) {
+            (0, "b") => String::new(),
+            (_, field) => format!("<div>{}</div>", field),
+        })
+        .unwrap();
+        assert_eq!(deck.notes()[0].fields(), ["<div>a</div>", "<div>back</div>"]);
+        assert_eq!(deck.notes()[0].cards().len(), 1);
+        // The front is empty now, so the card is gone
+        assert!(deck.notes()[1].cards().is_empty());
+        // Explicitly set cards are kept
+        assert_eq!(deck.notes()[2].cards()[0].ord, 1);
+    }
+
+    #[test]
+    fn extract_notes_keeps_order() {
+        let mut deck = deck_with_notes(1, &["a1", "b1", "a2", "b2"]);
+        let extracted = deck.extract_notes(|note| note.fields()[0].starts_with('a'));
+        assert_eq!(
+            extracted.iter().map(|note| note.fields()[0].as_str()).collect::<Vec<_>>(),
+            vec!["a1", "a2"]
+        );
+        assert_eq!(fronts(&deck), vec!["b1", "b2"]);
+    }
+
+    #[test]
+    fn move_notes_to_other_deck() {
+        let mut deck = deck_with_notes(1, &["a1", "b1", "a2"]);
+        let mut other = deck_with_notes(2, &["c1"]);
+        assert_eq!(deck.move_notes_to(&mut other, |note| note.fields()[0].starts_with('a')), 2);
+        assert_eq!(fronts(&deck), vec!["b1"]);
+        assert_eq!(fronts(&other), vec!["c1", "a1", "a2"]);
+    }
+}
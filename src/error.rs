@@ -1,7 +1,8 @@
-use std::{convert::Infallible, time::SystemTimeError};
+use std::{convert::Infallible, path::PathBuf, time::SystemTimeError};
 
 use zip::result::ZipError;
 
+use crate::collection_conf::SchedulerVersion;
 use crate::db_entries::Tmpl;
 
 // Make sure `Error` is `Send` and `Sync`
@@ -31,10 +32,78 @@ pub enum Error {
     JsonParser(Box<dyn std::error::Error + Send + Sync>),
     #[error("Could not compute required fields for this template; please check the formatting of \"qfmt\": {0:?}")]
     TemplateFormat(Tmpl),
-    #[error("number of model field ({0}) does not match number of fields ({1})")]
+    #[error("template names must not be empty")]
+    EmptyTemplateName,
+    #[error("template name \"{0}\" is used more than once in the model")]
+    DuplicateTemplateName(String),
+    #[error("{0:?} is not a permutation of the indices of the {1} templates")]
+    InvalidTemplateOrder(Vec<usize>, usize),
+    #[error("the generated collection failed the integrity check: {}", .0.join("; "))]
+    IntegrityCheck(Vec<String>),
+    #[error("ease {0} of a review log entry is not a button, answered reviews need an ease from 1 to 4")]
+    InvalidEase(i32),
+    #[error("review type {0} of a review log entry is not an answered review, the types are 0 (learn) to 3 (cram)")]
+    InvalidReviewType(i32),
+    #[error("time {0} of a review log entry is negative, it is the time taken to answer in milliseconds")]
+    NegativeReviewTime(i32),
+    #[error("\"{0}\" is not a date of the form YYYY-MM-DD")]
+    InvalidDate(String),
+    #[error("a card of type {0} in queue {1} isn't due in {2}")]
+    DueKindMismatch(i32, i32, &'static str),
+    #[error("CSS of model \"{0}\" is invalid: {1}")]
+    InvalidCss(String, String),
+    #[error("CSS of model \"{0}\" refers to \"{1}\", which is not a media file of the package")]
+    MissingCssMedia(String, String),
+    #[error("GUID \"{0}\" is used by more than one note of the package")]
+    DuplicateNoteGuid(String),
+    #[error("model {0} of a note is not registered in deck {1}")]
+    UnregisteredModel(i64, i64),
+    #[error("wrong number of fields for the model of the note: expected {0}, got {1}")]
     ModelFieldCountMismatch(usize, usize),
+    #[error("the first field of the note is empty, Anki does not import such notes")]
+    EmptyFirstField,
     #[error("One of the tags contains whitespace, this is not allowed!")]
     TagContainsWhitespace,
+    #[error("template \"{0}\" references field \"{1}\" (index {2}) but the note only has {3} fields")]
+    TemplateFieldOutOfRange(String, String, usize, usize),
+    #[error("card ord {0} is out of range for a model with {1} templates")]
+    CardOrdOutOfRange(i64, usize),
+    #[error("filtered deck \"{0}\" can't be written for scheduler {1:?}: {2}")]
+    FilteredDeckScheduler(String, SchedulerVersion, String),
+    #[error("default deck {1} of model {0} is not a deck of the package")]
+    UnknownDefaultDeck(i64, i64),
+    #[error("deck {0} uses the options preset {1}, which is not a deck config of the package")]
+    UnknownDeckConfig(i64, i64),
+    #[error("model {0} is not used by any note in the package")]
+    ModelNotFound(i64),
+    #[error("invalid package: {0}")]
+    InvalidPackage(String),
+    #[error("model id {0} is already used by another model in the package")]
+    ModelIdInUse(i64),
+    #[error("card id {0} is pinned by more than one card in the package")]
+    DuplicateCardId(i64),
+    #[error("note id {0} is pinned by more than one note in the package")]
+    DuplicateNoteId(i64),
+    #[error("collection id {0} is not positive")]
+    InvalidCollectionId(i64),
+    #[error("collection conf has to be a JSON object, got {0}")]
+    InvalidCollectionConf(String),
+    #[error("duplicate zip entry name \"{0}\" in package")]
+    DuplicateZipEntry(String),
+    #[error("media files {1:?} and {2:?} are both stored as \"{0}\", give one of them another name with `Package::add_media_with_name`")]
+    MediaCollision(String, PathBuf, PathBuf),
+    #[error("media file {0:?} is a directory, use `Package::add_media_directory` to add the files in it")]
+    MediaIsDirectory(PathBuf),
+    #[error("media files {paths:?} are missing, unreadable or have no usable filename")]
+    MediaNotFound { paths: Vec<PathBuf> },
+    /// Wraps another error with a description of what was being done when it happened, see
+    /// [`Error::context`]
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        #[source]
+        source: Box<Error>,
+    },
     #[error(transparent)]
     Io(#[from] std::io::Error),
     /// Indicates an error with the underlying template system
@@ -64,6 +133,26 @@ impl From<Infallible> for Error {
     }
 }
 
+impl Error {
+    /// Wraps this error with a description of what was being done when it happened
+    ///
+    /// ```rust
+    /// use genanki_rs::Error;
+    ///
+    /// let error = Error::TagContainsWhitespace.context("writing note 3");
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "writing note 3: One of the tags contains whitespace, this is not allowed!"
+    /// );
+    /// ```
+    pub fn context(self, context: impl Into<String>) -> Self {
+        Error::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+}
+
 pub(crate) fn database_error(e: rusqlite::Error) -> Error {
     Error::Template(Box::new(e))
 }
@@ -0,0 +1,223 @@
+use serde_json::{json, Value};
+
+use crate::collection_conf::SchedulerVersion;
+use crate::deck::native_deck_name;
+use crate::package::DeckInfoEntry;
+use crate::proto::ProtoWriter;
+use crate::Error;
+
+/// One search of a filtered deck: cards matching `search` are pulled in, at most `limit` of them,
+/// selected by `order`
+#[derive(Debug, Clone)]
+pub struct FilteredDeckTerm {
+    pub search: String,
+    pub limit: u32,
+    /// 0=oldest seen first, 1=random, 2=increasing intervals, 3=decreasing intervals, 4=most lapses,
+    /// 5=order added, 6=order due, 7=latest added first, 8=relative overdueness
+    pub order: i64,
+}
+
+impl FilteredDeckTerm {
+    pub fn new(search: &str, limit: u32, order: i64) -> Self {
+        Self {
+            search: search.to_string(),
+            limit,
+            order,
+        }
+    }
+}
+
+/// How cards are shown again when previewing (not rescheduling) in a filtered deck
+///
+/// The setting differs between scheduler versions, see [`FilteredDeck::preview`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilteredDeckPreview {
+    /// Scheduler default
+    Default,
+    /// Delay in minutes after pressing "Again" (v2 scheduler)
+    DelayMinutes(u32),
+    /// Delays in seconds after pressing "Again", "Hard" and "Good" (v3 scheduler)
+    DelaySecs { again: u32, hard: u32, good: u32 },
+}
+
+/// A filtered (dynamic) deck, which temporarily pulls in cards from other decks
+///
+/// Filtered decks are written in the format of the scheduler version set with
+/// `Package::set_scheduler_version` (v1 if it isn't set). Writing fails if the deck uses settings that
+/// scheduler version doesn't support.
+///
+/// ```rust
+/// use genanki_rs::{FilteredDeck, FilteredDeckTerm, Package, SchedulerVersion};
+///
+/// # fn main() -> Result<(), genanki_rs::Error> {
+/// let mut package = Package::new(vec![], vec![])?;
+/// package.set_scheduler_version(SchedulerVersion::V3);
+/// package.add_filtered_deck(
+///     FilteredDeck::new(1234, "Cram")
+///         .term(FilteredDeckTerm::new("deck:Capitals is:due", 100, 6))
+///         .term(FilteredDeckTerm::new("deck:Capitals is:new", 20, 5)),
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FilteredDeck {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    terms: Vec<FilteredDeckTerm>,
+    reschedule: bool,
+    preview: FilteredDeckPreview,
+}
+
+impl FilteredDeck {
+    /// Creates a new filtered deck with an `id` and `name`, which reschedules cards based on the answers
+    pub fn new(id: i64, name: &str) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+            description: String::new(),
+            terms: vec![],
+            reschedule: true,
+            preview: FilteredDeckPreview::Default,
+        }
+    }
+
+    /// Adds a search to the deck, the v1 scheduler supports one and the v2 and v3 schedulers two
+    pub fn term(mut self, term: FilteredDeckTerm) -> Self {
+        self.terms.push(term);
+        self
+    }
+
+    /// Sets whether answers in the deck reschedule the cards
+    pub fn reschedule(self, reschedule: bool) -> Self {
+        Self { reschedule, ..self }
+    }
+
+    /// Sets the preview delays used when the deck doesn't reschedule cards
+    pub fn preview(self, preview: FilteredDeckPreview) -> Self {
+        Self { preview, ..self }
+    }
+
+    pub fn terms(&self) -> &[FilteredDeckTerm] {
+        &self.terms
+    }
+
+    /// Returns `Err` if the deck can't be represented for the `scheduler`
+    pub(crate) fn check_scheduler(&self, scheduler: SchedulerVersion) -> Result<(), Error> {
+        let max_terms = match scheduler {
+            SchedulerVersion::V1 => 1,
+            SchedulerVersion::V2 | SchedulerVersion::V3 => 2,
+        };
+        let unsupported = |reason: &str| {
+            Err(Error::FilteredDeckScheduler(
+                self.name.clone(),
+                scheduler,
+                reason.to_string(),
+            ))
+        };
+        if self.terms.is_empty() {
+            return unsupported("a filtered deck needs at least one search");
+        }
+        if self.terms.len() > max_terms {
+            return unsupported(&format!("at most {} searches are supported", max_terms));
+        }
+        match (&self.preview, scheduler) {
+            (FilteredDeckPreview::Default, _)
+            | (FilteredDeckPreview::DelayMinutes(_), SchedulerVersion::V2)
+            | (FilteredDeckPreview::DelaySecs { .. }, SchedulerVersion::V3) => Ok(()),
+            (FilteredDeckPreview::DelayMinutes(_), _) => {
+                unsupported("preview delays in minutes need the v2 scheduler")
+            }
+            (FilteredDeckPreview::DelaySecs { .. }, _) => {
+                unsupported("preview delays in seconds need the v3 scheduler")
+            }
+        }
+    }
+
+    /// Returns the row of the `decks` table describing this deck in the format of the `scheduler`, used
+    /// from collection version 16 on
+    pub(crate) fn to_deck_info_entry(
+        &self,
+        scheduler: SchedulerVersion,
+        timestamp: f64,
+    ) -> Result<DeckInfoEntry, Error> {
+        self.check_scheduler(scheduler)?;
+        let filtered = self
+            .terms
+            .iter()
+            .fold(ProtoWriter::new().bool(1, self.reschedule), |filtered, term| {
+                let term = ProtoWriter::new()
+                    .string(1, &term.search)
+                    .int(2, i64::from(term.limit))
+                    .int(3, term.order);
+                filtered.message(2, &term.finish())
+            });
+        let filtered = match (scheduler, &self.preview) {
+            (SchedulerVersion::V1, _) => filtered,
+            (SchedulerVersion::V2, FilteredDeckPreview::DelayMinutes(minutes)) => filtered.int(4, i64::from(*minutes)),
+            (SchedulerVersion::V2, _) => filtered.int(4, 10),
+            (SchedulerVersion::V3, FilteredDeckPreview::DelaySecs { again, hard, good }) => filtered
+                .int(7, i64::from(*again))
+                .int(5, i64::from(*hard))
+                .int(6, i64::from(*good)),
+            (SchedulerVersion::V3, _) => filtered.int(7, 60).int(5, 600),
+        };
+        Ok(DeckInfoEntry {
+            id: self.id,
+            name: native_deck_name(&self.name),
+            mtime_secs: timestamp as i64,
+            usn: -1,
+            common: vec![],
+            kind: ProtoWriter::new().message(2, &filtered.finish()).finish(),
+        })
+    }
+
+    /// Returns the legacy `col.decks` entry of this deck in the format of the `scheduler`
+    pub(crate) fn to_json(&self, scheduler: SchedulerVersion, timestamp: f64) -> Result<Value, Error> {
+        self.check_scheduler(scheduler)?;
+        let mut deck = json!({
+            "id": self.id,
+            "name": self.name,
+            "desc": self.description,
+            "mod": timestamp as i64,
+            "usn": -1,
+            "dyn": 1,
+            "collapsed": false,
+            "browserCollapsed": false,
+            "newToday": [0, 0],
+            "revToday": [0, 0],
+            "lrnToday": [0, 0],
+            "timeToday": [0, 0],
+            "terms": self
+                .terms
+                .iter()
+                .map(|term| json!([term.search, term.limit, term.order]))
+                .collect::<Vec<_>>(),
+            "resched": self.reschedule,
+            "separate": true,
+            "return": true,
+            "delays": null,
+        });
+        match (scheduler, &self.preview) {
+            (SchedulerVersion::V1, _) => {}
+            (SchedulerVersion::V2, FilteredDeckPreview::DelayMinutes(minutes)) => {
+                deck["previewDelay"] = json!(minutes);
+            }
+            (SchedulerVersion::V2, _) => {
+                deck["previewDelay"] = json!(10);
+            }
+            (SchedulerVersion::V3, FilteredDeckPreview::DelaySecs { again, hard, good }) => {
+                deck["previewAgainSecs"] = json!(again);
+                deck["previewHardSecs"] = json!(hard);
+                deck["previewGoodSecs"] = json!(good);
+            }
+            (SchedulerVersion::V3, _) => {
+                deck["previewAgainSecs"] = json!(60);
+                deck["previewHardSecs"] = json!(600);
+                deck["previewGoodSecs"] = json!(0);
+            }
+        }
+        Ok(deck)
+    }
+}
@@ -0,0 +1,112 @@
+use crate::package::MediaFile;
+use crate::Error;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+/// Media files shared by several packages, e.g. one asset library used by many decks
+///
+/// Files with the same content are only held once. A package the pool is given to with
+/// [`Package::set_media_pool`](crate::Package::set_media_pool) only includes the files of the pool its
+/// notes, templates and CSS refer to.
+///
+/// Example:
+/// ```rust
+/// use genanki_rs::{basic_model, Deck, MediaFile, MediaPool, Note, Package};
+/// use std::sync::Arc;
+///
+/// # fn main() -> Result<(), genanki_rs::Error> {
+/// let mut pool = MediaPool::new();
+/// pool.add(MediaFile::new_from_bytes(b"...", "paris.jpg"))?;
+/// pool.add(MediaFile::new_from_bytes(b"...", "berlin.jpg"))?;
+/// let pool = Arc::new(pool);
+///
+/// let mut deck = Deck::new(1234, "France", "");
+/// deck.add_note(Note::new(basic_model(), vec!["Capital of France?", r#"<img src="paris.jpg">"#])?);
+/// let mut package = Package::new_from_memory(vec![deck], vec![])?;
+/// package.set_media_pool(Arc::clone(&pool));
+/// // only paris.jpg is written
+/// package.write(std::io::Cursor::new(Vec::new()))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MediaPool {
+    contents: Vec<Arc<[u8]>>,
+    content_by_hash: HashMap<[u8; 20], usize>,
+    files: BTreeMap<String, usize>,
+}
+
+impl MediaPool {
+    /// Creates an empty pool
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a media file to the pool, reading it right away if it is a path
+    ///
+    /// A file added under a name that is already in the pool replaces the earlier one.
+    pub fn add(&mut self, media_file: MediaFile) -> Result<(), Error> {
+        media_file.check_not_directory()?;
+        let bytes = media_file.read_bytes()?;
+        let hash: [u8; 20] = Sha1::digest(&bytes).into();
+        let content = match self.content_by_hash.get(&hash) {
+            Some(&content) => content,
+            None => {
+                self.contents.push(bytes.into());
+                self.content_by_hash.insert(hash, self.contents.len() - 1);
+                self.contents.len() - 1
+            }
+        };
+        self.files.insert(media_file.name().to_string(), content);
+        Ok(())
+    }
+
+    /// Number of file names in the pool
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether the pool has no files
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Number of distinct file contents among the files of the pool
+    pub fn unique_contents(&self) -> usize {
+        self.files.values().collect::<HashSet<_>>().len()
+    }
+
+    /// Whether the pool has a file with the given name
+    pub fn contains(&self, name: &str) -> bool {
+        self.files.contains_key(name)
+    }
+
+    /// Returns the files of the pool sorted by name
+    pub(crate) fn files(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.files
+            .iter()
+            .map(|(name, &content)| (name.as_str(), &*self.contents[content]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_contents_are_held_once() {
+        let mut pool = MediaPool::new();
+        pool.add(MediaFile::new_from_bytes(b"logo", "logo.png")).unwrap();
+        pool.add(MediaFile::new_from_bytes(b"logo", "_logo.png")).unwrap();
+        pool.add(MediaFile::new_from_bytes(b"map", "map.png")).unwrap();
+        assert_eq!((pool.len(), pool.unique_contents()), (3, 2));
+        pool.add(MediaFile::new_from_bytes(b"new map", "map.png")).unwrap();
+        assert_eq!((pool.len(), pool.unique_contents()), (3, 2));
+        assert_eq!(
+            pool.files().collect::<Vec<_>>(),
+            [("_logo.png", &b"logo"[..]), ("logo.png", b"logo"), ("map.png", b"new map")]
+        );
+        assert!(pool.contains("logo.png") && !pool.contains("other.png"));
+    }
+}
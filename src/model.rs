@@ -1,10 +1,14 @@
 use crate::builders::Template;
 use crate::db_entries::{Fld, ModelDbEntry, Tmpl};
 use crate::error::{json_error, template_error};
-use crate::{Error, Field};
+use crate::package::{FieldEntry, NotetypeEntry, TemplateEntry};
+use crate::proto::ProtoWriter;
+use crate::note::{check_template_fields_in_range, cloze_card_ords, generate_cards};
+use crate::{Error, Field, Note};
 use fancy_regex::Regex;
+use once_cell::sync::Lazy;
 use ramhorns::Template as RamTemplate;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 const DEFAULT_LATEX_PRE: &str = r#"
@@ -20,6 +24,19 @@ const DEFAULT_LATEX_PRE: &str = r#"
 const DEFAULT_LATEX_POST: &str = r"\end{document}";
 const SENTINEL: &str = "SeNtInEl";
 
+static TAG_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{\{\{?([#/^]?)([^{}]*?)\}?\}\}").expect("static regex"));
+
+static CSS_COMMENT_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)/\*.*?\*/").expect("static regex"));
+
+static CSS_REF_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)\burl\(\s*(?:"([^"]*)"|'([^']*)'|([^)\s"']*))\s*\)|@import\s+(?:"([^"]*)"|'([^']*)')"#)
+        .expect("static regex")
+});
+
+/// The rows of the `notetypes`, `fields` and `templates` tables describing a model
+pub(crate) type NormalizedModel = (NotetypeEntry, Vec<FieldEntry>, Vec<TemplateEntry>);
+
 /// `FrontBack` or `Cloze` to determine the type of a Model.
 ///
 /// When creating a Model, the default is `FrontBack`
@@ -51,6 +68,7 @@ pub struct Model {
     latex_post: String,
     sort_field_index: i64,
     latex_svg: bool,
+    default_deck: Option<i64>,
     sentinel_regexes: Arc<Vec<Regex>>, // Arc<_> so this can be clone and Sync
 }
 
@@ -82,7 +100,8 @@ impl Model {
             latex_post: DEFAULT_LATEX_POST.to_string(),
             sort_field_index: 0,
             latex_svg: false,
-            sentinel_regexes: compile_sentinel_regexes(&fields),
+            default_deck: None,
+            sentinel_regexes: compile_sentinel_regexes(fields.len()),
         }
     }
 
@@ -117,13 +136,15 @@ impl Model {
             latex_post: latex_post.unwrap_or(DEFAULT_LATEX_POST).to_string(),
             sort_field_index: sort_field_index.unwrap_or(0),
             latex_svg: latex_svg.unwrap_or(false),
-            sentinel_regexes: compile_sentinel_regexes(&fields),
+            default_deck: None,
+            sentinel_regexes: compile_sentinel_regexes(fields.len()),
         }
     }
 
     /// Adds an additional field to the model
     pub fn with_field(mut self, field: Field) -> Self {
         self.fields.push(field.into());
+        self.sentinel_regexes = compile_sentinel_regexes(self.fields.len());
         self
     }
 
@@ -170,14 +191,31 @@ impl Model {
         }
     }
 
+    /// Sets the deck Anki's Add dialog selects for new notes of this model
+    ///
+    /// By default this is the first deck of the package with notes of this model. Writing the package
+    /// returns `Err` if `deck_id` is not one of its decks.
+    pub fn default_deck(self, deck_id: i64) -> Self {
+        Self {
+            default_deck: Some(deck_id),
+            ..self
+        }
+    }
+
+    /// Returns the deck set with [`Model::default_deck`]
+    pub fn get_default_deck(&self) -> Option<i64> {
+        self.default_deck
+    }
+
     pub fn req(&self) -> Result<Vec<(usize, String, Vec<usize>)>, Error> {
         let field_names: Vec<String> = self.fields.iter().map(|field| field.name.clone()).collect();
-        let field_values = field_names
+        let placeholders: Vec<String> = (0..field_names.len()).map(field_placeholder).collect();
+        let field_values = placeholders
             .iter()
             .map(|field| (field.as_str(), format!("{}{}", &field, &SENTINEL)));
         let mut req = Vec::new();
         for (template_ord, template) in self.templates.iter().enumerate() {
-            let rendered = RamTemplate::new(template.qfmt.clone())
+            let rendered = RamTemplate::new(placeholder_qfmt(&template.qfmt, &field_names))
                 .map_err(template_error)?
                 .render::<HashMap<&str, String>>(&field_values.clone().collect());
             let required_fields = field_values
@@ -204,6 +242,65 @@ impl Model {
         Ok(req)
     }
 
+    /// Whether this is a cloze model, whose notes get a card per cloze number instead of one per template
+    pub fn is_cloze(&self) -> bool {
+        self.model_type == ModelType::Cloze
+    }
+
+    /// Returns how many cards this model generates for the fields of `note`
+    ///
+    /// A standard model generates a card for each template whose required fields are filled in, a cloze
+    /// model one for each distinct cloze number. Like in Anki, a cloze note without cloze deletions still
+    /// gets the card of `c1`, see [`Model::has_cloze_deletions`]. Returns 0 if the templates reference
+    /// fields the note doesn't have or can't be parsed.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_and_reversed_card_model, cloze_model, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(cloze_model(), vec!["{{c1::Paris}} is the capital of {{c2::France}}"])?;
+    /// assert_eq!(cloze_model().cards_per_note(&note), 2);
+    /// let note = Note::new(basic_and_reversed_card_model(), vec!["France", "Paris"])?;
+    /// assert_eq!(basic_and_reversed_card_model().cards_per_note(&note), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cards_per_note(&self, note: &Note) -> usize {
+        if check_template_fields_in_range(self, note.fields()).is_err() {
+            return 0;
+        }
+        generate_cards(self, note.fields()).map_or(0, |cards| cards.len())
+    }
+
+    /// Whether this is a cloze model and the fields of `note` contain cloze deletions like `{{c1::...}}`
+    /// in a field the cloze template uses
+    pub fn has_cloze_deletions(&self, note: &Note) -> bool {
+        self.is_cloze()
+            && check_template_fields_in_range(self, note.fields()).is_ok()
+            && !cloze_card_ords(self, note.fields()).is_empty()
+    }
+
+    /// Returns every field the templates reference as `(template name, field name, field ord)`,
+    /// including fields used in sections and with filters like `{{type:Answer}}`
+    pub(crate) fn template_field_refs(&self) -> Vec<(&str, &str, usize)> {
+        let mut refs = Vec::new();
+        for template in &self.templates {
+            for format in [&template.qfmt, &template.afmt] {
+                for captures in TAG_REGEX.captures_iter(format).flatten() {
+                    let tag = captures.get(2).map_or("", |m| m.as_str().trim());
+                    let name = tag.rsplit(':').next().unwrap_or(tag);
+                    if let Some(field_ord) = self.fields.iter().position(|field| field.name == name)
+                        && !refs.contains(&(template.name.as_str(), name, field_ord))
+                    {
+                        refs.push((template.name.as_str(), name, field_ord));
+                    }
+                }
+            }
+        }
+        refs
+    }
+
     pub fn fields(&self) -> Vec<Fld> {
         self.fields.clone()
     }
@@ -218,6 +315,7 @@ impl Model {
         timestamp: f64,
         deck_id: i64,
     ) -> Result<ModelDbEntry, Error> {
+        self.check_template_names()?;
         self.templates
             .iter_mut()
             .enumerate()
@@ -235,7 +333,7 @@ impl Model {
             vers: vec![],
             name: self.name.clone(),
             tags: vec![],
-            did: deck_id,
+            did: self.default_deck.unwrap_or(deck_id),
             usn: -1,
             req: self.req()?.clone(),
             flds: self.fields.clone(),
@@ -250,6 +348,187 @@ impl Model {
         })
     }
 
+    /// Returns the rows of the `notetypes`, `fields` and `templates` tables describing this model, used
+    /// from collection version 16 on
+    pub(crate) fn normalized_entries(&mut self, timestamp: f64) -> Result<NormalizedModel, Error> {
+        let entry = self.to_model_db_entry(timestamp, 1)?;
+        let mtime_secs = timestamp as i64;
+        let config = entry
+            .req
+            .iter()
+            .fold(ProtoWriter::new(), |config, (card_ord, kind, field_ords)| {
+                let kind = match kind.as_str() {
+                    "any" => 1,
+                    "all" => 2,
+                    _ => 0,
+                };
+                let field_ords: Vec<u32> = field_ords.iter().map(|&ord| ord as u32).collect();
+                let requirement = ProtoWriter::new()
+                    .int(1, *card_ord as i64)
+                    .int(2, kind)
+                    .packed_uints(3, &field_ords);
+                config.message(8, &requirement.finish())
+            })
+            .int(1, entry.model_db_entry_type)
+            .int(2, entry.sortf)
+            .string(3, &entry.css)
+            .string(5, &entry.latex_pre)
+            .string(6, &entry.latex_post)
+            .bool(7, self.latex_svg);
+        let notetype = NotetypeEntry {
+            id: self.id,
+            name: entry.name,
+            mtime_secs,
+            usn: -1,
+            config: config.finish(),
+        };
+        let fields = entry
+            .flds
+            .iter()
+            .map(|field| FieldEntry {
+                ntid: self.id,
+                ord: field.ord,
+                name: field.name.clone(),
+                config: ProtoWriter::new()
+                    .bool(1, field.sticky)
+                    .bool(2, field.rtl)
+                    .string(3, &field.font)
+                    .int(4, field.size)
+                    .bool(6, field.plain_text)
+                    .finish(),
+            })
+            .collect();
+        let templates = entry
+            .tmpls
+            .iter()
+            .map(|template| TemplateEntry {
+                ntid: self.id,
+                ord: template.ord,
+                name: template.name.clone(),
+                mtime_secs,
+                usn: -1,
+                config: ProtoWriter::new()
+                    .string(1, &template.qfmt)
+                    .string(2, &template.afmt)
+                    .string(3, &template.bqfmt)
+                    .string(4, &template.bafmt)
+                    .int(5, template.did.unwrap_or(0) as i64)
+                    .finish(),
+            })
+            .collect();
+        Ok((notetype, fields, templates))
+    }
+
+    /// Reorders the templates so that the template at index `new_order[i]` becomes the `i`th one,
+    /// which changes the ord of the cards generated from it
+    ///
+    /// Notes copy their model when they are created, so reorder the templates before creating notes.
+    ///
+    /// Returns `Err` if `new_order` is not a permutation of all template indices
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::basic_and_reversed_card_model;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut model = basic_and_reversed_card_model();
+    /// model.reorder_templates(&[1, 0])?;
+    /// assert_eq!(model.templates()[0].name, "Card 2");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reorder_templates(&mut self, new_order: &[usize]) -> Result<(), Error> {
+        let mut seen = vec![false; self.templates.len()];
+        let is_permutation = new_order.len() == self.templates.len()
+            && new_order
+                .iter()
+                .all(|&i| i < seen.len() && !std::mem::replace(&mut seen[i], true));
+        if !is_permutation {
+            return Err(Error::InvalidTemplateOrder(new_order.to_vec(), self.templates.len()));
+        }
+        self.templates = new_order.iter().map(|&i| self.templates[i].clone()).collect();
+        for (ord, template) in self.templates.iter_mut().enumerate() {
+            template.ord = ord as i64;
+        }
+        Ok(())
+    }
+
+    /// Checks that the braces of the model's CSS are balanced and that its comments and strings are
+    /// closed, the mistakes that make Anki drop the styling of the cards without telling
+    ///
+    /// This is not a CSS parser, use [`Package::validate_css`](crate::Package::validate_css) to also
+    /// check that the files the CSS imports are in the package.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Error};
+    ///
+    /// assert!(basic_model().validate_css().is_ok());
+    /// let broken = basic_model().css(".card { color: red;");
+    /// assert!(matches!(broken.validate_css(), Err(Error::InvalidCss(..))));
+    /// ```
+    pub fn validate_css(&self) -> Result<(), Error> {
+        let invalid = |problem: String| Error::InvalidCss(self.name.clone(), problem);
+        let mut chars = self.css.char_indices().peekable();
+        let mut open_braces = Vec::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '/' if chars.next_if(|&(_, c)| c == '*').is_some() => {
+                    let mut previous = ' ';
+                    if !chars.by_ref().any(|(_, c)| std::mem::replace(&mut previous, c) == '*' && c == '/') {
+                        return Err(invalid(format!("comment at byte {} is not closed", i)));
+                    }
+                }
+                '"' | '\'' => {
+                    let mut escaped = false;
+                    let closed = chars.by_ref().any(|(_, next)| {
+                        let closes = !escaped && next == c;
+                        escaped = !escaped && next == '\\';
+                        closes || next == '\n'
+                    });
+                    if !closed {
+                        return Err(invalid(format!("string at byte {} is not closed", i)));
+                    }
+                }
+                '{' => open_braces.push(i),
+                '}' if open_braces.pop().is_none() => {
+                    return Err(invalid(format!("`}}` at byte {} has no matching `{{`", i)));
+                }
+                _ => {}
+            }
+        }
+        match open_braces.last() {
+            Some(i) => Err(invalid(format!("`{{` at byte {} is not closed", i))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the files the model's CSS refers to with `url(...)` or `@import`, outside of comments
+    pub fn css_media_refs(&self) -> Vec<String> {
+        let css = CSS_COMMENT_REGEX.replace_all(&self.css, "");
+        CSS_REF_REGEX
+            .captures_iter(&css)
+            .flatten()
+            .filter_map(|captures| (1..=5).find_map(|group| captures.get(group)))
+            .map(|reference| reference.as_str().trim().to_string())
+            .filter(|reference| !reference.is_empty())
+            .collect()
+    }
+
+    /// Returns `Err` if a template name is empty or used by more than one template
+    fn check_template_names(&self) -> Result<(), Error> {
+        let mut names = HashSet::new();
+        for template in &self.templates {
+            if template.name.trim().is_empty() {
+                return Err(Error::EmptyTemplateName);
+            }
+            if !names.insert(template.name.as_str()) {
+                return Err(Error::DuplicateTemplateName(template.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
     pub fn to_json(&mut self, timestamp: f64, deck_id: i64) -> Result<String, Error> {
         Ok(
             serde_json::to_string(&self.to_model_db_entry(timestamp, deck_id)?)
@@ -270,14 +549,49 @@ impl Model {
     pub fn model_type_val(&self) -> ModelType { self.model_type.clone() }
 }
 
-fn compile_sentinel_regexes(fields: &[Field]) -> Arc<Vec<Regex>> {
+/// Name standing in for the field with `field_ord` when rendering templates in `Model::req`
+fn field_placeholder(field_ord: usize) -> String {
+    format!("f{}", field_ord)
+}
+
+/// Replaces the names in all `{{...}}` tags of `qfmt` with plain alphanumeric placeholders, as
+/// ramhorns can't handle field names containing spaces like `{{#Add Reverse}}`
+///
+/// Fields become `field_placeholder(ord)`, every other name (e.g. `FrontSide` or `type:Back`) a
+/// placeholder that doesn't render to anything.
+fn placeholder_qfmt(qfmt: &str, field_names: &[String]) -> String {
+    let mut other_names: Vec<&str> = Vec::new();
+    let mut rewritten = String::with_capacity(qfmt.len());
+    let mut last_end = 0;
+    for captures in TAG_REGEX.captures_iter(qfmt).flatten() {
+        let tag = captures.get(0).expect("whole match");
+        let sigil = captures.get(1).map_or("", |m| m.as_str());
+        let name = captures.get(2).map_or("", |m| m.as_str().trim());
+        let placeholder = match field_names.iter().position(|field| field == name) {
+            Some(field_ord) => field_placeholder(field_ord),
+            None => {
+                let other_ord = other_names.iter().position(|&other| other == name).unwrap_or_else(|| {
+                    other_names.push(name);
+                    other_names.len() - 1
+                });
+                format!("o{}", other_ord)
+            }
+        };
+        rewritten.push_str(&qfmt[last_end..tag.start()]);
+        rewritten.push_str(&format!("{{{{{}{}}}}}", sigil, placeholder));
+        last_end = tag.end();
+    }
+    rewritten.push_str(&qfmt[last_end..]);
+    rewritten
+}
+
+fn compile_sentinel_regexes(num_fields: usize) -> Arc<Vec<Regex>> {
     Arc::new(
-        fields
-            .iter()
-            .map(|f| {
+        (0..num_fields)
+            .map(|field_ord| {
                 Regex::new(&format!(
                     "(?!{field}{sentinel}\\b)\\b(\\w)*{sentinel}+",
-                    field = f.name(),
+                    field = field_placeholder(field_ord),
                     sentinel = SENTINEL
                 ))
                 .unwrap()
@@ -289,9 +603,7 @@ fn compile_sentinel_regexes(fields: &[Field]) -> Arc<Vec<Regex>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Deck, Note};
-    use std::collections::HashSet;
-    use tempfile::NamedTempFile;
+    use crate::{basic_model, Deck, Note};
 
     fn css() -> String {
         r#".card {
@@ -347,6 +659,28 @@ mod tests {
         )
     }
 
+    #[test]
+    fn notes_without_the_template_fields_get_no_cards() {
+        let with_extra = Model::new(
+            1894808898,
+            "Test Model",
+            vec![Field::new("Question"), Field::new("Answer"), Field::new("Extra")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Question}}")
+                .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}{{#Extra}}<br>{{text:Extra}}{{/Extra}}"#)],
+        );
+        let basic_note = Note::new(basic_model(), vec!["Capital of Germany", "Berlin"]).unwrap();
+        assert_eq!(with_extra.cards_per_note(&basic_note), 0);
+        let own_note = Note::new(with_extra.clone(), vec!["Capital of Germany", "Berlin", ""]).unwrap();
+        assert_eq!(with_extra.cards_per_note(&own_note), 1);
+
+        let one_field_note = Note::new(crate::cloze_model(), vec!["{{c1::Berlin}}"]).unwrap();
+        assert!(!multi_field_cloze_model().has_cloze_deletions(&one_field_note));
+        assert_eq!(multi_field_cloze_model().cards_per_note(&one_field_note), 0);
+        let two_field_note = Note::new(multi_field_cloze_model(), vec!["{{c1::Berlin}}", ""]).unwrap();
+        assert!(multi_field_cloze_model().has_cloze_deletions(&two_field_note));
+    }
+
     #[test]
     fn cloze() {
         let mut notes = vec![];
@@ -419,8 +753,11 @@ mod tests {
 
         let mut deck = Deck::new(0, "test", "");
         notes.iter().for_each(|note| deck.add_note(note.clone()));
-        let out_file = NamedTempFile::new().unwrap().into_temp_path();
-        deck.write_to_file(out_file.to_str().unwrap()).unwrap();
+        #[cfg(feature = "fs")]
+        {
+            let out_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+            deck.write_to_file(out_file.to_str().unwrap()).unwrap();
+        }
     }
 
     #[test]
@@ -484,4 +821,91 @@ mod tests {
             .sort_field_index(1)
             .model_type(ModelType::FrontBack);
     }
+
+    #[test]
+    fn template_names_must_be_unique_and_non_empty() {
+        let mut template = Template::new("Card 1").qfmt("{{front}}").afmt("{{back}}");
+        assert!(matches!(template.rename(" "), Err(Error::EmptyTemplateName)));
+        assert_eq!(template.name(), "Card 1");
+        template.rename("Forward").unwrap();
+        assert_eq!(template.name(), "Forward");
+
+        let fields = vec![Field::new("front"), Field::new("back")];
+        let mut model = Model::new(12345, "test model", fields.clone(), vec![template.clone()]);
+        assert!(model.to_model_db_entry(0.0, 1).is_ok());
+
+        let mut model = Model::new(12345, "test model", fields, vec![template.clone(), template]);
+        assert!(matches!(
+            model.to_model_db_entry(0.0, 1),
+            Err(Error::DuplicateTemplateName(name)) if name == "Forward"
+        ));
+    }
+
+    #[test]
+    fn reorder_templates() {
+        let templates = ["Card 1", "Card 2", "Card 3"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Template::new(name).qfmt(&format!("{{{{f{}}}}}", i)).afmt("{{FrontSide}}"))
+            .collect();
+        let fields = vec![Field::new("f0"), Field::new("f1"), Field::new("f2")];
+        let mut model = Model::new(12345, "test model", fields, templates);
+
+        for invalid in [&[0, 1][..], &[0, 1, 1], &[0, 1, 3], &[0, 1, 2, 3]] {
+            assert!(matches!(
+                model.reorder_templates(invalid),
+                Err(Error::InvalidTemplateOrder(_, 3))
+            ));
+        }
+        model.reorder_templates(&[2, 0, 1]).unwrap();
+        let templates = model.templates();
+        assert_eq!(
+            templates.iter().map(|t| (t.name.as_str(), t.ord)).collect::<Vec<_>>(),
+            vec![("Card 3", 0), ("Card 1", 1), ("Card 2", 2)]
+        );
+        let note = Note::new(model.clone(), vec!["", "", "c"]).unwrap();
+        assert_eq!(note.cards().iter().map(|card| card.ord).collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn cards_per_note() {
+        let cloze = crate::cloze_model();
+        assert!(cloze.is_cloze() && !basic_model().is_cloze());
+        let note = Note::new(cloze.clone(), vec!["{{c1::a}} {{c3::b}} {{c1::c}}"]).unwrap();
+        assert_eq!(cloze.cards_per_note(&note), 2);
+        assert!(cloze.has_cloze_deletions(&note));
+        let note = Note::new(cloze.clone(), vec!["no deletions"]).unwrap();
+        assert_eq!(cloze.cards_per_note(&note), 1);
+        assert!(!cloze.has_cloze_deletions(&note));
+
+        let reversed = crate::basic_and_reversed_card_model();
+        assert_eq!(reversed.cards_per_note(&Note::new(reversed.clone(), vec!["a", "b"]).unwrap()), 2);
+        assert_eq!(reversed.cards_per_note(&Note::new(reversed.clone(), vec!["a", ""]).unwrap()), 1);
+        assert_eq!(reversed.cards_per_note(&Note::new(cloze.clone(), vec!["{{c1::a}}"]).unwrap()), 0);
+    }
+
+    #[test]
+    fn validate_css() {
+        let with_css = |css: &str| basic_model().css(css);
+        for css in [
+            "",
+            ".card { color: red; }",
+            "@media (max-width: 600px) { .card { font-size: 12px; } }",
+            r#".card::before { content: "}"; } /* { */"#,
+            r#".card::after { content: '\'{'; }"#,
+        ] {
+            assert!(with_css(css).validate_css().is_ok(), "{}", css);
+        }
+        for css in [".card { color: red;", ".card { color: red; }}", ".card { } /* open", r#".a { content: "x; }"#] {
+            assert!(matches!(with_css(css).validate_css(), Err(Error::InvalidCss(..))), "{}", css);
+        }
+        assert_eq!(
+            with_css(
+                r#"@import "_base.css"; @import url('_fonts.css'); /* url(old.png) */
+                .card { background: url( bg%20image.png ) , url("data:image/png;base64,AA"); }"#
+            )
+            .css_media_refs(),
+            ["_base.css", "_fonts.css", "bg%20image.png", "data:image/png;base64,AA"]
+        );
+    }
 }
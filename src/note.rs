@@ -1,11 +1,12 @@
-use crate::card::Card;
+use crate::card::{Card, Flag};
 use crate::error::database_error;
 use crate::model::{Model, ModelType};
+use crate::text::{media_ref_ranges, strip_html_preserving_media_filenames};
 use crate::util::guid_for;
 use crate::Error;
 use fancy_regex::Regex;
 use rusqlite::{params, Transaction};
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::ops::RangeFrom;
 use std::str::FromStr;
 
@@ -17,9 +18,8 @@ static CLOZE_REGEX: Lazy<Regex> =
 static CLOZE2_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new("<%cloze:(.+?)%>").expect("static regex"));
 
-// Regex for stripping HTML tags (used for checksum calculation)
-static HTML_TAG_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"<[^>]*>").expect("static regex"));
+const MARKED_TAG: &str = "marked";
+const LEECH_TAG: &str = "leech";
 
 static UPDATES_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(?s){{c(\d+)::.+?}}").expect("static regex"));
@@ -27,10 +27,9 @@ static UPDATES_REGEX: Lazy<Regex> =
 static INVALID_HTML_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"<(?!/?[a-z0-9]+(?: .*|/?)>)(?:.|\n)*?>").expect("static regex"));
 
-/// Strip HTML tags from text while preserving media filenames
-/// This matches Anki's strip_html_preserving_media_filenames behavior
-fn strip_html(text: &str) -> String {
-    HTML_TAG_REGEX.replace_all(text, "").to_string()
+/// Whether text has nothing but whitespace and zero-width spaces, like Anki's empty field check
+fn is_blank(text: &str) -> bool {
+    text.chars().all(|c| c.is_whitespace() || c == '\u{200b}')
 }
 
 /// Note (Flashcard) to be added to a `Deck`
@@ -43,10 +42,14 @@ pub struct Note {
     guid: String,
     pub(crate) id: Option<i64>,
     cards: Vec<Card>,
+    custom_cards: bool,
     sfld_override: Option<String>,
     csum_override: Option<i64>,
     usn: i32,
     mod_time: Option<i64>,
+    allow_empty_first_field: bool,
+    flags: i32,
+    data: String,
 }
 
 impl Note {
@@ -61,11 +64,9 @@ impl Note {
     /// let note = Note::new(basic_model(), vec!["What is the capital of France?", "Paris"]);
     /// ```
     pub fn new(model: Model, fields: Vec<&str>) -> Result<Self, Error> {
-        let fields = fields.iter().map(|&s| s.to_string()).collect();
-        let cards = match model.get_model_type() {
-            ModelType::FrontBack => front_back_cards(&model, &fields)?,
-            ModelType::Cloze => cloze_cards(&model, &fields),
-        };
+        let fields: Vec<String> = fields.iter().map(|&s| s.to_string()).collect();
+        check_field_count(&model, &fields)?;
+        let cards = generate_cards(&model, &fields)?;
         let guid = guid_for(&fields);
         Ok(Self {
             model,
@@ -75,17 +76,21 @@ impl Note {
             guid,
             id: None,
             cards,
+            custom_cards: false,
             sfld_override: None,
             csum_override: None,
             usn: -1,
             mod_time: None,
+            allow_empty_first_field: false,
+            flags: 0,
+            data: String::new(),
         })
     }
 
     /// Creates a new Note with a new `model`, `fields` and custom parameters:
     /// * `sort_field` - whether to sort field, default is `false`
     /// * `tags` - List of tags
-    /// * `guid` - Custom unique note id, default is hash of all fields
+    /// * `guid` - Custom unique note id, default is [`guid_for`](crate::guid_for) of all fields
     ///
     /// Returns `Err` if tags or fields are invalid
     pub fn new_with_options(
@@ -101,11 +106,10 @@ impl Note {
             .map(|s| s.to_string())
             .collect();
         validate_tags(&tags)?;
-        let fields = fields.iter().map(|s| s.to_string()).collect();
-        let cards = match model.get_model_type() {
-            ModelType::FrontBack => front_back_cards(&model, &fields)?,
-            ModelType::Cloze => cloze_cards(&model, &fields),
-        };
+        let tags = normalize_tags(tags);
+        let fields: Vec<String> = fields.iter().map(|s| s.to_string()).collect();
+        check_field_count(&model, &fields)?;
+        let cards = generate_cards(&model, &fields)?;
         let guid = guid.unwrap_or(&guid_for(&fields)).to_string();
         Ok(Self {
             model,
@@ -115,10 +119,14 @@ impl Note {
             guid,
             id: None,
             cards,
+            custom_cards: false,
             sfld_override: None,
             csum_override: None,
             usn: -1,
             mod_time: None,
+            allow_empty_first_field: false,
+            flags: 0,
+            data: String::new(),
         })
     }
 
@@ -128,22 +136,87 @@ impl Note {
     }
 
     /// Sets or replaces tags with the provided ones
+    ///
+    /// Tags are normalized the way Anki does it: whitespace separates tags, so `"foo bar"` becomes
+    /// the two tags `foo` and `bar`, and `::` separates the levels of a hierarchical tag like
+    /// `"tag::sub"`. Duplicate tags (ignoring case) are dropped.
     pub fn tags(self, tags: impl IntoIterator<Item = impl ToString>) -> Self {
         Self {
-            tags: tags.into_iter().map(|tag| tag.to_string()).collect(),
+            tags: normalize_tags(tags.into_iter().map(|tag| tag.to_string())),
             ..self
         }
     }
 
-    /// Adds an additional tag
+    /// Adds an additional tag, normalized like in [`Note::tags`]
     pub fn with_tag(mut self, tag: impl ToString) -> Self {
-        self.tags.push(tag.to_string());
+        let tags = std::mem::take(&mut self.tags);
+        self.tags = normalize_tags(tags.into_iter().chain(std::iter::once(tag.to_string())));
         self
     }
 
-    /// Sets the GUID for this note
+    /// Replaces the tags of this note, like [`Note::tags`] but rejecting tags with whitespace
+    ///
+    /// Anki separates the tags in the `tags` column with spaces, so a tag containing whitespace
+    /// would silently become several tags. Returns `Err(Error::TagContainsWhitespace)` instead.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Note, basic_model};
+    ///
+    /// let note = Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?
+    ///     .set_tags(vec!["geography".to_string(), "europe::france".to_string()])?;
+    /// assert_eq!(note.get_tags(), ["geography", "europe::france"]);
+    /// assert!(note.set_tags(vec!["two words".to_string()]).is_err());
+    /// # Ok::<(), genanki_rs::Error>(())
+    /// ```
+    pub fn set_tags(self, tags: Vec<String>) -> Result<Self, Error> {
+        validate_tags(&tags)?;
+        Ok(self.tags(tags))
+    }
+
+    /// Adds a tag, like [`Note::with_tag`] but rejecting tags with whitespace as in
+    /// [`Note::set_tags`]
+    pub fn add_tag(self, tag: &str) -> Result<Self, Error> {
+        validate_tags(&vec![tag.to_string()])?;
+        Ok(self.with_tag(tag))
+    }
+
+    /// Adds Anki's special `marked` tag, which highlights the note in the browser
+    pub fn mark(self) -> Self {
+        self.with_tag(MARKED_TAG)
+    }
+
+    /// Adds Anki's special `leech` tag, which Anki gives to notes whose cards were failed too often
+    pub fn flag_as_leech(self) -> Self {
+        self.with_tag(LEECH_TAG)
+    }
+
+    /// Returns the field values of this note
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Returns the tags of this note
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Sets the GUID Anki identifies this note by, instead of the one derived from its fields
+    ///
+    /// Anki updates a note in place when a package with a note of the same GUID is imported, so a stable GUID
+    /// keeps a note the same note even when its fields change. The caller is responsible for the GUIDs being
+    /// unique, writing a package with two notes of the same GUID returns [`Error::DuplicateNoteGuid`] unless
+    /// duplicates are dropped with [`Package::set_drop_duplicate_guids`](crate::Package::set_drop_duplicate_guids).
     ///
-    /// The GUID is auto-generated if this option is not provided.
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(basic_model(), vec!["Capital of France?", "Paris"])?.guid("capital-fr");
+    /// # Ok(())
+    /// # }
+    /// ```
     pub fn guid(self, guid: impl ToString) -> Self {
         Self {
             guid: guid.to_string(),
@@ -157,6 +230,34 @@ impl Note {
         self
     }
 
+    /// Sets the colored flag stored in the note's `flags`
+    ///
+    /// Anki shows the flags of cards, see [`Card::flags`].
+    pub fn flags(mut self, flag: Flag) -> Self {
+        self.flags = (self.flags & !0b111) | i32::from(flag);
+        self
+    }
+
+    /// Sets the raw value of the note's `flags` column
+    pub(crate) fn set_flags(mut self, flags: i32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Returns the colored flag stored in the note's `flags`
+    pub fn flag(&self) -> Flag {
+        Flag::from_flags(self.flags)
+    }
+
+    /// Allows writing this note when its first field is empty
+    ///
+    /// Anki refuses to import notes whose first field has no text or media, so writing such a note
+    /// returns [`Error::EmptyFirstField`] unless this is set.
+    pub fn allow_empty_first_field(mut self) -> Self {
+        self.allow_empty_first_field = true;
+        self
+    }
+
     /// Sets a custom sort field value (sfld) for this note
     ///
     /// By default, sfld is computed from the sort field index in the model.
@@ -175,6 +276,38 @@ impl Note {
         self
     }
 
+    /// Returns the checksum Anki stores in `notes.csum` and finds duplicate notes with
+    ///
+    /// This is the first 4 bytes of the SHA-1 of the first field with the HTML stripped (keeping media file
+    /// names) and the entities decoded, like Anki's `field_checksum`, unless it was set with
+    /// [`Note::set_csum`].
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(basic_model(), vec!["<b>Paris</b>", "France"])?;
+    /// assert_eq!(note.checksum(), Note::new(basic_model(), vec!["Paris", "Capital"])?.checksum());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn checksum(&self) -> u32 {
+        use sha1::{Digest, Sha1};
+        if let Some(csum) = self.csum_override {
+            return csum as u32;
+        }
+        let digest = Sha1::digest(self.checksum_text().as_bytes());
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+    }
+
+    /// Returns the text of the first field the checksum is computed from
+    pub(crate) fn checksum_text(&self) -> String {
+        self.fields
+            .first()
+            .map_or_else(String::new, |field| strip_html_preserving_media_filenames(field))
+    }
+
     /// Sets the USN (update sequence number) for this note
     ///
     /// By default, USN is -1 (indicating local changes not synced).
@@ -184,6 +317,26 @@ impl Note {
         self
     }
 
+    /// Sets the note's `data` column
+    ///
+    /// Anki doesn't use it and writes it empty, which is the default.
+    /// Use this method to preserve data values from imported Anki decks.
+    pub fn set_data(mut self, data: impl ToString) -> Self {
+        self.data = data.to_string();
+        self
+    }
+
+    /// Replaces the cards generated from the model's templates with `cards`
+    ///
+    /// The cards are written exactly as given, including `custom_card_id`, scheduling and review history.
+    /// Use this to re-export notes of an existing collection. For non-cloze models, writing fails if a card's
+    /// `ord` has no matching template.
+    pub fn with_cards(mut self, cards: Vec<Card>) -> Self {
+        self.cards = cards;
+        self.custom_cards = true;
+        self
+    }
+
     /// Sets the modification timestamp for this note
     ///
     /// By default, mod is set to the package build timestamp.
@@ -202,15 +355,17 @@ impl Note {
         guid: Option<&str>,
         tags: Option<Vec<&str>>,
     ) -> Result<Self, Error> {
-        let fields = fields.iter().map(|&s| s.to_string()).collect();
+        let fields: Vec<String> = fields.iter().map(|&s| s.to_string()).collect();
         let tags = tags
             .unwrap_or_default()
             .iter()
             .map(|s| s.to_string())
             .collect();
         validate_tags(&tags)?;
+        let tags = normalize_tags(tags);
         let guid = guid.unwrap_or(&guid_for(&fields)).to_string();
-        
+        check_field_count(&model, &fields)?;
+
         Ok(Self {
             model,
             fields,
@@ -219,34 +374,86 @@ impl Note {
             guid,
             id: None,
             cards,
+            custom_cards: true,
             sfld_override: None,
             csum_override: None,
             usn: -1,
             mod_time: None,
+            allow_empty_first_field: false,
+            flags: 0,
+            data: String::new(),
         })
     }
 
-    pub(super) fn model(&self) -> Model {
-        self.model.clone()
+    /// Returns the model (note type) this note uses
+    pub fn model(&self) -> &Model {
+        &self.model
     }
 
-    #[allow(dead_code)]
-    pub(super) fn cards(&self) -> Vec<Card> {
-        self.cards.clone()
+    /// Returns the ID of the model (note type) this note uses
+    pub fn model_id(&self) -> i64 {
+        self.model.id
     }
 
-    fn get_guid(&self) -> String {
+    pub(super) fn fields_mut(&mut self) -> &mut [String] {
+        &mut self.fields
+    }
+
+    /// Replaces every field with `f(field index, field)`, the cards are generated again from the new
+    /// fields unless they were set explicitly
+    pub(super) fn transform_fields<F: FnMut(usize, &str) -> String>(&mut self, f: &mut F) -> Result<(), Error> {
+        for (i, field) in self.fields.iter_mut().enumerate() {
+            *field = f(i, field);
+        }
+        if !self.custom_cards {
+            self.cards = generate_cards(&self.model, &self.fields)?;
+        }
+        Ok(())
+    }
+
+    /// Changes the ID of the model (note type) this note uses
+    pub(super) fn set_model_id(&mut self, model_id: i64) {
+        self.model.id = model_id;
+    }
+
+    pub(super) fn cards(&self) -> &[Card] {
+        &self.cards
+    }
+
+    /// The ID set with [`Note::set_id`], if any
+    pub(super) fn pinned_id(&self) -> Option<i64> {
+        self.id
+    }
+
+    pub(crate) fn get_guid(&self) -> String {
         self.guid.clone()
     }
 
-    fn check_number_model_fields_matches_num_fields(&self) -> Result<(), Error> {
-        if self.model.fields().len() != self.fields.len() {
-            Err(Error::ModelFieldCountMismatch(
-                self.model.fields().len(),
-                self.fields.len(),
-            ))
-        } else {
-            Ok(())
+    fn check_first_field_not_empty(&self) -> Result<(), Error> {
+        if self.allow_empty_first_field {
+            return Ok(());
+        }
+        match self.fields.first() {
+            Some(first) if media_ref_ranges(first).is_empty() && is_blank(&strip_html_preserving_media_filenames(first)) => {
+                Err(Error::EmptyFirstField)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_card_ords_match_templates(&self) -> Result<(), Error> {
+        // Cloze models have a single template but one card per cloze number
+        if !self.custom_cards || self.model.get_model_type() == ModelType::Cloze {
+            return Ok(());
+        }
+        let num_templates = self.model.templates().len();
+        match self
+            .cards
+            .iter()
+            .find(|card| card.ord < 0 || card.ord as usize >= num_templates)
+        {
+            Some(card) => Err(Error::CardOrdOutOfRange(card.ord, num_templates)),
+            None => Ok(()),
         }
     }
 
@@ -274,26 +481,29 @@ impl Note {
              format!(" {} ", self.tags.join(" "))
         }
     }
+    /// Writes the note and its cards, new cards without an explicit `due` get `new_card_due` as their position
     pub(super) fn write_to_db(
         &self,
         transaction: &Transaction,
         timestamp: f64,
         deck_id: i64,
-        mut id_gen: &mut RangeFrom<usize>,
+        id_gen: &mut RangeFrom<usize>,
+        new_card_due: Option<i64>,
     ) -> Result<(), Error> {
-        self.check_number_model_fields_matches_num_fields()?;
         self.check_invalid_html_tags_in_fields()?;
-        // sfld should be the text value of the sort field (defaults to first field)
-        // Use override if present (for preserving original Anki values)
+        self.check_card_ords_match_templates()?;
+        self.check_first_field_not_empty()?;
+        // sfld is the text of the sort field (defaults to first field) with the HTML stripped like for the
+        // checksum. Use override if present (for preserving original Anki values)
         let computed_sfld;
         let sfld_value = if let Some(ref override_val) = self.sfld_override {
             override_val.as_str()
         } else {
             let sort_field_idx = self.model.sort_field_idx() as usize;
             computed_sfld = if sort_field_idx < self.fields.len() {
-                self.fields[sort_field_idx].clone()
+                strip_html_preserving_media_filenames(&self.fields[sort_field_idx])
             } else if !self.fields.is_empty() {
-                self.fields[0].clone() // fallback to first field if index is out of bounds
+                self.checksum_text() // fallback to first field if index is out of bounds
             } else {
                 String::new()
             };
@@ -307,38 +517,12 @@ impl Note {
         };
 
         // Checksum logic: Use override if present, otherwise compute from first field
-        let csum: i64 = if let Some(override_csum) = self.csum_override {
-            override_csum
-        } else {
-            // Compute checksum from first field using Sha1
-            // This matches Anki's field_checksum(strip_html_preserving_media_filenames(fields[0]))
-            use sha1::{Sha1, Digest};
-            if !self.fields.is_empty() {
-                // First strip HTML tags (preserving media filenames)
-                let stripped = strip_html(&self.fields[0]);
-
-                // Then replace HTML entities
-                let field_for_checksum = stripped
-                    .replace("&nbsp;", " ")
-                    .replace("&lt;", "<")
-                    .replace("&gt;", ">")
-                    .replace("&amp;", "&")
-                    .replace("&quot;", "\"");
-
-                let mut hasher = Sha1::new();
-                hasher.update(field_for_checksum.as_bytes());
-                let result = hasher.finalize();
-                // Take first 4 bytes as u32
-                let bytes: [u8; 4] = [result[0], result[1], result[2], result[3]];
-                u32::from_be_bytes(bytes) as i64
-            } else {
-                0
-            }
-        };
+        let csum: i64 = self.csum_override.unwrap_or_else(|| i64::from(self.checksum()));
 
         transaction
+            .prepare_cached("INSERT INTO notes VALUES(?,?,?,?,?,?,?,?,?,?,?);")
+            .map_err(database_error)?
             .execute(
-                "INSERT INTO notes VALUES(?,?,?,?,?,?,?,?,?,?,?);",
                 params![
                     note_id,              // id
                     self.get_guid(),      // guid
@@ -349,21 +533,75 @@ impl Note {
                     self.format_fields(), // flds
                     sfld_value,           // sfld - text value of sort field
                     csum,                 // csum
-                    0,                    // flags
-                    "",                   // data
+                    self.flags,           // flags
+                    self.data,            // data
                 ],
             )
             .map_err(database_error)?;
         // let note_id = transaction.last_insert_rowid() as usize; // We already know note_id
         for card in &self.cards {
-            card.write_to_db(transaction, timestamp, deck_id, note_id, &mut id_gen)?
+            match new_card_due {
+                Some(due) if card.due.is_none() && card.card_type.unwrap_or(0) == 0 => Card {
+                    due: Some(due),
+                    ..card.clone()
+                }
+                .write_to_db(transaction, timestamp, deck_id, note_id, id_gen)?,
+                _ => card.write_to_db(transaction, timestamp, deck_id, note_id, id_gen)?,
+            }
         }
         Ok(())
     }
 }
 
-fn cloze_cards(model: &Model, self_fields: &Vec<String>) -> Vec<Card> {
-    let mut card_ords: HashSet<i64> = HashSet::new();
+pub(crate) fn generate_cards(model: &Model, fields: &[String]) -> Result<Vec<Card>, Error> {
+    match model.get_model_type() {
+        ModelType::FrontBack => front_back_cards(model, fields),
+        ModelType::Cloze => Ok(cloze_cards(model, fields)),
+    }
+}
+
+/// Returns `Err` if the number of `fields` is not the number of fields of the `model`
+fn check_field_count(model: &Model, fields: &[String]) -> Result<(), Error> {
+    let expected = model.fields().len();
+    if fields.len() != expected {
+        return Err(Error::ModelFieldCountMismatch(expected, fields.len()));
+    }
+    Ok(())
+}
+
+/// Returns `Err` if a template of the `model` references a field the note doesn't have, which only
+/// happens for the fields of a note of another model
+pub(crate) fn check_template_fields_in_range(model: &Model, fields: &[String]) -> Result<(), Error> {
+    match model
+        .template_field_refs()
+        .into_iter()
+        .find(|&(_, _, field_ord)| field_ord >= fields.len())
+    {
+        Some((template, field, field_ord)) => Err(Error::TemplateFieldOutOfRange(
+            template.to_string(),
+            field.to_string(),
+            field_ord,
+            fields.len(),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn cloze_cards(model: &Model, self_fields: &[String]) -> Vec<Card> {
+    let mut card_ords = cloze_card_ords(model, self_fields);
+    if card_ords.is_empty() {
+        card_ords.insert(0);
+    }
+    card_ords
+        .iter()
+        .map(|&card_ord| Card::new(card_ord, false))
+        .collect()
+}
+
+/// Returns the ords of the cloze deletions (`{{c1::...}}` is ord 0) in the fields the cloze template uses,
+/// in ascending order so that cards are always written in the same order
+pub(crate) fn cloze_card_ords(model: &Model, self_fields: &[String]) -> BTreeSet<i64> {
+    let mut card_ords: BTreeSet<i64> = BTreeSet::new();
     let mut cloze_replacements: HashSet<String> = HashSet::new();
     cloze_replacements.extend(re_findall(&CLOZE_REGEX, &model.templates()[0].qfmt));
     cloze_replacements.extend(re_findall(&CLOZE2_REGEX, &model.templates()[0].qfmt));
@@ -386,16 +624,10 @@ fn cloze_cards(model: &Model, self_fields: &Vec<String>) -> Vec<Card> {
             .filter(|&m| m >= 0);
         card_ords.extend(updates);
     }
-    if card_ords.is_empty() {
-        card_ords.insert(0);
-    }
     card_ords
-        .iter()
-        .map(|&card_ord| Card::new(card_ord, false))
-        .collect()
 }
 
-fn front_back_cards(model: &Model, self_fields: &Vec<String>) -> Result<Vec<Card>, Error> {
+fn front_back_cards(model: &Model, self_fields: &[String]) -> Result<Vec<Card>, Error> {
     let mut rv = vec![];
     for (card_ord, any_or_all, required_field_ords) in model.req()?.drain(..) {
         let mut iter = required_field_ords.iter().map(|&ord| &self_fields[ord]);
@@ -427,13 +659,50 @@ fn re_findall(regex: &Regex, to_match: &str) -> Vec<String> {
 }
 
 fn validate_tags(tags: &Vec<String>) -> Result<(), Error> {
-    if tags.iter().any(|tag| tag.contains(' ')) {
+    if tags.iter().any(|tag| tag.contains(char::is_whitespace)) {
         Err(Error::TagContainsWhitespace)
     } else {
         Ok(())
     }
 }
 
+/// Splits `tags` on whitespace, normalizes every tag and removes duplicates (ignoring case)
+fn normalize_tags(tags: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        for tag in tag.split_whitespace().map(normalize_tag) {
+            if !normalized.iter().any(|t| t.to_lowercase() == tag.to_lowercase()) {
+                normalized.push(tag);
+            }
+        }
+    }
+    normalized
+}
+
+/// Normalizes a single (whitespace-free) tag like Anki: control characters are removed and empty
+/// components of a hierarchical tag are replaced with `blank`
+///
+/// The special tags `marked` and `leech` are always lowercase, which is how Anki writes them.
+fn normalize_tag(tag: &str) -> String {
+    if let Some(special) = [MARKED_TAG, LEECH_TAG]
+        .into_iter()
+        .find(|special| tag.eq_ignore_ascii_case(special))
+    {
+        return special.to_string();
+    }
+    tag.split("::")
+        .map(|component| {
+            let component: String = component.chars().filter(|c| !c.is_control()).collect();
+            if component.is_empty() {
+                "blank".to_string()
+            } else {
+                component
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
 fn find_invalid_html_tags_in_field(field: &str) -> Vec<String> {
     INVALID_HTML_REGEX
         .find_iter(field)
@@ -446,7 +715,7 @@ mod tests {
     use super::*;
     use crate::apkg_col::APKG_COL;
     use crate::apkg_schema::APKG_SCHEMA;
-    use crate::{Field, Model, Note, Template};
+    use crate::{basic_model, Field, Model, Note, Template};
     use rusqlite::Connection;
     use std::time::{SystemTime, UNIX_EPOCH};
     use tempfile::{NamedTempFile, TempPath};
@@ -477,9 +746,122 @@ mod tests {
         let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
         let transaction = conn.transaction().unwrap();
         my_note
-            .write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
+            .write_to_db(&transaction, timestamp, deck_id, &mut id_gen, None)
+            .unwrap();
+        transaction.commit().unwrap();
+    }
+
+    #[test]
+    fn checksum_matches_anki() {
+        let note = Note::new(basic_model(), vec!["<b>Paris</b> &amp; Lyon", "France"]).unwrap();
+        assert_eq!(note.checksum(), 1349613939);
+        assert_eq!(note.set_csum(42).checksum(), 42);
+
+        // Anki hashes the file names of media tags, so notes with different pictures are no duplicates
+        let paris = Note::new(basic_model(), vec![r#"<img src="paris.jpg">"#, "Paris"]).unwrap();
+        let lima = Note::new(basic_model(), vec![r#"<img src="lima.jpg">"#, "Lima"]).unwrap();
+        assert_eq!(paris.checksum_text(), " paris.jpg ");
+        assert_ne!(paris.checksum(), lima.checksum());
+    }
+
+    #[test]
+    fn default_guid_matches_genanki() {
+        let note = Note::new(basic_model(), vec!["ü", "日本"]).unwrap();
+        assert_eq!(note.get_guid(), "g<v1@rcw6$");
+        assert_eq!(Note::new(basic_model(), vec!["foo", ""]).unwrap().get_guid(), guid_for(&["foo", ""]));
+    }
+
+    #[test]
+    fn empty_first_field() {
+        let my_model = Model::new(
+            1376484377,
+            "Simple Model",
+            vec![Field::new("Question"), Field::new("Answer")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Question}}{{Answer}}")
+                .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+        );
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        let write = |note: Note, id_gen: &mut RangeFrom<usize>| {
+            note.write_to_db(&transaction, timestamp, deck_id, id_gen, None)
+        };
+        let blank = Note::new(my_model.clone(), vec!["<b>&nbsp;\u{200b}</b> ", "Buenos Aires"]).unwrap();
+        assert!(matches!(
+            write(blank.clone(), &mut id_gen),
+            Err(Error::EmptyFirstField)
+        ));
+        write(blank.allow_empty_first_field(), &mut id_gen).unwrap();
+        let image = Note::new(my_model, vec![r#"<img src="flag.png">"#, "Buenos Aires"]).unwrap();
+        write(image, &mut id_gen).unwrap();
+    }
+
+    #[test]
+    fn original_deck_is_written() {
+        let my_model = Model::new(
+            1376484377,
+            "Simple Model",
+            vec![Field::new("Question"), Field::new("Answer")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Question}}")
+                .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+        );
+        let my_note = Note::new_with_cards(
+            my_model,
+            vec!["Capital of Argentina", "Buenos Aires"],
+            vec![Card::new(0, false).set_original_deck(1234, 42)],
+            None,
+            None,
+        )
+        .unwrap();
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        my_note
+            .write_to_db(&transaction, timestamp, deck_id, &mut id_gen, None)
             .unwrap();
         transaction.commit().unwrap();
+        let (odid, odue): (i64, i64) = conn
+            .query_row("SELECT odid, odue FROM cards", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((odid, odue), (1234, 42));
+    }
+
+    #[test]
+    fn with_cards_replaces_generated_cards() {
+        let model = Model::new(
+            1376484377,
+            "Simple Model",
+            vec![Field::new("Question"), Field::new("Answer")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Question}}")
+                .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+        );
+        let mut card = Card::new_with_review_data(0, false, 4, 0, 12, 230, 2500, 2, 2, 0);
+        card.custom_card_id = Some(1600000000123);
+        let note = Note::new(model.clone(), vec!["Capital of Argentina", "Buenos Aires"])
+            .unwrap()
+            .with_cards(vec![card]);
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen, None)
+            .unwrap();
+        let (id, ivl, due): (i64, i64, i64) = transaction
+            .query_row("SELECT id, ivl, due FROM cards", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!((id, ivl, due), (1600000000123, 12, 230));
+
+        let note = Note::new(model, vec!["Capital of Peru", "Lima"])
+            .unwrap()
+            .with_cards(vec![Card::new(1, false)]);
+        assert!(matches!(
+            note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen, None),
+            Err(Error::CardOrdOutOfRange(1, 1))
+        ));
     }
 
     #[test]
@@ -508,69 +890,90 @@ mod tests {
     }
 
     #[test]
-    fn num_fields_equals_model_ok() {
-        let model = Model::new(
-            1894808898,
-            "Test Model",
-            vec![
-                Field::new("Question"),
-                Field::new("Answer"),
-                Field::new("Extra"),
-            ],
-            vec![Template::new("Card 1")
-                .qfmt("{{Question}}")
-                .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+    fn tags_are_normalized() {
+        let note = Note::new(Model::new(0, "test", vec![], vec![]), vec![])
+            .unwrap()
+            .tags(["foo bar", "tag::sub", "Foo", "::x\u{7}"])
+            .with_tag("baz  tag::SUB");
+        assert_eq!(
+            note.get_tags(),
+            ["foo", "bar", "tag::sub", "blank::x", "baz"]
         );
+    }
 
-        let note = Note::new(
-            model,
-            vec![
-                "Capital of Germany",
-                "Berlin",
-                "Berlin was divided by a wall until 1989",
-            ],
-        )
-        .unwrap();
-        let db_file = NamedTempFile::new().unwrap().into_temp_path();
-        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
-        let transaction = conn.transaction().unwrap();
-        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
+    #[test]
+    fn marked_and_leech_tags() {
+        let note = Note::new(Model::new(0, "test", vec![], vec![]), vec![])
+            .unwrap()
+            .tags(["Marked", "LEECH", "leech::old"])
+            .mark()
+            .flag_as_leech();
+        assert_eq!(note.get_tags(), ["marked", "leech", "leech::old"]);
+    }
+
+    #[test]
+    fn set_and_add_tags() {
+        let model = Model::new(0, "test", vec![], vec![]);
+        let note = Note::new(model.clone(), vec![])
+            .unwrap()
+            .set_tags(vec!["foo".to_string()])
+            .unwrap()
+            .add_tag("tag::sub")
             .unwrap();
-        transaction.commit().unwrap();
+        assert_eq!(note.get_tags(), ["foo", "tag::sub"]);
+        assert!(matches!(
+            note.clone().add_tag("two words"),
+            Err(Error::TagContainsWhitespace)
+        ));
+        assert!(matches!(
+            note.set_tags(vec!["tab\tseparated".to_string()]),
+            Err(Error::TagContainsWhitespace)
+        ));
     }
 
     #[test]
-    #[should_panic]
-    fn num_fields_less_than_model_panic() {
+    fn tags_column_format() {
         let model = Model::new(
-            1894808898,
-            "Test Model",
-            vec![
-                Field::new("Question"),
-                Field::new("Answer"),
-                Field::new("Extra"),
-            ],
+            1376484377,
+            "Simple Model",
+            vec![Field::new("Question"), Field::new("Answer")],
             vec![Template::new("Card 1")
                 .qfmt("{{Question}}")
                 .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
         );
-
-        let note = Note::new(model, vec!["Capital of Germany", "Berlin"]).unwrap();
+        let tagged = Note::new(model.clone(), vec!["Capital of Argentina", "Buenos Aires"])
+            .unwrap()
+            .set_tags(vec!["geography".to_string(), "south_america".to_string()])
+            .unwrap();
+        let untagged = Note::new(model, vec!["Capital of Peru", "Lima"]).unwrap();
         let db_file = NamedTempFile::new().unwrap().into_temp_path();
         let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
         let transaction = conn.transaction().unwrap();
-        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
-            .unwrap();
+        for note in [tagged, untagged] {
+            note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen, None)
+                .unwrap();
+        }
         transaction.commit().unwrap();
+        let tags: Vec<String> = conn
+            .prepare("SELECT tags FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(tags, [" geography south_america ", ""]);
     }
 
     #[test]
-    #[should_panic]
-    fn num_fields_more_than_model_panic() {
+    fn num_fields_equals_model_ok() {
         let model = Model::new(
             1894808898,
             "Test Model",
-            vec![Field::new("Question"), Field::new("Answer")],
+            vec![
+                Field::new("Question"),
+                Field::new("Answer"),
+                Field::new("Extra"),
+            ],
             vec![Template::new("Card 1")
                 .qfmt("{{Question}}")
                 .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
@@ -588,11 +991,38 @@ mod tests {
         let db_file = NamedTempFile::new().unwrap().into_temp_path();
         let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
         let transaction = conn.transaction().unwrap();
-        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
+        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen, None)
             .unwrap();
         transaction.commit().unwrap();
     }
 
+    #[test]
+    fn field_count_is_checked_on_creation() {
+        let model = Model::new(
+            1894808898,
+            "Test Model",
+            vec![Field::new("Question"), Field::new("Answer"), Field::new("Notes")],
+            vec![Template::new("Card 1").qfmt("{{Question}}").afmt("{{Answer}}")],
+        );
+        assert!(matches!(
+            Note::new(model.clone(), vec!["Capital of Germany", "Berlin", "", ""]),
+            Err(Error::ModelFieldCountMismatch(3, 4))
+        ));
+        assert!(matches!(
+            Note::new_with_options(model.clone(), vec!["Capital of Germany", "Berlin"], None, None, None),
+            Err(Error::ModelFieldCountMismatch(3, 2))
+        ));
+        assert!(matches!(
+            Note::new_with_cards(model.clone(), vec!["Capital of Germany"], vec![], None, None),
+            Err(Error::ModelFieldCountMismatch(3, 1))
+        ));
+        assert_eq!(
+            Note::new(model.clone(), vec!["Capital of Germany", "Berlin", "", ""]).err().unwrap().to_string(),
+            "wrong number of fields for the model of the note: expected 3, got 4"
+        );
+        assert!(Note::new(model, vec!["Capital of Germany", "Berlin", ""]).is_ok());
+    }
+
     #[test]
     fn find_invalid_html_tags_in_field_ok() {
         assert_eq!(
@@ -1,19 +1,36 @@
-use rusqlite::{Connection, Transaction, params};
+use rusqlite::{Connection, Transaction, params, MAIN_DB};
+#[cfg(feature = "zstd")]
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
-use tempfile::NamedTempFile;
+#[cfg(feature = "zstd")]
+use zip::CompressionMethod;
 use zip::{write::FileOptions, ZipWriter};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+#[cfg(feature = "fs")]
 use std::fs::File;
 use std::io::{Read, Seek, Write};
-use std::path::{Path, PathBuf};
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::path::PathBuf;
 use log::info;
 
 use crate::apkg_schema::{APKG_SCHEMA, APKG_SCHEMA_V11, APKG_SCHEMA_FIELDS};
 use crate::apkg_col::APKG_COL;
-use crate::deck::Deck;
+use crate::collection_conf::{AnkiVersion, CollectionConf, PackageFormat, SchedulerVersion};
+use crate::filtered_deck::FilteredDeck;
+use crate::deck::{group_duplicates, Deck};
 use crate::error::{database_error, json_error, zip_error};
+use crate::media_pool::MediaPool;
+use crate::model::NormalizedModel;
+use crate::note::Note;
+use crate::proto::ProtoWriter;
+use crate::text::{decode_entities, media_ref_ranges, percent_decode, rewrite_media_refs};
+use crate::util::zip_date_time;
 use crate::Error;
+#[cfg(feature = "fs")]
 use std::str::FromStr;
 use crate::db_entries::{DeckDbEntry, ModelDbEntry};
 
@@ -58,19 +75,26 @@ pub struct NotetypeEntry {
 }
 
 /// the location of the media files, either as a path on the filesystem or as bytes from memory
+#[derive(Clone)]
 pub enum MediaFile {
     /// a path on the filesystem
+    #[cfg(feature = "fs")]
     Path(PathBuf),
     /// bytes of the file and a filename
     Bytes(Vec<u8>, String),
+    /// a path on the filesystem and the filename it is stored under in the package
+    #[cfg(feature = "fs")]
+    NamedPath(PathBuf, String),
 }
 impl MediaFile {
     /// Create a new `MediaFile` from a path on the filesystem
+    #[cfg(feature = "fs")]
     pub fn new_from_file<P: AsRef<Path>>(path: P) -> Self {
         Self::Path(path.as_ref().to_path_buf())
     }
 
     /// Create a new `MediaFile` from a path on the filesystem using a `&str`
+    #[cfg(feature = "fs")]
     pub fn new_from_file_path(path: &str) -> Result<Self, Error> {
         Ok(Self::Path(PathBuf::from_str(path)?))
     }
@@ -79,6 +103,103 @@ impl MediaFile {
     pub fn new_from_bytes(bytes: &[u8], name: &str) -> Self {
         Self::Bytes(bytes.to_vec(), name.to_owned())
     }
+
+    /// Create a new `MediaFile` from a path on the filesystem, stored in the package as `name`
+    #[cfg(feature = "fs")]
+    pub fn new_from_file_with_name<P: AsRef<Path>>(path: P, name: &str) -> Self {
+        Self::NamedPath(path.as_ref().to_path_buf(), name.to_owned())
+    }
+
+    /// The filename this media file is stored under in the package
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            #[cfg(feature = "fs")]
+            MediaFile::Path(path) => path
+                .file_name()
+                .expect("Should always have a filename")
+                .to_str()
+                .expect("should always have string"),
+            #[cfg(feature = "fs")]
+            MediaFile::NamedPath(_, name) => name,
+            MediaFile::Bytes(_, name) => name,
+        }
+    }
+
+    /// The path of the file on the filesystem, or the filename for media files created from bytes
+    pub(crate) fn source(&self) -> PathBuf {
+        match self {
+            #[cfg(feature = "fs")]
+            MediaFile::Path(path) | MediaFile::NamedPath(path, _) => path.clone(),
+            MediaFile::Bytes(_, name) => PathBuf::from(name),
+        }
+    }
+
+    /// Whether both media files have the same content, because they are the same file on the filesystem or
+    /// the same bytes
+    fn is_same_file(&self, other: &MediaFile) -> bool {
+        match (self, other) {
+            (MediaFile::Bytes(bytes, _), MediaFile::Bytes(other_bytes, _)) => bytes == other_bytes,
+            #[cfg(feature = "fs")]
+            (MediaFile::Bytes(..), _) | (_, MediaFile::Bytes(..)) => false,
+            #[cfg(feature = "fs")]
+            _ => self.source() == other.source(),
+        }
+    }
+
+    /// Returns `Err` if the media file is a path to a directory instead of a file
+    pub(crate) fn check_not_directory(&self) -> Result<(), Error> {
+        match self {
+            #[cfg(feature = "fs")]
+            MediaFile::Path(path) | MediaFile::NamedPath(path, _) if path.is_dir() || path.file_name().is_none() => {
+                Err(Error::MediaIsDirectory(path.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the media file can be written to a package: paths have to be readable files and, unless the
+    /// media file is stored under another name, have a UTF-8 filename
+    ///
+    /// Only opens the file and looks at its metadata, the content is not read.
+    fn is_usable(&self) -> bool {
+        match self {
+            #[cfg(feature = "fs")]
+            MediaFile::Path(path) => path.file_name().and_then(|name| name.to_str()).is_some() && is_readable_file(path),
+            #[cfg(feature = "fs")]
+            MediaFile::NamedPath(path, _) => is_readable_file(path),
+            MediaFile::Bytes(..) => true,
+        }
+    }
+
+    pub(crate) fn read_bytes(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            #[cfg(feature = "fs")]
+            MediaFile::Path(path) | MediaFile::NamedPath(path, _) => read_file_bytes(path),
+            MediaFile::Bytes(bytes, _) => Ok(bytes.clone()),
+        }
+    }
+}
+
+/// Prefixes `name` with `_` unless it already starts with one.
+///
+/// Anki's "Check Media" never deletes files starting with `_`, which is how assets referenced
+/// only from templates or CSS (and not from any note field) survive it.
+#[cfg(feature = "fs")]
+fn template_asset_name(name: &str) -> String {
+    if name.starts_with('_') {
+        name.to_string()
+    } else {
+        format!("_{}", name)
+    }
+}
+
+/// Returns the id of the parent deck named `name` that is added for subdecks, derived from the name so that
+/// it stays the same when the package is written again
+fn parent_deck_id(name: &str) -> i64 {
+    let digest = Sha256::digest(name.to_lowercase().as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 has 32 bytes"));
+    // Ids stay below 2^52 so that JavaScript reads them exactly, and above the id of the default deck
+    (hash >> 12).max(2) as i64
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -143,6 +264,58 @@ pub struct TagEntry {
     pub config: Option<Vec<u8>>, // Optional config blob
 }
 
+/// Result of `Package::resolve_media_refs`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MediaResolutionReport {
+    /// References that were rewritten, as `(reference, media file name)`
+    pub rewritten: Vec<(String, String)>,
+    /// References that don't match any media file of the package
+    pub unresolved: Vec<String>,
+}
+
+/// Report of `Package::stats_json`
+#[derive(serde::Serialize)]
+struct PackageStats<'a> {
+    decks: Vec<DeckStats<'a>>,
+    media: MediaStats,
+}
+
+#[derive(serde::Serialize)]
+struct DeckStats<'a> {
+    id: i64,
+    name: &'a str,
+    notes: usize,
+    cards: usize,
+    models: Vec<ModelStats<'a>>,
+    tags: Vec<&'a str>,
+}
+
+#[derive(serde::Serialize)]
+struct ModelStats<'a> {
+    id: i64,
+    name: &'a str,
+    fields: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct MediaStats {
+    count: usize,
+    total_bytes: u64,
+}
+
+/// Returns the form of a media reference or filename used to match references against media files:
+/// entities and `%XX` escapes decoded, without directories and lowercase
+fn normalize_media_ref(reference: &str) -> String {
+    let decoded = percent_decode(&decode_entities(reference));
+    let name = decoded.rsplit(['/', '\\']).next().unwrap_or_default();
+    name.trim().to_lowercase()
+}
+
+/// Whether a reference points outside the package (e.g. `https://...` or `data:...`)
+fn is_external_ref(reference: &str) -> bool {
+    reference.contains("://") || reference.to_lowercase().starts_with("data:")
+}
+
 pub struct Package {
     pub decks: Vec<Deck>,
     media_files: Vec<MediaFile>,
@@ -164,38 +337,199 @@ pub struct Package {
     col_models: Option<String>,
     col_decks: Option<String>,
     col_dconf: Option<String>,
+    default_note_type: Option<i64>,
+    collection_conf: Option<CollectionConf>,
+    scheduler_version: Option<SchedulerVersion>,
+    filtered_decks: Vec<FilteredDeck>,
+    omit_empty_media_entry: bool,
+    collection_id: Option<i64>,
+    media_index_base: usize,
+    zip_mtime: Option<i64>,
+    media_pool: Option<Arc<MediaPool>>,
+    anki_version: Option<AnkiVersion>,
+    integrity_check: bool,
+    collapsed_tags: Vec<String>,
+    drop_duplicate_guids: bool,
 }
 
 impl Package {
     /// Create a new package with `decks` and `media_files`
     ///
-    /// Returns `Err` if `media_files` are invalid
+    /// Returns `Err` if `media_files` are invalid or one of them is a directory
+    #[cfg(feature = "fs")]
     pub fn new(decks: Vec<Deck>, media_files: Vec<String>) -> Result<Self, Error> {
         let media_files = media_files
             .iter()
             .map(|s| PathBuf::from_str(s.as_str()).map(|p| MediaFile::Path(p)))
             .collect::<Result<Vec<_>, _>>()?;
-        Ok(Self {
-            decks,
-            media_files,
-            configs: Vec::new(),
-            deck_configs: Vec::new(),
-            deck_infos: Vec::new(),
-            notetypes: Vec::new(),
-            field_entries: Vec::new(),
-            template_entries: Vec::new(),
-            graves: Vec::new(),
-            tags: Vec::new(),
-            col_crt: None,
-            col_ver: None,
-            col_scm: None,
-            col_usn: None,
-            col_ls: None,
-            col_conf: None,
-            col_models: None,
-            col_decks: None,
-            col_dconf: None,
-        })
+        media_files.iter().try_for_each(MediaFile::check_not_directory)?;
+        Self::new_from_memory(decks, media_files)
+    }
+
+    /// Adds all files in the directory `dir` and its subdirectories as media files
+    ///
+    /// The files are stored under their filename, the directory structure is not preserved.
+    ///
+    /// Returns `Err` if `dir` or one of its subdirectories cannot be read
+    #[cfg(feature = "fs")]
+    pub fn add_media_directory<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), Error> {
+        let mut entries = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                self.add_media_directory(path)?;
+            } else {
+                self.media_files.push(MediaFile::Path(path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a media file, which can be bytes from memory or a path on the filesystem
+    pub fn add_media_file(&mut self, media_file: MediaFile) {
+        self.media_files.push(media_file);
+    }
+
+    /// Adds a media file created in memory, e.g. generated audio or a rendered image, stored in the package
+    /// as `name`
+    ///
+    /// Fields have to reference the media file by `name`, e.g. `[sound:name]`.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Pronunciation", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Bonjour", "[sound:bonjour.mp3]"])?);
+    /// let mut package = Package::new_from_memory(vec![deck], vec![])?;
+    /// package.add_media_bytes(b"ID3...", "bonjour.mp3");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_media_bytes(&mut self, bytes: &[u8], name: &str) {
+        self.add_media_file(MediaFile::new_from_bytes(bytes, name));
+    }
+
+    /// Adds the files in `base_dir` that notes refer to with `[sound:...]` or `src="..."` (and templates or CSS
+    /// with `url(...)`), so that they don't have to be listed separately
+    ///
+    /// References are resolved relative to `base_dir` and the files stored under their filename. URLs,
+    /// references to files that don't exist and names of media files the package already has are skipped, as
+    /// are absolute references and ones with `..`, which could point outside of `base_dir`.
+    /// References with directories, like `img/logo.png`, can be rewritten to the filename with
+    /// [`Package::resolve_media_refs`]. Returns the names of the added files.
+    ///
+    /// Example:
+    /// ```rust,no_run
+    /// use genanki_rs::{basic_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Pronunciation", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Bonjour", "[sound:bonjour.mp3]"])?);
+    /// let mut package = Package::new(vec![deck], vec![])?;
+    /// let added = package.collect_media_from_fields("media");
+    /// assert_eq!(added, ["bonjour.mp3"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn collect_media_from_fields<P: AsRef<Path>>(&mut self, base_dir: P) -> Vec<String> {
+        let mut refs: Vec<String> = self
+            .media_refs()
+            .into_iter()
+            .filter(|reference| !is_external_ref(reference))
+            .collect();
+        refs.sort();
+        let mut added = vec![];
+        for reference in refs {
+            let relative = Path::new(&reference);
+            if !relative
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)))
+            {
+                continue;
+            }
+            let path = base_dir.as_ref().join(relative);
+            if !path.is_file() {
+                continue;
+            }
+            let media_file = MediaFile::new_from_file(path);
+            if self.media_files.iter().any(|existing| existing.name() == media_file.name()) {
+                continue;
+            }
+            added.push(media_file.name().to_string());
+            self.media_files.push(media_file);
+        }
+        added
+    }
+
+    /// Adds a media file from `path`, stored in the package as `name` instead of its filename on disk
+    ///
+    /// Fields have to reference the media file by `name`, e.g. `<img src="name">`.
+    #[cfg(feature = "fs")]
+    pub fn add_media_with_name<P: AsRef<Path>>(&mut self, path: P, name: &str) {
+        self.media_files
+            .push(MediaFile::new_from_file_with_name(path, name));
+    }
+
+    /// Adds a media file which is only referenced by templates or CSS (fonts, scripts, background images)
+    ///
+    /// The file is stored as `name` prefixed with `_`, which keeps Anki's "Check Media" from
+    /// deleting it as unused. Returns the stored name, which is what templates and CSS have to reference.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::Package;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut package = Package::new(vec![], vec![])?;
+    /// let font = package.add_template_asset("fonts/Roboto.ttf", "Roboto.ttf");
+    /// assert_eq!(font, "_Roboto.ttf");
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn add_template_asset<P: AsRef<Path>>(&mut self, path: P, name: &str) -> String {
+        let name = template_asset_name(name);
+        self.add_media_with_name(path, &name);
+        name
+    }
+
+    /// Adds a JavaScript file which templates load with a `<script>` tag, see [`Package::add_template_asset`]
+    ///
+    /// Returns the `<script>` tag loading the stored file, to paste into a template, or `Err` if `path`
+    /// is not an existing file
+    ///
+    /// Example:
+    /// ```rust,no_run
+    /// use genanki_rs::{Package, Template};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut package = Package::new(vec![], vec![])?;
+    /// let script = package.add_template_script("js/drawing.js")?;
+    /// assert_eq!(script, r#"<script src="_drawing.js"></script>"#);
+    /// let template = Template::new("Card 1").qfmt(&format!("{{{{Front}}}}{}", script));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn add_template_script<P: AsRef<Path>>(&mut self, path: P) -> Result<String, Error> {
+        let path = path.as_ref();
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) if !path.is_dir() => file_name.to_string(),
+            _ => return Err(Error::MediaIsDirectory(path.to_path_buf())),
+        };
+        if !path.is_file() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("template script {:?} does not exist", path),
+            )));
+        }
+        let name = self.add_template_asset(path, &file_name);
+        Ok(format!(r#"<script src="{}"></script>"#, name))
     }
 
     /// Adds a configuration entry to the package.
@@ -262,6 +596,15 @@ impl Package {
         self.tags.push(entry);
     }
 
+    /// Sets the tags that are collapsed in the sidebar of the browser, hiding their child tags
+    ///
+    /// The tags are matched case-insensitively and registered in the `tags` table even if no note uses
+    /// them. Tag entries added with [`Package::add_tag_entry`] keep their own collapse state. Collections
+    /// before version 12 have no `tags` table and don't store the collapse state.
+    pub fn set_collapsed_tags(&mut self, tags: Vec<&str>) {
+        self.collapsed_tags = tags.into_iter().map(str::to_string).collect();
+    }
+
     /// Create a new package with `decks` and `media_files`,
     /// where `media_files` can be bytes from memory or a path on the filesystem
     /// 
@@ -287,12 +630,508 @@ impl Package {
             col_models: None,
             col_decks: None,
             col_dconf: None,
+            default_note_type: None,
+            collection_conf: None,
+            scheduler_version: None,
+            filtered_decks: Vec::new(),
+            omit_empty_media_entry: false,
+            collection_id: None,
+            media_index_base: 0,
+            zip_mtime: None,
+            integrity_check: false,
+            collapsed_tags: Vec::new(),
+            drop_duplicate_guids: false,
+            media_pool: None,
+            anki_version: None,
+        })
+    }
+
+    /// Reads a package from the `.apkg` archive in `reader`, e.g. one that was downloaded into memory
+    ///
+    /// Notes are put into the deck of their first card (the home deck for cards in a filtered deck)
+    /// and keep their ids, guids and cards with their review history. Media files are read into memory.
+    ///
+    /// Note types and decks are read from the `col` table before collection version 16 and from their own
+    /// tables since, the collection settings and deck options presets are kept. Packages in the newest
+    /// format of Anki (`collection.anki21b`) are not supported.
+    ///
+    /// Returns `Err` if the archive or its collection is invalid
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, Package};
+    /// use std::io::Cursor;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Capitals", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// let mut package = Package::new_from_memory(vec![deck], vec![])?;
+    /// package.set_col_data(None, Some(11), None, None, None, None, None, None, None);
+    /// let mut apkg = Cursor::new(Vec::new());
+    /// package.write(&mut apkg)?;
+    ///
+    /// let package = Package::read_from_reader(apkg)?;
+    /// assert_eq!(package.decks[0].notes()[0].fields(), ["Capital of France?", "Paris"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_from_reader<R: Read + Seek>(reader: R) -> Result<Package, Error> {
+        crate::apkg_reader::read_package(reader)
+    }
+
+    /// Reads a package from an `.apkg` file, see [`Package::read_from_reader`]
+    ///
+    /// Returns `Err` if the `file` cannot be opened or is not a valid package
+    #[cfg(feature = "fs")]
+    pub fn read_from_file(file: &str) -> Result<Package, Error> {
+        Self::read_from_reader(File::open(file)?)
+    }
+
+    /// Creates a deep copy of the package, so that several variants can be built from one base package
+    ///
+    /// Decks, media files and all config entries are copied; media given as paths is not read.
+    ///
+    /// Returns `Err` if a part of the package cannot be duplicated
+    pub fn try_clone(&self) -> Result<Package, Error> {
+        Ok(Self {
+            decks: self.decks.clone(),
+            media_files: self.media_files.clone(),
+            configs: self.configs.clone(),
+            deck_configs: self.deck_configs.clone(),
+            deck_infos: self.deck_infos.clone(),
+            notetypes: self.notetypes.clone(),
+            field_entries: self.field_entries.clone(),
+            template_entries: self.template_entries.clone(),
+            graves: self.graves.clone(),
+            tags: self.tags.clone(),
+            col_crt: self.col_crt,
+            col_ver: self.col_ver,
+            col_scm: self.col_scm,
+            col_usn: self.col_usn,
+            col_ls: self.col_ls,
+            col_conf: self.col_conf.clone(),
+            col_models: self.col_models.clone(),
+            col_decks: self.col_decks.clone(),
+            col_dconf: self.col_dconf.clone(),
+            default_note_type: self.default_note_type,
+            collection_conf: self.collection_conf.clone(),
+            scheduler_version: self.scheduler_version,
+            filtered_decks: self.filtered_decks.clone(),
+            omit_empty_media_entry: self.omit_empty_media_entry,
+            collection_id: self.collection_id,
+            media_index_base: self.media_index_base,
+            zip_mtime: self.zip_mtime,
+            integrity_check: self.integrity_check,
+            collapsed_tags: self.collapsed_tags.clone(),
+            drop_duplicate_guids: self.drop_duplicate_guids,
+            media_pool: self.media_pool.clone(),
+            anki_version: self.anki_version,
         })
     }
 
+    /// Sets collection-wide settings like sibling burying, see [`CollectionConf`]
+    ///
+    /// Only settings set this way are written for collection versions 16 and newer, Anki uses its
+    /// own defaults for everything else.
+    pub fn set_collection_conf(&mut self, collection_conf: CollectionConf) {
+        self.collection_conf = Some(collection_conf);
+    }
+
+    /// Sets collection config keys from a JSON object, e.g. `sortType` for the browser
+    ///
+    /// The keys are added to [`CollectionConf::extra`] of the package's settings, starting from the default
+    /// [`CollectionConf`] if none were set, so the typed settings of [`CollectionConf`] and the ones set on
+    /// the package take precedence over keys with the same name. A later [`Package::set_collection_conf`]
+    /// replaces them. A `curDeck` that is not a deck of the package is replaced with the first deck of the
+    /// package.
+    ///
+    /// Returns `Err` if `conf` is not a JSON object
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::Package;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut package = Package::new(vec![], vec![])?;
+    /// package.set_collection_conf_json(serde_json::json!({"sortType": "noteCrt", "sortBackwards": true}))?;
+    /// assert!(package.set_collection_conf_json(serde_json::json!(["sortType"])).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_collection_conf_json(&mut self, conf: serde_json::Value) -> Result<(), Error> {
+        match conf {
+            serde_json::Value::Object(conf) => {
+                self.collection_conf.get_or_insert_with(CollectionConf::default).extra.extend(conf);
+                Ok(())
+            }
+            conf => Err(Error::InvalidCollectionConf(conf.to_string())),
+        }
+    }
+
+    /// Writes the package in the format of the given Anki version
+    ///
+    /// This sets the collection version, overriding the one given to `set_col_data`, together with the
+    /// tables note types and decks are written to and the name of the collection in the archive:
+    /// * [`AnkiVersion::Legacy`] writes them as JSON in the `col` table of `collection.anki2`
+    /// * [`AnkiVersion::Modern`] writes them to the `notetypes`, `fields`, `templates` and `decks`
+    ///   tables of `collection.anki21`, and a `meta` entry. Entries added with `add_notetype_entry`
+    ///   and the like take precedence over the ones generated for the models and decks with the same id.
+    ///
+    /// Without a target version the package is written to `collection.anki2` with the collection version of
+    /// `set_col_data` (18 by default). From version 16 on, note types and decks are written to the tables
+    /// like for [`AnkiVersion::Modern`].
+    pub fn target_anki_version(&mut self, version: AnkiVersion) {
+        self.anki_version = Some(version);
+        self.col_ver = Some(version.col_ver());
+    }
+
+    /// Sets the scheduler version (`schedVer` and `sched2021`) of the collection
+    ///
+    /// Filtered decks are written in the format of this version. If it isn't set, Anki treats the
+    /// collection as using the v1 scheduler.
+    pub fn set_scheduler_version(&mut self, scheduler_version: SchedulerVersion) {
+        self.scheduler_version = Some(scheduler_version);
+    }
+
+    /// Adds a filtered deck to the package, see [`FilteredDeck`]
+    ///
+    /// Like regular decks, filtered decks are written to `col.decks`.
+    pub fn add_filtered_deck(&mut self, filtered_deck: FilteredDeck) {
+        self.filtered_decks.push(filtered_deck);
+    }
+
+    /// Sets whether the `media` entry is left out of packages without media files
+    ///
+    /// By default, it is written containing `{}`, which is what Anki itself does.
+    pub fn set_omit_empty_media_entry(&mut self, omit: bool) {
+        self.omit_empty_media_entry = omit;
+    }
+
+    /// Sets the index of the first media file in the archive, the others follow in the order they were
+    /// added
+    ///
+    /// By default the index base is 0. A higher base keeps the entries from colliding with the numbered
+    /// files of another package, e.g. when merging the media of several packages.
+    pub fn set_media_index_base(&mut self, base: usize) {
+        self.media_index_base = base;
+    }
+
+    /// Shares a [`MediaPool`] with this package, the files of the pool its notes, templates and CSS refer
+    /// to are written after its own media files
+    ///
+    /// A media file of the package takes precedence over a file of the pool with the same name.
+    pub fn set_media_pool(&mut self, pool: Arc<MediaPool>) {
+        self.media_pool = Some(pool);
+    }
+
+    /// Sets the modification time of the entries in the archive, in seconds since the Unix epoch
+    ///
+    /// By default the entries get the timestamp the package is written with, so writing the same package
+    /// with [`Package::write_timestamp`] gives the same bytes every time.
+    pub fn set_zip_mtime(&mut self, unix_secs: i64) {
+        self.zip_mtime = Some(unix_secs);
+    }
+
+    /// Checks the generated collection with `PRAGMA integrity_check` and `PRAGMA foreign_key_check` before
+    /// it is zipped, which is off by default
+    ///
+    /// Writing fails with [`Error::IntegrityCheck`] if SQLite reports problems, instead of Anki failing to
+    /// import the package.
+    pub fn set_integrity_check(&mut self, enabled: bool) {
+        self.integrity_check = enabled;
+    }
+
+    /// Sets whether notes with the GUID of an earlier note of the package are left out with a warning when
+    /// writing, instead of failing with [`Error::DuplicateNoteGuid`], which is the default
+    ///
+    /// Anki can't import several notes with the same GUID reliably, which happens e.g. when the default
+    /// GUIDs of notes with identical fields collide.
+    pub fn set_drop_duplicate_guids(&mut self, drop: bool) {
+        self.drop_duplicate_guids = drop;
+    }
+
+    /// Sets the id of the row in the `col` table, which SQLite assigns (1) by default
+    ///
+    /// Returns `Err` if `id` is not positive
+    pub fn set_collection_id(&mut self, id: i64) -> Result<(), Error> {
+        if id <= 0 {
+            return Err(Error::InvalidCollectionId(id));
+        }
+        self.collection_id = Some(id);
+        Ok(())
+    }
+
+    /// Sets the note type (model) that Anki's "Add" dialog selects by default after importing
+    ///
+    /// By default this is the model of the first note in the package.
+    ///
+    /// Returns `Err` if no note in the package uses the model with `model_id`
+    pub fn set_default_note_type(&mut self, model_id: i64) -> Result<(), Error> {
+        let model_in_package = self
+            .decks
+            .iter()
+            .flat_map(|deck| deck.notes())
+            .any(|note| note.model_id() == model_id);
+        if !model_in_package {
+            return Err(Error::ModelNotFound(model_id));
+        }
+        self.default_note_type = Some(model_id);
+        Ok(())
+    }
+
+    /// Changes the id of the model (note type) `old_id` to `new_id` in every note using it, e.g. to
+    /// resolve a clash between different note types with the same id from merged packages
+    ///
+    /// The models registered in the decks, notetype, field and template entries of the model and the
+    /// default note type are updated too.
+    ///
+    /// Returns the number of notes changed, or `Err` if no note uses the model `old_id` or another
+    /// model (of a note, registered in a deck or added with [`Package::add_notetype_entry`]) already has
+    /// the id `new_id`
+    pub fn remap_model(&mut self, old_id: i64, new_id: i64) -> Result<usize, Error> {
+        let notes = || self.decks.iter().flat_map(|deck| deck.notes());
+        if !notes().any(|note| note.model_id() == old_id) {
+            return Err(Error::ModelNotFound(old_id));
+        }
+        let id_in_use = notes().any(|note| note.model_id() == new_id)
+            || self.decks.iter().any(|deck| deck.models().contains_key(&new_id))
+            || self.notetypes.iter().any(|notetype| notetype.id == new_id);
+        if new_id != old_id && id_in_use {
+            return Err(Error::ModelIdInUse(new_id));
+        }
+
+        let mut changed = 0;
+        for deck in &mut self.decks {
+            deck.remap_model(old_id, new_id);
+            for note in deck.notes_mut() {
+                if note.model_id() == old_id {
+                    note.set_model_id(new_id);
+                    changed += 1;
+                }
+            }
+        }
+        self.notetypes
+            .iter_mut()
+            .filter(|notetype| notetype.id == old_id)
+            .for_each(|notetype| notetype.id = new_id);
+        self.field_entries
+            .iter_mut()
+            .filter(|field| field.ntid == old_id)
+            .for_each(|field| field.ntid = new_id);
+        self.template_entries
+            .iter_mut()
+            .filter(|template| template.ntid == old_id)
+            .for_each(|template| template.ntid = new_id);
+        if self.default_note_type == Some(old_id) {
+            self.default_note_type = Some(new_id);
+        }
+        Ok(changed)
+    }
+
+    /// Checks the CSS of every model used by a note with [`Model::validate_css`](crate::Model::validate_css)
+    /// and that the files it refers to with `url(...)` or `@import` are media files of the package
+    ///
+    /// References to URLs and `data:` are not checked.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Error, MediaFile, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let model = basic_model().css("@font-face { font-family: Serif; src: url('_serif.ttf'); }");
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(model, vec!["front", "back"])?);
+    /// let package = Package::new_from_memory(vec![deck.clone()], vec![])?;
+    /// assert!(matches!(package.validate_css(), Err(Error::MissingCssMedia(_, name)) if name == "_serif.ttf"));
+    ///
+    /// let package = Package::new_from_memory(vec![deck], vec![MediaFile::new_from_bytes(b"", "_serif.ttf")])?;
+    /// package.validate_css()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate_css(&self) -> Result<(), Error> {
+        let media_names: HashSet<&str> = self.media_files.iter().map(MediaFile::name).collect();
+        let mut checked = HashSet::new();
+        for note in self.decks.iter().flat_map(|deck| deck.notes()) {
+            let model = note.model();
+            if !checked.insert(model.id) {
+                continue;
+            }
+            model.validate_css()?;
+            if let Some(missing) = model.css_media_refs().into_iter().find(|reference| {
+                !is_external_ref(reference)
+                    && !media_names.contains(reference.as_str())
+                    && !media_names.contains(percent_decode(reference).as_str())
+            }) {
+                return Err(Error::MissingCssMedia(model.name().to_string(), missing));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that all media files on the filesystem exist, can be opened and have a usable filename, without
+    /// reading them
+    ///
+    /// Returns `Err(Error::MediaNotFound)` listing every media file that doesn't. Writing the package does the
+    /// same check before anything is written.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{Error, Package};
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let package = Package::new(vec![], vec!["does/not/exist.mp3".to_string()])?;
+    /// assert!(matches!(package.validate(), Err(Error::MediaNotFound { paths }) if paths.len() == 1));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<(), Error> {
+        let paths: Vec<PathBuf> = self
+            .media_files
+            .iter()
+            .filter(|media_file| !media_file.is_usable())
+            .map(MediaFile::source)
+            .collect();
+        if paths.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::MediaNotFound { paths })
+        }
+    }
+
+    /// Rewrites media references in note fields which don't exactly match a media file name of the
+    /// package, but do so ignoring case, `%XX` escapes, HTML entities and directories (`Images\\My%20Pic.PNG`
+    /// becomes `my pic.png` if the package has a media file named that way)
+    ///
+    /// References to URLs and `data:` are left alone. Returns which references were rewritten and which
+    /// still don't match any media file.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, MediaFile, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_model(), vec![r#"<img src="img/My%20Pic.PNG">"#, "[sound:gone.mp3]"])?);
+    /// let mut package = Package::new_from_memory(vec![deck], vec![MediaFile::new_from_bytes(b"", "my pic.png")])?;
+    ///
+    /// let report = package.resolve_media_refs();
+    /// assert_eq!(package.decks[0].notes()[0].fields()[0], r#"<img src="my pic.png">"#);
+    /// assert_eq!(report.unresolved, ["gone.mp3"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn resolve_media_refs(&mut self) -> MediaResolutionReport {
+        let media_names: HashSet<&str> = self.media_files.iter().map(MediaFile::name).collect();
+        let mut normalized_names: HashMap<String, &str> = HashMap::new();
+        for media_file in &self.media_files {
+            normalized_names
+                .entry(normalize_media_ref(media_file.name()))
+                .or_insert(media_file.name());
+        }
+
+        let mut report = MediaResolutionReport::default();
+        for note in self.decks.iter_mut().flat_map(|deck| deck.notes_mut()) {
+            for field in note.fields_mut() {
+                *field = rewrite_media_refs(field, |reference| {
+                    if media_names.contains(reference) || is_external_ref(reference) {
+                        return None;
+                    }
+                    match normalized_names.get(&normalize_media_ref(reference)) {
+                        Some(&name) => {
+                            let rewrite = (reference.to_string(), name.to_string());
+                            if !report.rewritten.contains(&rewrite) {
+                                report.rewritten.push(rewrite);
+                            }
+                            Some(name.to_string())
+                        }
+                        None => {
+                            if !report.unresolved.iter().any(|unresolved| unresolved == reference) {
+                                report.unresolved.push(reference.to_string());
+                            }
+                            None
+                        }
+                    }
+                });
+            }
+        }
+        report
+    }
+
+    /// Removes the media files no note field, template or CSS of the package refers to, like Anki's
+    /// "Check Media" deleting unused files
+    ///
+    /// Files starting with `_` are kept, see [`Package::add_template_asset`]. Returns the removed files,
+    /// their path on the filesystem or their name for media files created from bytes.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, MediaFile, Note, Package};
+    /// use std::path::PathBuf;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_model(), vec![r#"<img src="used.png">"#, "back"])?);
+    /// let media = vec![
+    ///     MediaFile::new_from_bytes(b"", "used.png"),
+    ///     MediaFile::new_from_bytes(b"", "unused.png"),
+    ///     MediaFile::new_from_bytes(b"", "_font.ttf"),
+    /// ];
+    /// let mut package = Package::new_from_memory(vec![deck], media)?;
+    ///
+    /// assert_eq!(package.remove_unused_media(), [PathBuf::from("unused.png")]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_unused_media(&mut self) -> Vec<PathBuf> {
+        let refs = self.media_refs();
+        let (kept, removed) = std::mem::take(&mut self.media_files)
+            .into_iter()
+            .partition(|media_file| media_file.name().starts_with('_') || refs.contains(media_file.name()));
+        self.media_files = kept;
+        removed.iter().map(MediaFile::source).collect::<Vec<_>>()
+    }
+
     /// Writes the package to any writer that implements Write and Seek
     pub fn write<W: Write + Seek>(&mut self, writer: W) -> Result<(), Error> {
-        self.write_maybe_timestamp(writer, None)
+        self.write_maybe_timestamp(writer, None, PackageFormat::Legacy)
+    }
+
+    /// Writes the package to any writer that implements Write and Seek in the given archive format
+    ///
+    /// [`PackageFormat::Legacy`] is what [`Package::write`] writes. `PackageFormat::LatestZstd`, with the `zstd`
+    /// feature, always writes collection version 18, like [`AnkiVersion::Modern`], and needs Anki 2.1.50 or
+    /// newer to import.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, PackageFormat};
+    /// use std::io::Cursor;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Capitals", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// let mut package = deck.into_package();
+    /// package.add_media_bytes(b"ID3...", "paris.mp3");
+    /// package.write_with_format(Cursor::new(Vec::new()), PackageFormat::Legacy)?;
+    /// #[cfg(feature = "zstd")]
+    /// package.write_with_format(Cursor::new(Vec::new()), PackageFormat::LatestZstd)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_with_format<W: Write + Seek>(&mut self, writer: W, format: PackageFormat) -> Result<(), Error> {
+        self.write_maybe_timestamp(writer, None, format)
+    }
+
+    /// Writes the package to any writer that implements Write and Seek in the given archive format using a
+    /// timestamp, see [`Package::write_with_format`]
+    pub fn write_with_format_timestamp<W: Write + Seek>(
+        &mut self,
+        writer: W,
+        timestamp: f64,
+        format: PackageFormat,
+    ) -> Result<(), Error> {
+        self.write_maybe_timestamp(writer, Some(timestamp), format)
     }
 
     /// Writes the package to any writer that implements Write and Seek using a timestamp
@@ -301,96 +1140,307 @@ impl Package {
         writer: W,
         timestamp: f64,
     ) -> Result<(), Error> {
-        self.write_maybe_timestamp(writer, Some(timestamp))
+        self.write_maybe_timestamp(writer, Some(timestamp), PackageFormat::Legacy)
     }
 
     /// Writes the package to a file
     ///
     /// Returns `Err` if the `file` cannot be created
+    #[cfg(feature = "fs")]
     pub fn write_to_file(&mut self, file: &str) -> Result<(), Error> {
         let file = File::create(file)?;
-        self.write_maybe_timestamp(file, None)
+        self.write_maybe_timestamp(file, None, PackageFormat::Legacy)
     }
 
     /// Writes the package to a file using a timestamp
     ///
     /// Returns `Err` if the `file` cannot be created
+    #[cfg(feature = "fs")]
     pub fn write_to_file_timestamp(&mut self, file: &str, timestamp: f64) -> Result<(), Error> {
         let file = File::create(file)?;
-        self.write_maybe_timestamp(file, Some(timestamp))
+        self.write_maybe_timestamp(file, Some(timestamp), PackageFormat::Legacy)
     }
 
     fn write_maybe_timestamp<W: Write + Seek>(
         &mut self,
         writer: W,
         timestamp_opt: Option<f64>,
+        format: PackageFormat,
     ) -> Result<(), Error> {
-        let db_file = NamedTempFile::new()?.into_temp_path();
-        let mut conn = Connection::open(&db_file).map_err(database_error)?;
+        self.media_files
+            .iter()
+            .try_for_each(MediaFile::check_not_directory)?;
+        self.validate()?;
+        self.check_media_names()?;
+        self.check_pinned_ids()?;
+        self.check_default_decks()?;
+        self.check_deck_configs()?;
+        let skipped_notes = self.duplicate_guid_notes()?;
+
+        let mut conn = Connection::open_in_memory().map_err(database_error)?;
         let transaction = conn.transaction().map_err(database_error)?;
 
         let timestamp_sec = timestamp_opt
             .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64());
 
-        self.write_schema_and_col_table(&transaction, timestamp_sec)?;
-        self.write_deck_content_data(&transaction, timestamp_sec)?;
+        if self.needs_scheduler_version() {
+            log::warn!(
+                "The package contains cards with review data but no scheduler version, Anki will schedule \
+                 them with the v1 scheduler. Call `Package::set_scheduler_version` if they were studied with \
+                 the v2 or v3 scheduler."
+            );
+        }
+
+        self.write_schema_and_col_table(&transaction, timestamp_sec, self.collection_version(format))?;
+        self.write_deck_content_data(&transaction, timestamp_sec, &skipped_notes)?;
 
         transaction.commit().map_err(database_error)?;
-        conn.close().map_err(|(_, e)| database_error(e)).expect("Should always close");
+        if self.integrity_check {
+            check_integrity(&conn)?;
+        }
+        let collection = conn.serialize(MAIN_DB).map_err(database_error)?;
 
+        // All entries get the same modification time so that the archive only depends on the package
+        let zip_mtime = self.zip_mtime.unwrap_or(timestamp_sec as i64);
+        let options = FileOptions::default().last_modified_time(zip_date_time(zip_mtime));
         let mut outzip = ZipWriter::new(writer);
-        outzip
-            .start_file("collection.anki2", FileOptions::default())
-            .map_err(zip_error)?;
-        outzip.write_all(&read_file_bytes(db_file)?)?;
+        let mut entry_names = HashSet::new();
+        #[cfg(feature = "zstd")]
+        if format == PackageFormat::LatestZstd {
+            // The entries are compressed with zstd already, so they are stored as they are
+            let options = options.compression_method(CompressionMethod::Stored);
+            // Package metadata (version 3: zstd-compressed collection.anki21b and media, protobuf media map)
+            start_zip_entry(&mut outzip, &mut entry_names, "meta", options)?;
+            outzip.write_all(&ProtoWriter::new().int(1, 3).finish())?;
+            start_zip_entry(&mut outzip, &mut entry_names, "collection.anki21b", options)?;
+            outzip.write_all(&zstd::encode_all(&collection[..], 0)?)?;
+            self.write_zstd_media(&mut outzip, &mut entry_names, options)?;
+            outzip.finish().map_err(zip_error)?;
+            return Ok(());
+        }
+        if self.anki_version == Some(AnkiVersion::Modern) {
+            // Package metadata (version 2: collection.anki21 and a JSON media map)
+            start_zip_entry(&mut outzip, &mut entry_names, "meta", options)?;
+            outzip.write_all(&ProtoWriter::new().int(1, 2).finish())?;
+            start_zip_entry(&mut outzip, &mut entry_names, "collection.anki21", options)?;
+        } else {
+            start_zip_entry(&mut outzip, &mut entry_names, "collection.anki2", options)?;
+        }
+        outzip.write_all(&collection)?;
 
-        let media_file_idx_to_path = self
-            .media_files
-            .iter()
-            .enumerate()
-            .collect::<HashMap<usize, &MediaFile>>();
-        let media_map = media_file_idx_to_path
-            .clone()
-            .into_iter()
-            .map(|(id, media_file)| {
-                (
-                    id.to_string(),
-                    match media_file {
-                        MediaFile::Path(path) => path.file_name()
-                            .expect("Should always have a filename")
-                            .to_str()
-                            .expect("should always have string"),
-                        MediaFile::Bytes(_, name) => name,
-                    },
-                )
-            })
-            .collect::<HashMap<String, &str>>();
-        let media_json = serde_json::to_string(&media_map).map_err(json_error)?;
-        outzip
-            .start_file("media", FileOptions::default())
-            .map_err(zip_error)?;
-        outzip.write_all(media_json.as_bytes())?;
-
-        for (idx, &media_file) in &media_file_idx_to_path {
-            outzip
-                .start_file(idx.to_string(), FileOptions::default())
-                .map_err(zip_error)?;
-            outzip.write_all(&match media_file {
-                MediaFile::Path(path) => read_file_bytes(path)?,
-                MediaFile::Bytes(bytes, _) => bytes.clone(),
-            })?;
+        if self.media_files.is_empty() && self.media_pool.is_none() {
+            // Without media files the media entry is always the empty map, so skip building it
+            if !self.omit_empty_media_entry {
+                start_zip_entry(&mut outzip, &mut entry_names, "media", options)?;
+                outzip.write_all(b"{}")?;
+            }
+        } else {
+            self.write_media(&mut outzip, &mut entry_names, options)?;
         }
         outzip.finish().map_err(zip_error)?;
         Ok(())
     }
 
-    fn write_schema_and_col_table(&self, transaction: &Transaction, timestamp_sec: f64) -> Result<(), Error> {
-        // Determine version early to use for conditional schema creation
-        let ver: i64 = self.col_ver.unwrap_or(18);
-
-        // Use version-appropriate schema
-        if ver < 12 {
-            // Anki 2.0 (version 11 and below) - minimal tables only
+    /// Writes the media entry mapping the numbered entries to file names, followed by the media files
+    fn write_media<W: Write + Seek>(
+        &self,
+        outzip: &mut ZipWriter<W>,
+        entry_names: &mut HashSet<String>,
+        options: FileOptions,
+    ) -> Result<(), Error> {
+        // Media files are numbered in the order they were added, starting at the index base, followed
+        // by the used files of the media pool
+        let pooled_files = self.used_pooled_media();
+        let media_map = self
+            .media_files
+            .iter()
+            .map(MediaFile::name)
+            .chain(pooled_files.iter().map(|&(name, _)| name))
+            .enumerate()
+            .map(|(i, name)| ((self.media_index_base + i).to_string(), name))
+            .collect::<BTreeMap<String, &str>>();
+        if !(media_map.is_empty() && self.omit_empty_media_entry) {
+            let media_json = serde_json::to_string(&media_map).map_err(json_error)?;
+            start_zip_entry(outzip, entry_names, "media", options)?;
+            outzip.write_all(media_json.as_bytes())?;
+        }
+
+        let mut idx = self.media_index_base..;
+        for media_file in &self.media_files {
+            start_zip_entry(outzip, entry_names, &idx.next().unwrap().to_string(), options)?;
+            outzip.write_all(&media_file.read_bytes()?)?;
+        }
+        for (_, bytes) in pooled_files {
+            start_zip_entry(outzip, entry_names, &idx.next().unwrap().to_string(), options)?;
+            outzip.write_all(bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the media files compressed with zstd, followed by the media entry listing their names, sizes
+    /// and SHA-1 hashes as a zstd-compressed protobuf `MediaEntries` message, for [`PackageFormat::LatestZstd`]
+    #[cfg(feature = "zstd")]
+    fn write_zstd_media<W: Write + Seek>(
+        &self,
+        outzip: &mut ZipWriter<W>,
+        entry_names: &mut HashSet<String>,
+        options: FileOptions,
+    ) -> Result<(), Error> {
+        let pooled_files = self.used_pooled_media();
+        let mut entries = ProtoWriter::new();
+        let mut write_file = |i: usize, name: &str, bytes: &[u8]| -> Result<(), Error> {
+            let zip_name = self.media_index_base + i;
+            start_zip_entry(outzip, entry_names, &zip_name.to_string(), options)?;
+            outzip.write_all(&zstd::encode_all(bytes, 0)?)?;
+            let mut entry = ProtoWriter::new()
+                .string(1, name)
+                .int(2, bytes.len() as i64)
+                .message(3, &Sha1::digest(bytes));
+            // Anki expects the files in entries named by their index unless told otherwise
+            if zip_name != i {
+                entry = entry.int(255, zip_name as i64);
+            }
+            entries = std::mem::take(&mut entries).message(1, &entry.finish());
+            Ok(())
+        };
+        for (i, media_file) in self.media_files.iter().enumerate() {
+            write_file(i, media_file.name(), &media_file.read_bytes()?)?;
+        }
+        for (i, &(name, bytes)) in pooled_files.iter().enumerate() {
+            write_file(self.media_files.len() + i, name, bytes)?;
+        }
+        let entries = entries.finish();
+        if !(entries.is_empty() && self.omit_empty_media_entry) {
+            start_zip_entry(outzip, entry_names, "media", options)?;
+            outzip.write_all(&zstd::encode_all(&entries[..], 0)?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the media files notes, templates or CSS refer to
+    fn media_refs(&self) -> HashSet<String> {
+        fn add_refs(refs: &mut HashSet<String>, text: &str) {
+            for range in media_ref_ranges(text) {
+                refs.insert(percent_decode(&decode_entities(&text[range])));
+            }
+        }
+        let mut refs = HashSet::new();
+        let mut models = HashSet::new();
+        for note in self.decks.iter().flat_map(|deck| deck.notes()) {
+            note.fields().iter().for_each(|field| add_refs(&mut refs, field));
+            let model = note.model();
+            if models.insert(model.id) {
+                for template in model.templates() {
+                    add_refs(&mut refs, &template.qfmt);
+                    add_refs(&mut refs, &template.afmt);
+                }
+                refs.extend(model.css_media_refs().iter().map(|reference| percent_decode(reference)));
+            }
+        }
+        refs
+    }
+
+    /// Returns the files of the media pool that notes, templates or CSS refer to and that aren't
+    /// media files of the package itself
+    fn used_pooled_media(&self) -> Vec<(&str, &[u8])> {
+        let Some(pool) = &self.media_pool else {
+            return vec![];
+        };
+        let refs = self.media_refs();
+        let own_names: HashSet<&str> = self.media_files.iter().map(MediaFile::name).collect();
+        pool.files()
+            .filter(|(name, _)| refs.contains(*name) && !own_names.contains(name))
+            .collect()
+    }
+
+    /// Makes sure no two different media files are stored under the same name, e.g. `a/logo.png` and
+    /// `b/logo.png`, of which Anki would only keep one
+    fn check_media_names(&self) -> Result<(), Error> {
+        let mut by_name: HashMap<&str, &MediaFile> = HashMap::new();
+        for media_file in &self.media_files {
+            match by_name.get(media_file.name()) {
+                Some(first) if !first.is_same_file(media_file) => {
+                    return Err(Error::MediaCollision(
+                        media_file.name().to_string(),
+                        first.source(),
+                        media_file.source(),
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    by_name.insert(media_file.name(), media_file);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure no two notes and no two cards were given the same ID, which would otherwise fail
+    /// halfway through the write on a UNIQUE constraint
+    fn check_pinned_ids(&self) -> Result<(), Error> {
+        let mut note_ids = HashSet::new();
+        let mut card_ids = HashSet::new();
+        for note in self.decks.iter().flat_map(|deck| deck.notes()) {
+            if let Some(id) = note.pinned_id()
+                && !note_ids.insert(id)
+            {
+                return Err(Error::DuplicateNoteId(id));
+            }
+            for id in note.cards().iter().filter_map(|card| card.custom_card_id) {
+                if !card_ids.insert(id) {
+                    return Err(Error::DuplicateCardId(id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the indices of the notes of each deck that have the GUID of an earlier note and are left out
+    ///
+    /// Returns `Err` for the first such note unless duplicates are dropped, see
+    /// [`Package::set_drop_duplicate_guids`]
+    fn duplicate_guid_notes(&self) -> Result<Vec<HashSet<usize>>, Error> {
+        let mut guids = HashSet::new();
+        let mut skipped_notes = vec![HashSet::new(); self.decks.len()];
+        for (deck, skipped) in self.decks.iter().zip(&mut skipped_notes) {
+            for (i, note) in deck.notes().iter().enumerate() {
+                let guid = note.get_guid();
+                if guids.contains(&guid) {
+                    if !self.drop_duplicate_guids {
+                        return Err(Error::DuplicateNoteGuid(guid));
+                    }
+                    log::warn!("Leaving out note {} of deck \"{}\", its GUID {} is used by another note", i, deck.name, guid);
+                    skipped.insert(i);
+                } else {
+                    guids.insert(guid);
+                }
+            }
+        }
+        Ok(skipped_notes)
+    }
+
+    /// Returns the collection version (`col.ver`) the package is written with in `format`
+    fn collection_version(&self, format: PackageFormat) -> i64 {
+        match format {
+            #[cfg(feature = "zstd")]
+            PackageFormat::LatestZstd => AnkiVersion::Modern.col_ver(),
+            PackageFormat::Legacy => self
+                .anki_version
+                .map(|version| version.col_ver())
+                .or(self.col_ver)
+                .unwrap_or(18),
+        }
+    }
+
+    fn write_schema_and_col_table(&self, transaction: &Transaction, timestamp_sec: f64, ver: i64) -> Result<(), Error> {
+        let scheduler_version = self.scheduler_version.unwrap_or(SchedulerVersion::V1);
+        for filtered_deck in &self.filtered_decks {
+            filtered_deck.check_scheduler(scheduler_version)?;
+        }
+
+        // Use version-appropriate schema
+        if ver < 12 {
+            // Anki 2.0 (version 11 and below) - minimal tables only
             transaction.execute_batch(APKG_SCHEMA_V11).map_err(database_error)?;
         } else {
             // Anki 2.1 (version 12+) - includes all modern tables
@@ -451,6 +1501,19 @@ impl Package {
                     ],
                 ).map_err(database_error)?;
             }
+            // Register the tags used by notes (and the parents of hierarchical tags) so that they show up
+            // as a tree in the sidebar
+            let explicit_tags: HashSet<String> = self.tags.iter().map(|t| t.tag.to_lowercase()).collect();
+            let collapsed_tags: HashSet<String> = self.collapsed_tags.iter().map(|t| t.to_lowercase()).collect();
+            for tag in self.note_tags() {
+                if explicit_tags.contains(&tag.to_lowercase()) {
+                    continue;
+                }
+                transaction.execute(
+                    "INSERT INTO tags (tag, usn, collapsed, config) VALUES (?, ?, ?, NULL)",
+                    params![tag, -1, collapsed_tags.contains(&tag.to_lowercase())],
+                ).map_err(database_error)?;
+            }
         }
 
         // Initialize dconf_map_for_col before version check (needed for col table later)
@@ -525,7 +1588,8 @@ impl Package {
         ).map_err(database_error)?; // Ensure this uses map_err(database_error)
 
         // Insert deck_info entries
-        for deck_info_entry in &self.deck_infos {
+        let generated_deck_infos = self.generated_deck_info_entries(ver, scheduler_version, timestamp_sec)?;
+        for deck_info_entry in self.deck_infos.iter().chain(&generated_deck_infos) {
             transaction.execute(
                 "INSERT INTO decks (id, name, mtime_secs, usn, common, kind) VALUES (?, ?, ?, ?, ?, ?)",
                 params![
@@ -566,17 +1630,33 @@ impl Package {
             }
             info!("Wrote {} entries to notetypes table.", self.notetypes.len());
 
+            let generated_models = self.generated_notetype_entries(ver, timestamp_sec)?;
+            for (notetype_entry, _, _) in &generated_models {
+                transaction.execute(
+                    "INSERT INTO notetypes (id, name, mtime_secs, usn, config) VALUES (?, ?, ?, ?, ?)",
+                    params![
+                        notetype_entry.id,
+                        notetype_entry.name,
+                        notetype_entry.mtime_secs,
+                        notetype_entry.usn,
+                        notetype_entry.config,
+                    ],
+                ).map_err(database_error)?;
+            }
+
             // Create fields table and insert data
             transaction.execute_batch(APKG_SCHEMA_FIELDS).map_err(database_error)?;
             let mut stmt_fields = transaction.prepare("INSERT INTO fields (ntid, ord, name, config) VALUES (?, ?, ?, ?)").map_err(database_error)?;
-            for entry in &self.field_entries {
+            let generated_fields = generated_models.iter().flat_map(|(_, fields, _)| fields);
+            for entry in self.field_entries.iter().chain(generated_fields) {
                 stmt_fields.execute(params![entry.ntid, entry.ord, entry.name, entry.config]).map_err(database_error)?;
             }
             info!("Wrote {} entries to fields table.", self.field_entries.len());
 
             // Create templates table and insert data (CREATE TABLE is in APKG_SCHEMA)
             let mut stmt_templates = transaction.prepare("INSERT INTO templates (ntid, ord, name, mtime_secs, usn, config) VALUES (?, ?, ?, ?, ?, ?)").map_err(database_error)?;
-            for entry in &self.template_entries {
+            let generated_templates = generated_models.iter().flat_map(|(_, _, templates)| templates);
+            for entry in self.template_entries.iter().chain(generated_templates) {
                 stmt_templates.execute(params![entry.ntid, entry.ord, entry.name, entry.mtime_secs, entry.usn, entry.config]).map_err(database_error)?;
             }
             info!("Wrote {} entries to templates table.", self.template_entries.len());
@@ -617,6 +1697,10 @@ impl Package {
             decks_map_for_col.insert(deck_item.id.to_string(), deck_item.to_deck_db_entry());
         }
 
+        for parent_deck in self.parent_decks() {
+            decks_map_for_col.insert(parent_deck.id.to_string(), parent_deck.to_deck_db_entry());
+        }
+
         if !decks_map_for_col.contains_key("1") {
             let default_deck = Deck::new(1, "Default", "");
             decks_map_for_col.insert("1".to_string(), default_deck.to_deck_db_entry());
@@ -628,22 +1712,80 @@ impl Package {
         } else if ver >= 16 {
             "{}".to_string()
         } else {
-            serde_json::to_string(&decks_map_for_col).map_err(json_error)?
+            let mut decks_json = serde_json::to_value(&decks_map_for_col).map_err(json_error)?;
+            for filtered_deck in &self.filtered_decks {
+                decks_json[filtered_deck.id.to_string()] =
+                    filtered_deck.to_json(scheduler_version, timestamp_sec)?;
+            }
+            serde_json::to_string(&decks_json).map_err(json_error)?
         };
         
         let default_conf_json = "{\"activeDecks\": [1], \"addToCur\": true, \"collapseTime\": 1200, \"curDeck\": 1, \"curModel\": \"1607392319\", \"dueCounts\": true, \"estTimes\": true, \"newBury\": true, \"newSpread\": 0, \"nextPos\": 1, \"sortBackwards\": false, \"sortType\": \"noteFld\", \"timeLim\": 0}";
 
+        // curModel is the explicitly chosen default note type, otherwise the first model used in the package
+        let cur_model = self.default_note_type.or_else(|| {
+            self.decks
+                .iter()
+                .flat_map(|deck| deck.notes())
+                .map(|note| note.model_id())
+                .next()
+        });
+
+        // Explicitly configured collection settings are applied on top of the base conf
+        let conf_entries = self.collection_conf_entries();
+
         // Use custom conf if provided, otherwise use config_entry or default
         let conf_val = if let Some(ref custom_conf) = self.col_conf {
-            custom_conf.clone()
+            with_conf_entries(custom_conf, &conf_entries)?
         } else if ver >= 16 {
              "{}".to_string()
         } else if let Some(conf_entry) = self.configs.iter().find(|c| c.key == "conf") {
-             std::str::from_utf8(&conf_entry.val).unwrap_or(default_conf_json).to_string()
+             let conf = std::str::from_utf8(&conf_entry.val).unwrap_or(default_conf_json);
+             with_conf_entries(conf, &conf_entries)?
         } else {
-             default_conf_json.to_string()
+             let mut default_entries: Vec<_> = cur_model
+                 .map(|model_id| ("curModel", serde_json::Value::from(model_id)))
+                 .into_iter()
+                 .collect();
+             default_entries.extend(conf_entries.iter().cloned());
+             with_conf_entries(default_conf_json, &default_entries)?
         };
 
+        // The Add dialog starts in curDeck, which has to be one of the decks of the package
+        let conf_val = if ver >= 16 {
+            conf_val
+        } else {
+            let cur_deck = serde_json::from_str::<serde_json::Value>(&conf_val)
+                .ok()
+                .and_then(|conf| conf["curDeck"].as_i64());
+            with_conf_entries(&conf_val, &self.cur_deck_entries(cur_deck))?
+        };
+
+        // Since version 16 collection settings live in the config table instead of col.conf
+        if ver >= 16 {
+            let cur_deck = self
+                .configs
+                .iter()
+                .find(|config| config.key == "curDeck")
+                .and_then(|config| serde_json::from_slice::<i64>(&config.val).ok())
+                .unwrap_or(1);
+            let default_deck_entries = self.default_deck_entries();
+            let default_deck_entries = default_deck_entries.iter().map(|(key, val)| (key.as_str(), val.clone()));
+            for (key, val) in conf_entries
+                .iter()
+                .cloned()
+                .chain(self.cur_deck_entries(Some(cur_deck)))
+                .chain(default_deck_entries)
+            {
+                transaction
+                    .execute(
+                        "INSERT OR REPLACE INTO config (key, usn, mtime_secs, val) VALUES (?, ?, ?, ?)",
+                        params![key, -1, timestamp_sec as i64, val.to_string().into_bytes()],
+                    )
+                    .map_err(database_error)?;
+            }
+        }
+
         // Use custom dconf if provided, otherwise compute from deck configs
         let dconf_json_str = if let Some(ref custom_dconf) = self.col_dconf {
             custom_dconf.clone()
@@ -656,9 +1798,17 @@ impl Package {
         // Use the tags entry if it exists in the package or a string field from col_tags
         // Note: We don't currently have a separate tags table entry structure, but we can check config
         let tags_val = if let Some(tags_entry) = self.configs.iter().find(|c| c.key == "tags") {
-            std::str::from_utf8(&tags_entry.val).unwrap_or("{}")
+            std::str::from_utf8(&tags_entry.val).unwrap_or("{}").to_string()
+        } else if ver < 12 {
+            // Before the tags table existed, the col table kept a map of tag -> usn
+            let tags_map: serde_json::Map<String, serde_json::Value> = self
+                .note_tags()
+                .into_iter()
+                .map(|tag| (tag, serde_json::Value::from(-1)))
+                .collect();
+            serde_json::to_string(&tags_map).map_err(json_error)?
         } else {
-            "{}"
+            "{}".to_string()
         };
 
         // Use custom usn if provided, otherwise default to -1 (needs upload)
@@ -668,8 +1818,9 @@ impl Package {
         let ls_val = self.col_ls.unwrap_or(0);
 
         transaction.execute(
-            "INSERT INTO col (id, crt, mod, scm, ver, dty, usn, ls, conf, models, decks, dconf, tags) VALUES (NULL, ?, ?, ?, ?, 0, ?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO col (id, crt, mod, scm, ver, dty, usn, ls, conf, models, decks, dconf, tags) VALUES (?, ?, ?, ?, ?, 0, ?, ?, ?, ?, ?, ?, ?)",
             params![
+                self.collection_id,
                 crt_val,
                 mod_val,
                 scm_val,
@@ -686,20 +1837,2419 @@ impl Package {
         Ok(())
     }
 
-    fn write_deck_content_data(&mut self, transaction: &Transaction, timestamp_sec: f64) -> Result<(), Error> {
+    /// Returns the `notetypes`, `fields` and `templates` rows of the models used by notes for collection
+    /// version `ver`, except for note types added explicitly
+    ///
+    /// From version 16 on, these tables are the only place note types are stored.
+    fn generated_notetype_entries(&self, ver: i64, timestamp_sec: f64) -> Result<Vec<NormalizedModel>, Error> {
+        let mut entries = vec![];
+        if ver < 16 {
+            return Ok(entries);
+        }
+        let mut seen: HashSet<i64> = self.notetypes.iter().map(|notetype| notetype.id).collect();
+        for note in self.decks.iter().flat_map(|deck| deck.notes()) {
+            if seen.insert(note.model_id()) {
+                entries.push(note.model().clone().normalized_entries(timestamp_sec)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the `decks` rows of the decks and filtered decks for collection version `ver`, except for
+    /// decks added explicitly, and of the default deck
+    ///
+    /// From version 16 on, the `decks` table is the only place decks are stored.
+    fn generated_deck_info_entries(
+        &self,
+        ver: i64,
+        scheduler_version: SchedulerVersion,
+        timestamp_sec: f64,
+    ) -> Result<Vec<DeckInfoEntry>, Error> {
+        let mut entries = vec![];
+        if ver < 16 {
+            return Ok(entries);
+        }
+        let mut seen: HashSet<i64> = self.deck_infos.iter().map(|deck_info| deck_info.id).collect();
+        for deck in &self.decks {
+            if seen.insert(deck.id) {
+                entries.push(deck.to_deck_info_entry(timestamp_sec));
+            }
+        }
+        for deck in self.parent_decks() {
+            if seen.insert(deck.id) {
+                entries.push(deck.to_deck_info_entry(timestamp_sec));
+            }
+        }
+        for filtered_deck in &self.filtered_decks {
+            if seen.insert(filtered_deck.id) {
+                entries.push(filtered_deck.to_deck_info_entry(scheduler_version, timestamp_sec)?);
+            }
+        }
+        if seen.insert(1) {
+            entries.push(Deck::new(1, "Default", "").to_deck_info_entry(timestamp_sec));
+        }
+        Ok(entries)
+    }
+
+    /// Returns the parent decks of subdecks like `Spanish::Verbs` which are not decks of the package, as Anki
+    /// expects every parent of a deck to exist
+    ///
+    /// Deck names are compared ignoring case, like in Anki. The ids of the parent decks are derived from their
+    /// names, see [`parent_deck_id`].
+    fn parent_decks(&self) -> Vec<Deck> {
+        let regular_names = self.decks.iter().map(|deck| deck.name.as_str());
+        let all_names = regular_names.chain(self.filtered_decks.iter().map(|deck| deck.name.as_str()));
+        let normalized = |name: &str| name.split("::").map(str::trim).collect::<Vec<_>>().join("::").to_lowercase();
+        let mut names: HashSet<String> = all_names.clone().map(normalized).collect();
+        let mut ids: HashSet<i64> = self.decks.iter().map(|deck| deck.id).collect();
+        ids.extend(self.filtered_decks.iter().map(|deck| deck.id));
+        if ids.insert(1) {
+            names.insert("default".to_string());
+        }
+        let mut parents = vec![];
+        for name in all_names {
+            let components: Vec<&str> = name.split("::").map(str::trim).collect();
+            for end in 1..components.len() {
+                let parent = components[..end].join("::");
+                if names.insert(parent.to_lowercase()) {
+                    let mut id = parent_deck_id(&parent);
+                    while !ids.insert(id) {
+                        id += 1;
+                    }
+                    parents.push(Deck::new(id, &parent, ""));
+                }
+            }
+        }
+        parents
+    }
+
+    /// Returns `curDeck` and `activeDecks` entries selecting the first deck of the package if `cur_deck` is
+    /// not one of its decks, e.g. the default deck when the package doesn't use it
+    fn cur_deck_entries(&self, cur_deck: Option<i64>) -> Vec<(&'static str, serde_json::Value)> {
+        let deck_ids = self.regular_deck_ids();
+        match deck_ids.first() {
+            Some(&first) if !cur_deck.is_some_and(|cur_deck| deck_ids.contains(&cur_deck)) => vec![
+                ("curDeck", serde_json::Value::from(first)),
+                ("activeDecks", serde_json::json!([first])),
+            ],
+            _ => vec![],
+        }
+    }
+
+    /// Returns the ids of the decks of the package and of the regular decks of a custom `col.decks`
+    fn regular_deck_ids(&self) -> Vec<i64> {
+        let mut deck_ids: Vec<i64> = self.decks.iter().map(|deck| deck.id).collect();
+        if let Some(custom_decks) = self
+            .col_decks
+            .as_deref()
+            .and_then(|decks| serde_json::from_str::<serde_json::Value>(decks).ok())
+        {
+            let custom_decks = custom_decks.as_object().into_iter().flat_map(|decks| decks.values());
+            deck_ids.extend(
+                custom_decks
+                    .filter(|deck| deck["dyn"].as_i64().unwrap_or_default() == 0)
+                    .filter_map(|deck| deck["id"].as_i64()),
+            );
+        }
+        deck_ids
+    }
+
+    /// Makes sure the default deck of every model is a deck of the package, the default deck with id 1
+    /// is always written
+    fn check_default_decks(&self) -> Result<(), Error> {
+        let mut deck_ids = self.regular_deck_ids();
+        deck_ids.push(1);
+        for model in self.decks.iter().flat_map(|deck| deck.notes()).map(|note| note.model()) {
+            if let Some(deck_id) = model.get_default_deck()
+                && !deck_ids.contains(&deck_id)
+            {
+                return Err(Error::UnknownDefaultDeck(model.id, deck_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure every deck uses the default options preset or one added with
+    /// [`Package::add_deck_config_entry`] or a custom `col.dconf`
+    fn check_deck_configs(&self) -> Result<(), Error> {
+        let mut config_ids: HashSet<i64> = self.deck_configs.iter().map(|config| config.id).collect();
+        config_ids.insert(1);
+        if let Some(custom_dconf) = self
+            .col_dconf
+            .as_deref()
+            .and_then(|dconf| serde_json::from_str::<serde_json::Value>(dconf).ok())
+        {
+            let custom_ids = custom_dconf.as_object().into_iter().flat_map(|dconf| dconf.keys());
+            config_ids.extend(custom_ids.filter_map(|id| id.parse::<i64>().ok()));
+        }
+        match self.decks.iter().find(|deck| !config_ids.contains(&deck.config_id())) {
+            Some(deck) => Err(Error::UnknownDeckConfig(deck.id, deck.config_id())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the config entries holding the default deck of the models, which moved from the model's
+    /// `did` to the config table in collection version 16
+    fn default_deck_entries(&self) -> Vec<(String, serde_json::Value)> {
+        let mut seen = HashSet::new();
+        self.decks
+            .iter()
+            .flat_map(|deck| deck.notes())
+            .map(|note| note.model())
+            .filter(|model| seen.insert(model.id))
+            .filter_map(|model| {
+                let deck_id = model.get_default_deck()?;
+                Some((format!("_nt_{}_lastDeck", model.id), serde_json::Value::from(deck_id)))
+            })
+            .collect()
+    }
+
+    /// Returns the collection config keys that were set explicitly on this package
+    fn collection_conf_entries(&self) -> Vec<(&str, serde_json::Value)> {
+        let mut entries = Vec::new();
+        if let Some(ref collection_conf) = self.collection_conf {
+            entries.extend(collection_conf.entries());
+        }
+        if let Some(model_id) = self.default_note_type {
+            entries.push(("curModel", serde_json::Value::from(model_id)));
+        }
+        if let Some(scheduler_version) = self.scheduler_version {
+            entries.extend(scheduler_version.entries());
+        }
+        entries
+    }
+
+    /// Whether the package has cards with review history or scheduling data, but the scheduler
+    /// version they were studied with is neither set nor part of a custom `col.conf`
+    fn needs_scheduler_version(&self) -> bool {
+        let custom_conf_has_version = self
+            .col_conf
+            .as_deref()
+            .and_then(|conf| serde_json::from_str::<serde_json::Value>(conf).ok())
+            .is_some_and(|conf| conf.get("schedVer").is_some());
+        if self.scheduler_version.is_some() || custom_conf_has_version {
+            return false;
+        }
+        self.decks
+            .iter()
+            .flat_map(|deck| deck.notes())
+            .flat_map(|note| note.cards())
+            .any(|card| {
+                !card.review_history.is_empty()
+                    || card.has_review_source()
+                    || card.card_type.is_some_and(|card_type| card_type != 0)
+                    || card.reps.is_some_and(|reps| reps > 0)
+            })
+    }
+
+    /// Returns how many cards writing the package creates
+    ///
+    /// Notes generate their cards when they are created (one per template with content, one per cloze
+    /// number for cloze models) or are given them with `Note::with_cards`, so this doesn't render
+    /// anything.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_and_reversed_card_model, cloze_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["front", "back"])?);
+    /// deck.add_note(Note::new(cloze_model(), vec!["{{c1::Paris}} is in {{c2::France}}"])?);
+    /// let package = Package::new_from_memory(vec![deck], vec![])?;
+    /// assert_eq!(package.card_count(), 4);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn card_count(&self) -> usize {
+        self.decks
+            .iter()
+            .flat_map(|deck| deck.notes())
+            .map(|note| note.cards().len())
+            .sum()
+    }
+
+    /// Returns the groups of notes in all decks of the package that Anki considers duplicates: notes of the
+    /// same model whose first fields have the same checksum (`csum`) and text, see [`Deck::find_duplicates`]
+    ///
+    /// Anki looks for duplicates in the whole collection, so notes in different decks are duplicates too.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut europe = Deck::new(1234, "Europe", "");
+    /// europe.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// let mut capitals = Deck::new(1235, "Capitals", "");
+    /// capitals.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// capitals.add_note(Note::new(basic_model(), vec!["Capital of Peru?", "Lima"])?);
+    /// let package = Package::new_from_memory(vec![europe, capitals], vec![])?;
+    /// assert_eq!(package.find_duplicates().len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn find_duplicates(&self) -> Vec<Vec<&Note>> {
+        group_duplicates(self.decks.iter().flat_map(|deck| deck.notes()))
+    }
+
+    /// Returns a JSON report about the content of the package, e.g. for a catalog page
+    ///
+    /// For every deck it has the number of notes and cards, the models of the notes with their field
+    /// names and the sorted tags of the notes. The media files, including the used files of the media
+    /// pool, are summed up as their count and total size in bytes:
+    ///
+    /// ```json
+    /// {"decks":[{"id":1234,"name":"Capitals","notes":1,"cards":2,
+    ///   "models":[{"id":1485830179,"name":"Basic (and reversed card) (genanki)","fields":["Front","Back"]}],
+    ///   "tags":["geography"]}],
+    ///  "media":{"count":1,"total_bytes":3}}
+    /// ```
+    ///
+    /// Returns `Err` if a media file can't be read
+    pub fn stats_json(&self) -> Result<String, Error> {
+        let decks = self
+            .decks
+            .iter()
+            .map(|deck| {
+                let mut models: Vec<ModelStats> = Vec::new();
+                let mut tags: BTreeSet<&str> = BTreeSet::new();
+                for note in deck.notes() {
+                    if !models.iter().any(|model| model.id == note.model_id()) {
+                        models.push(ModelStats {
+                            id: note.model_id(),
+                            name: note.model().name(),
+                            fields: note.model().fields().into_iter().map(|field| field.name).collect(),
+                        });
+                    }
+                    tags.extend(note.get_tags().iter().map(String::as_str));
+                }
+                DeckStats {
+                    id: deck.id,
+                    name: &deck.name,
+                    notes: deck.notes().len(),
+                    cards: deck.notes().iter().map(|note| note.cards().len()).sum(),
+                    models,
+                    tags: tags.into_iter().collect(),
+                }
+            })
+            .collect();
+        let pooled_files = self.used_pooled_media();
+        let mut total_bytes = pooled_files.iter().map(|(_, bytes)| bytes.len() as u64).sum();
+        for media_file in &self.media_files {
+            total_bytes += media_file.read_bytes()?.len() as u64;
+        }
+        let media = MediaStats {
+            count: self.media_files.len() + pooled_files.len(),
+            total_bytes,
+        };
+        serde_json::to_string(&PackageStats { decks, media }).map_err(json_error)
+    }
+
+    /// Returns a hash of the content of the package that does not depend on when it is written, e.g. as a
+    /// cache key to skip rebuilding a deck whose inputs didn't change
+    ///
+    /// The hash covers the decks (id, name, description and option preset), the notes (GUID, model, fields,
+    /// tags and flags), their cards (scheduling and review history), the definitions of the models of the
+    /// notes, the filtered decks, the collection settings and `col` data, the config and deck config entries
+    /// and the names and contents of the media files, including the used files of the media pool. It is stable
+    /// across runs and versions of Rust.
+    ///
+    /// Not covered are review log entries of a [`Card::with_review_source`](crate::Card::with_review_source),
+    /// which can only be read once, the other table entries added with `add_deck_info_entry`,
+    /// `add_notetype_entry` and the like, and how the archive is written (e.g. `set_zip_mtime` or
+    /// `set_media_index_base`).
+    ///
+    /// Returns `Err` if a media file can't be read, a model is invalid or a filtered deck can't be written
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Capitals", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?);
+    /// let package = Package::new_from_memory(vec![deck.clone()], vec![])?;
+    /// let same_package = Package::new_from_memory(vec![deck], vec![])?;
+    /// assert_eq!(package.content_fingerprint()?, same_package.content_fingerprint()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn content_fingerprint(&self) -> Result<u64, Error> {
+        let mut hasher = Sha256::new();
+        // Every value is prefixed with its length so that the boundaries between values are part of the hash
+        let mut update = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        let mut models = BTreeMap::new();
+        for deck in &self.decks {
+            update(&deck.id.to_le_bytes());
+            update(deck.name.as_bytes());
+            update(deck.description.as_bytes());
+            update(&deck.config_id().to_le_bytes());
+            update(&(deck.notes().len() as u64).to_le_bytes());
+            for note in deck.notes() {
+                models.entry(note.model_id()).or_insert_with(|| note.model().clone());
+                update(note.get_guid().as_bytes());
+                update(&note.model_id().to_le_bytes());
+                update(&i32::from(note.flag()).to_le_bytes());
+                update(&(note.fields().len() as u64).to_le_bytes());
+                note.fields().iter().for_each(|field| update(field.as_bytes()));
+                update(note.get_tags().join(" ").as_bytes());
+                update(&(note.cards().len() as u64).to_le_bytes());
+                for card in note.cards() {
+                    for value in [
+                        Some(card.ord),
+                        Some(i64::from(card.suspend)),
+                        card.reps.map(i64::from),
+                        card.lapses.map(i64::from),
+                        card.ivl.map(i64::from),
+                        card.due,
+                        card.factor.map(i64::from),
+                        card.card_type.map(i64::from),
+                        card.queue.map(i64::from),
+                        card.left.map(i64::from),
+                        card.custom_card_id,
+                        card.deck_id,
+                        card.original_deck_id,
+                        card.original_due,
+                        Some(i64::from(card.flags)),
+                    ] {
+                        // An unset value is empty, which the length prefix tells apart from any set value
+                        update(&value.map_or_else(Vec::new, |value| value.to_le_bytes().to_vec()));
+                    }
+                    update(card.data.as_deref().unwrap_or_default().as_bytes());
+                    update(&(card.review_history.len() as u64).to_le_bytes());
+                    for entry in &card.review_history {
+                        update(&entry.id.to_le_bytes());
+                        for value in [entry.ease, entry.ivl, entry.last_ivl, entry.factor, entry.time, entry.review_type, entry.usn] {
+                            update(&value.to_le_bytes());
+                        }
+                    }
+                }
+            }
+        }
+        for model in models.values_mut() {
+            // The modification time and deck id of the model entry are fixed so that only the definition counts
+            update(model.to_json(0.0, 0)?.as_bytes());
+            update(&[u8::from(model.get_latex_svg())]);
+        }
+        let scheduler_version = self.scheduler_version.unwrap_or(SchedulerVersion::V1);
+        for filtered_deck in &self.filtered_decks {
+            update(filtered_deck.to_json(scheduler_version, 0.0)?.to_string().as_bytes());
+        }
+        for (key, val) in self.collection_conf_entries() {
+            update(key.as_bytes());
+            update(val.to_string().as_bytes());
+        }
+        for value in [self.col_crt, self.col_ver, self.col_scm, self.col_usn.map(i64::from), self.col_ls] {
+            update(&value.map_or_else(Vec::new, |value| value.to_le_bytes().to_vec()));
+        }
+        for value in [&self.col_conf, &self.col_models, &self.col_decks, &self.col_dconf] {
+            // An unset value is a single byte, which no JSON string is
+            update(value.as_deref().map_or(&[0xff][..], str::as_bytes));
+        }
+        for config in &self.configs {
+            update(config.key.as_bytes());
+            update(&config.val);
+        }
+        for deck_config in &self.deck_configs {
+            update(&deck_config.id.to_le_bytes());
+            update(deck_config.name.as_bytes());
+            update(&deck_config.config_blob);
+        }
+        for media_file in &self.media_files {
+            update(media_file.name().as_bytes());
+            update(&Sha256::digest(media_file.read_bytes()?));
+        }
+        for (name, bytes) in self.used_pooled_media() {
+            update(name.as_bytes());
+            update(&Sha256::digest(bytes));
+        }
+        let digest = hasher.finalize();
+        Ok(u64::from_le_bytes(digest[..8].try_into().expect("SHA-256 has 32 bytes")))
+    }
+
+    /// Returns a package with only the notes that were added or changed since `base`, e.g. the last released
+    /// version, to update collections that imported `base`, and graves for the notes of `base` that are gone
+    ///
+    /// Notes are matched by GUID, so Anki updates changed notes in place. A note changed if its fields, tags,
+    /// flag or model differ from the note with its GUID in `base`, or if the definition of its model changed.
+    /// Decks without such notes and media files none of them refers to are left out, all other settings are
+    /// copied from this package.
+    ///
+    /// Graves hold the ids of the deleted notes and their cards, so only notes of `base` with an id get one,
+    /// e.g. the notes of the released `.apkg` read with [`Package::read_from_reader`]. Anki's `.apkg` importer
+    /// ignores graves, they are only used when syncing, so importing the package does not delete these notes
+    /// from a collection.
+    ///
+    /// Returns `Err` if a model is invalid
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut released = Deck::new(1234, "Capitals", "");
+    /// released.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?.guid("fr").set_id(1));
+    /// released.add_note(Note::new(basic_model(), vec!["Capital of Peru?", "Lima"])?.guid("pe").set_id(2));
+    /// let base = Package::new_from_memory(vec![released], vec![])?;
+    ///
+    /// let mut current = Deck::new(1234, "Capitals", "");
+    /// current.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"])?.guid("fr"));
+    /// current.add_note(Note::new(basic_model(), vec!["Capital of Chile?", "Santiago"])?.guid("cl"));
+    /// let update = Package::new_from_memory(vec![current], vec![])?.diff_against(&base)?;
+    ///
+    /// assert_eq!(update.decks[0].notes().len(), 1);
+    /// assert_eq!(update.decks[0].notes()[0].fields()[1], "Santiago");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diff_against(&self, base: &Package) -> Result<Package, Error> {
+        fn model_definitions(package: &Package) -> Result<HashMap<i64, String>, Error> {
+            let mut definitions = HashMap::new();
+            for model in package.decks.iter().flat_map(|deck| deck.notes()).map(Note::model) {
+                if let std::collections::hash_map::Entry::Vacant(entry) = definitions.entry(model.id) {
+                    // The modification time and deck id are fixed so that only the definition counts
+                    entry.insert(model.clone().to_json(0.0, 0)?);
+                }
+            }
+            Ok(definitions)
+        }
+        let base_models = model_definitions(base)?;
+        let changed_models: HashSet<i64> = model_definitions(self)?
+            .into_iter()
+            .filter(|(id, definition)| base_models.get(id) != Some(definition))
+            .map(|(id, _)| id)
+            .collect();
+        let base_notes: HashMap<String, &Note> = base
+            .decks
+            .iter()
+            .flat_map(|deck| deck.notes())
+            .map(|note| (note.get_guid(), note))
+            .collect();
+        let is_changed = |note: &Note| match base_notes.get(&note.get_guid()) {
+            Some(base_note) => {
+                changed_models.contains(&note.model_id())
+                    || note.model_id() != base_note.model_id()
+                    || note.fields() != base_note.fields()
+                    || note.get_tags() != base_note.get_tags()
+                    || note.flag() != base_note.flag()
+            }
+            None => true,
+        };
+
+        let mut diff = self.try_clone()?;
+        diff.decks = self
+            .decks
+            .iter()
+            .map(|deck| deck.subset(is_changed))
+            .filter(|deck| !deck.notes().is_empty())
+            .collect();
+        diff.remove_unused_media();
+
+        let guids: HashSet<String> = self.decks.iter().flat_map(|deck| deck.notes()).map(Note::get_guid).collect();
+        let mut missing_ids = 0;
+        for note in base.decks.iter().flat_map(|deck| deck.notes()) {
+            if guids.contains(&note.get_guid()) {
+                continue;
+            }
+            let Some(note_id) = note.pinned_id() else {
+                missing_ids += 1;
+                continue;
+            };
+            let card_ids = note.cards().iter().filter_map(|card| card.custom_card_id);
+            diff.graves.extend(card_ids.map(|card_id| GraveEntry { oid: card_id, gtype: 0, usn: -1 }));
+            diff.graves.push(GraveEntry { oid: note_id, gtype: 1, usn: -1 });
+        }
+        if missing_ids > 0 {
+            log::warn!("{} notes were removed since the base package but have no id, no graves are written for them", missing_ids);
+        }
+        Ok(diff)
+    }
+
+    /// Returns the tags used by the notes in the package and the collapsed tags together with the parents of
+    /// hierarchical tags, sorted and without case-insensitive duplicates
+    ///
+    /// Like in Anki, a child tag takes on the case of a parent that was registered first.
+    fn note_tags(&self) -> Vec<String> {
+        let mut tags: BTreeMap<String, String> = BTreeMap::new();
+        let note_tags = self.decks.iter().flat_map(|deck| deck.notes()).flat_map(|note| note.get_tags());
+        for tag in note_tags.chain(&self.collapsed_tags) {
+            let mut registered = String::new();
+            for component in tag.split("::") {
+                let candidate = if registered.is_empty() {
+                    component.to_string()
+                } else {
+                    format!("{}::{}", registered, component)
+                };
+                registered = tags.entry(candidate.to_lowercase()).or_insert(candidate).clone();
+            }
+        }
+        tags.into_values().collect()
+    }
+
+    fn write_deck_content_data(
+        &mut self,
+        transaction: &Transaction,
+        timestamp_sec: f64,
+        skipped_notes: &[HashSet<usize>],
+    ) -> Result<(), Error> {
         let mut id_gen = ((timestamp_sec * 1000.0) as usize)..;
         log::info!("Writing content for {} decks", self.decks.len());
-        for deck in &mut self.decks {
+        for (deck, skipped) in self.decks.iter_mut().zip(skipped_notes) {
             log::info!("Writing content for deck {}: {} notes", deck.id, deck.notes().len());
-            deck.write_notes_and_cards_to_db(&transaction, timestamp_sec, &mut id_gen)?;
+            deck.write_notes_and_cards_to_db(&transaction, timestamp_sec, &mut id_gen, skipped)?;
         }
         Ok(())
     }
 }
 
+/// Runs SQLite's integrity and foreign key checks on `conn`, returning the problems they report as an error
+fn check_integrity(conn: &Connection) -> Result<(), Error> {
+    let mut problems = conn
+        .prepare("PRAGMA integrity_check")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>())
+        .map_err(database_error)?;
+    problems.retain(|problem| problem != "ok");
+    let foreign_key_problems = conn
+        .prepare("PRAGMA foreign_key_check")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(format!(
+                    "row {} of table {} refers to a missing row of table {}",
+                    row.get::<_, Option<i64>>(1)?.map_or("without rowid".to_string(), |id| id.to_string()),
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(2)?
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(database_error)?;
+    problems.extend(foreign_key_problems);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::IntegrityCheck(problems))
+    }
+}
+
+/// Starts a new zip entry called `name`, refusing names that were already used in `entry_names`
+///
+/// Some Anki versions mishandle archives that contain the same entry name twice.
+fn start_zip_entry<W: Write + Seek>(
+    outzip: &mut ZipWriter<W>,
+    entry_names: &mut HashSet<String>,
+    name: &str,
+    options: FileOptions,
+) -> Result<(), Error> {
+    if !entry_names.insert(name.to_string()) {
+        return Err(Error::DuplicateZipEntry(name.to_string()));
+    }
+    outzip
+        .start_file(name, options)
+        .map_err(zip_error)
+}
+
+/// Sets the given keys of the collection config JSON `conf`, leaving `conf` untouched if there are none
+fn with_conf_entries(conf: &str, entries: &[(&str, serde_json::Value)]) -> Result<String, Error> {
+    if entries.is_empty() {
+        return Ok(conf.to_string());
+    }
+    let mut conf: serde_json::Value = serde_json::from_str(conf).map_err(json_error)?;
+    if let Some(conf) = conf.as_object_mut() {
+        for (key, val) in entries {
+            conf.insert(key.to_string(), val.clone());
+        }
+    }
+    serde_json::to_string(&conf).map_err(json_error)
+}
+
+/// Whether `path` is a file that can be opened for reading
+#[cfg(feature = "fs")]
+fn is_readable_file(path: &Path) -> bool {
+    File::open(path)
+        .and_then(|file| file.metadata())
+        .is_ok_and(|metadata| metadata.is_file())
+}
+
+#[cfg(feature = "fs")]
 fn read_file_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
     let mut handle = File::open(path)?;
     let mut data = Vec::new();
     handle.read_to_end(&mut data)?;
     Ok(data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "zstd")]
+    use crate::proto::ProtoReader;
+    use crate::{basic_and_reversed_card_model, basic_model, cloze_model, guid_for, Card, CardQueueState, Field, Flag, FilteredDeckPreview, FilteredDeckTerm, Model, ModelType, Note, RevlogEntry, Template};
+    use std::io::{Cursor, Read};
+    #[cfg(feature = "fs")]
+    use tempfile::TempDir;
+    use tempfile::{NamedTempFile, TempPath};
+    use zip::ZipArchive;
+
+    fn write_to_archive(package: &mut Package) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut out = Cursor::new(Vec::new());
+        package.write(&mut out).unwrap();
+        ZipArchive::new(out).unwrap()
+    }
+
+    fn media_map(archive: &mut ZipArchive<Cursor<Vec<u8>>>) -> HashMap<String, String> {
+        let mut media_json = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media_json)
+            .unwrap();
+        serde_json::from_str(&media_json).unwrap()
+    }
+
+    fn open_collection(archive: &mut ZipArchive<Cursor<Vec<u8>>>) -> (TempPath, Connection) {
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let mut collection = Vec::new();
+        let name = if archive.by_name("collection.anki21").is_ok() {
+            "collection.anki21"
+        } else {
+            "collection.anki2"
+        };
+        archive.by_name(name).unwrap().read_to_end(&mut collection).unwrap();
+        std::fs::write(&db_file, collection).unwrap();
+        let conn = Connection::open(&db_file).unwrap();
+        (db_file, conn)
+    }
+
+    fn legacy_package(decks: Vec<Deck>) -> Package {
+        let mut package = Package::new_from_memory(decks, vec![]).unwrap();
+        package.set_col_data(None, Some(11), None, None, None, None, None, None, None);
+        package
+    }
+
+    fn cur_model_in_col_conf(package: &mut Package) -> serde_json::Value {
+        let (_db_file, conn) = open_collection(&mut write_to_archive(package));
+        let conf: String = conn
+            .query_row("SELECT conf FROM col", [], |row| row.get(0))
+            .unwrap();
+        serde_json::from_str::<serde_json::Value>(&conf).unwrap()["curModel"].clone()
+    }
+
+    #[test]
+    fn default_note_type_must_be_in_package() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["a", "b"]).unwrap());
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        assert!(matches!(
+            package.set_default_note_type(cloze_model().id),
+            Err(Error::ModelNotFound(_))
+        ));
+        assert!(package.set_default_note_type(basic_model().id).is_ok());
+    }
+
+    #[test]
+    fn remap_model_changes_notes_of_the_model() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["a", "b"]).unwrap());
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::a}}"]).unwrap());
+        deck.add_note(Note::new(basic_model(), vec!["c", "d"]).unwrap());
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.set_default_note_type(basic_model().id).unwrap();
+
+        assert_eq!(package.remap_model(basic_model().id, 42).unwrap(), 2);
+        let model_ids: Vec<i64> = package.decks[0].notes().iter().map(Note::model_id).collect();
+        assert_eq!(model_ids, vec![42, cloze_model().id, 42]);
+        assert_eq!(package.default_note_type, Some(42));
+        package.decks[0].validate().unwrap();
+        assert!(!package.decks[0].models().contains_key(&basic_model().id));
+
+        assert!(matches!(
+            package.remap_model(basic_model().id, 43),
+            Err(Error::ModelNotFound(_))
+        ));
+        assert!(matches!(
+            package.remap_model(42, cloze_model().id),
+            Err(Error::ModelIdInUse(_))
+        ));
+
+        package.add_notetype_entry(NotetypeEntry {
+            id: 7,
+            name: "Imported".to_string(),
+            mtime_secs: 0,
+            usn: 0,
+            config: vec![],
+        });
+        assert!(matches!(package.remap_model(42, 7), Err(Error::ModelIdInUse(7))));
+        assert_eq!(package.remap_model(42, 42).unwrap(), 2);
+    }
+
+    #[test]
+    fn resolve_media_refs_matches_messy_references() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(
+            Note::new(
+                basic_model(),
+                vec![
+                    r#"<img src="Photos\Paris%20At%20Night.JPG"><img src="https://example.com/a.png">"#,
+                    "[sound:SONG.MP3][sound:missing.mp3]",
+                ],
+            )
+            .unwrap(),
+        );
+        deck.add_note(Note::new(basic_model(), vec![r#"<img src="logo.svg">"#, "[sound:song.mp3]"]).unwrap());
+        let media = ["paris at night.jpg", "song.mp3", "logo.svg"]
+            .iter()
+            .map(|name| MediaFile::new_from_bytes(b"", name))
+            .collect();
+        let mut package = Package::new_from_memory(vec![deck], media).unwrap();
+
+        let report = package.resolve_media_refs();
+        assert_eq!(
+            report.rewritten,
+            vec![
+                ("Photos\\Paris%20At%20Night.JPG".to_string(), "paris at night.jpg".to_string()),
+                ("SONG.MP3".to_string(), "song.mp3".to_string()),
+            ]
+        );
+        assert_eq!(report.unresolved, vec!["missing.mp3".to_string()]);
+        let notes = package.decks[0].notes();
+        assert_eq!(
+            notes[0].fields(),
+            [
+                r#"<img src="paris at night.jpg"><img src="https://example.com/a.png">"#,
+                "[sound:song.mp3][sound:missing.mp3]"
+            ]
+        );
+        assert_eq!(notes[1].fields(), [r#"<img src="logo.svg">"#, "[sound:song.mp3]"]);
+    }
+
+    #[test]
+    fn review_data_needs_scheduler_version() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["a", "b"]).unwrap());
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        assert!(!package.needs_scheduler_version());
+
+        let reviewed = Card::new_with_review_data(0, false, 3, 0, 10, 100, 2500, 2, 2, 0);
+        package.decks[0].add_note(
+            Note::new(basic_model(), vec!["c", "d"])
+                .unwrap()
+                .with_cards(vec![reviewed]),
+        );
+        assert!(package.needs_scheduler_version());
+
+        package.set_col_data(None, None, None, None, None, Some(r#"{"schedVer": 2}"#.to_string()), None, None, None);
+        assert!(!package.needs_scheduler_version());
+        package.set_col_data(None, None, None, None, None, None, None, None, None);
+        package.set_scheduler_version(SchedulerVersion::V3);
+        assert!(!package.needs_scheduler_version());
+    }
+
+    #[test]
+    fn media_indices_follow_insertion_order() {
+        let media = (0..12)
+            .map(|i| MediaFile::new_from_bytes(&[i], &format!("{}.png", i)))
+            .collect();
+        let mut package = Package::new_from_memory(vec![], media).unwrap();
+        package.set_media_index_base(100);
+        let mut archive = write_to_archive(&mut package);
+        let media = media_map(&mut archive);
+        assert_eq!(media.len(), 12);
+        for i in 0..12u8 {
+            assert_eq!(media[&(100 + i as usize).to_string()], format!("{}.png", i));
+            let mut content = Vec::new();
+            archive
+                .by_name(&(100 + i as usize).to_string())
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, [i]);
+        }
+        let names: Vec<String> = (0..archive.len())
+            .map(|i| archive.by_index(i).unwrap().name().to_string())
+            .filter(|name| name.parse::<usize>().is_ok())
+            .collect();
+        assert_eq!(names, (100..112).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn duplicate_guids() {
+        let note = |back: &str| Note::new(basic_model(), vec!["front", back]).unwrap();
+        let mut first = Deck::new(1, "first", "");
+        first.add_notes(vec![note("back"), note("other back").guid("unique")]);
+        let mut second = Deck::new(2, "second", "");
+        second.add_notes(vec![note("back"), note("back").guid("unique"), note("third back")]);
+        let mut package = Package::new_from_memory(vec![first, second], vec![]).unwrap();
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::DuplicateNoteGuid(guid)) if guid == guid_for(&["front", "back"])
+        ));
+
+        package.set_drop_duplicate_guids(true);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let fields: Vec<String> = conn
+            .prepare("SELECT flds FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(fields, ["front\x1fback", "front\x1fother back", "front\x1fthird back"]);
+    }
+
+    #[test]
+    fn pinned_ids_must_be_unique() {
+        let pinned_card = |id| {
+            let mut card = Card::new(0, false);
+            card.custom_card_id = Some(id);
+            card
+        };
+        let note = |front: &str| Note::new(basic_model(), vec![front, "back"]).unwrap();
+        let mut first = Deck::new(1, "first", "");
+        first.add_note(note("a").set_id(10).with_cards(vec![pinned_card(100)]));
+        let mut second = Deck::new(2, "second", "");
+        second.add_note(note("b").set_id(11).with_cards(vec![pinned_card(100)]));
+        let mut package = Package::new_from_memory(vec![first.clone(), second], vec![]).unwrap();
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::DuplicateCardId(100))
+        ));
+
+        let mut second = Deck::new(2, "second", "");
+        second.add_note(note("b").set_id(10).with_cards(vec![pinned_card(101)]));
+        let mut package = Package::new_from_memory(vec![first.clone(), second], vec![]).unwrap();
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::DuplicateNoteId(10))
+        ));
+
+        let mut second = Deck::new(2, "second", "");
+        second.add_note(note("b").set_id(11).with_cards(vec![pinned_card(101)]));
+        let mut package = Package::new_from_memory(vec![first, second], vec![]).unwrap();
+        package.write(Cursor::new(Vec::new())).unwrap();
+    }
+
+    #[test]
+    fn integrity_check() {
+        let mut deck = Deck::new(1234, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["front", "back"]).unwrap());
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.set_integrity_check(true);
+        package.write(Cursor::new(Vec::new())).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             CREATE TABLE parent (id INTEGER PRIMARY KEY);
+             CREATE TABLE child (id INTEGER PRIMARY KEY, parent INTEGER REFERENCES parent(id));
+             INSERT INTO child VALUES (1, 42);",
+        )
+        .unwrap();
+        match check_integrity(&conn) {
+            Err(Error::IntegrityCheck(problems)) => {
+                assert_eq!(problems, ["row 1 of table child refers to a missing row of table parent"])
+            }
+            other => panic!("expected an integrity check error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn archives_are_reproducible() {
+        let write = |package: &mut Package| {
+            let mut apkg = Cursor::new(Vec::new());
+            package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+            apkg.into_inner()
+        };
+        let mut deck = Deck::new(1234, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["front", "back"]).unwrap());
+        deck.add_note(Note::new(cloze_model(), vec!["{{c2::a}} {{c5::b}} {{c1::c}} {{c4::d}} {{c6::e}} {{c3::f}}"]).unwrap());
+        let media = vec![MediaFile::new_from_bytes(b"png", "a.png")];
+        let mut package = Package::new_from_memory(vec![deck.clone()], media.clone()).unwrap();
+        let apkg = write(&mut package);
+        let mut other = Package::new_from_memory(vec![deck.clone()], media.clone()).unwrap();
+        assert_eq!(apkg, write(&mut other));
+
+        // Extra collection config keys are written in the same order every time
+        let with_extra_conf = || {
+            let mut package = Package::new_from_memory(vec![deck.clone()], media.clone()).unwrap();
+            package.set_collection_conf(CollectionConf {
+                extra: (0..20).map(|i| (format!("addon{}", i), serde_json::json!(i))).collect(),
+                ..Default::default()
+            });
+            package
+        };
+        assert_eq!(write(&mut with_extra_conf()), write(&mut with_extra_conf()));
+
+        let mut archive = ZipArchive::new(Cursor::new(apkg)).unwrap();
+        for i in 0..archive.len() {
+            // 2023-11-14 22:13:20 UTC
+            let mtime = archive.by_index(i).unwrap().last_modified();
+            assert_eq!(
+                (mtime.year(), mtime.month(), mtime.day(), mtime.hour(), mtime.minute(), mtime.second()),
+                (2023, 11, 14, 22, 13, 20)
+            );
+        }
+
+        package.set_zip_mtime(951_782_400);
+        let mut archive = ZipArchive::new(Cursor::new(write(&mut package))).unwrap();
+        let mtime = archive.by_name("media").unwrap().last_modified();
+        assert_eq!((mtime.year(), mtime.month(), mtime.day()), (2000, 2, 29));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn remove_unused_media() {
+        let model = Model::new(
+            1607392319,
+            "Styled Model",
+            vec![Field::new("Front"), Field::new("Back")],
+            vec![Template::new("Card 1")
+                .qfmt(r#"<img src="logo.png">{{Front}}"#)
+                .afmt("{{FrontSide}}<hr id=answer>{{Back}}")],
+        )
+        .css("@font-face { font-family: Serif; src: url('serif.ttf'); }");
+        let mut deck = Deck::new(1234, "deck", "");
+        deck.add_note(Note::new(model, vec!["<img src='my%20pic.png'>", "[sound:paris.mp3]"]).unwrap());
+        let mut package = Package::new_from_memory(
+            vec![deck],
+            vec![
+                MediaFile::new_from_bytes(b"pic", "my pic.png"),
+                MediaFile::new_from_bytes(b"unused", "berlin.jpg"),
+                MediaFile::new_from_bytes(b"logo", "logo.png"),
+                MediaFile::new_from_bytes(b"serif", "serif.ttf"),
+                MediaFile::new_from_bytes(b"asset", "_unreferenced.js"),
+            ],
+        )
+        .unwrap();
+        package.media_files.push(MediaFile::new_from_file("media/paris.mp3"));
+        package.media_files.push(MediaFile::new_from_file("media/rome.mp3"));
+        package.add_media_with_name("media/other.mp3", "madrid.mp3");
+
+        assert_eq!(
+            package.remove_unused_media(),
+            [PathBuf::from("berlin.jpg"), PathBuf::from("media/rome.mp3"), PathBuf::from("media/other.mp3")]
+        );
+        let kept: Vec<&str> = package.media_files.iter().map(MediaFile::name).collect();
+        assert_eq!(kept, ["my pic.png", "logo.png", "serif.ttf", "_unreferenced.js", "paris.mp3"]);
+        assert!(package.remove_unused_media().is_empty());
+    }
+
+    #[test]
+    fn media_pool_files_are_written_when_used() {
+        let mut pool = MediaPool::new();
+        for (name, bytes) in [
+            ("paris.jpg", &b"pooled paris"[..]),
+            ("berlin.jpg", b"berlin"),
+            ("_logo.png", b"logo"),
+            ("_serif.ttf", b"serif"),
+            ("my pic.png", b"pic"),
+        ] {
+            pool.add(MediaFile::new_from_bytes(bytes, name)).unwrap();
+        }
+        let pool = Arc::new(pool);
+        let model = Model::new(
+            1607392319,
+            "Pooled Model",
+            vec![Field::new("Front"), Field::new("Back")],
+            vec![Template::new("Card 1")
+                .qfmt(r#"<img src="_logo.png">{{Front}}"#)
+                .afmt("{{FrontSide}}<hr id=answer>{{Back}}")],
+        )
+        .css("@font-face { font-family: Serif; src: url('_serif.ttf'); }");
+        let mut deck = Deck::new(1234, "deck", "");
+        deck.add_note(Note::new(model, vec![r#"<img src="paris.jpg">"#, "<img src='my%20pic.png'>"]).unwrap());
+        let own = vec![MediaFile::new_from_bytes(b"own paris", "paris.jpg")];
+        let mut package = Package::new_from_memory(vec![deck], own).unwrap();
+        package.set_media_pool(Arc::clone(&pool));
+
+        let mut archive = write_to_archive(&mut package);
+        let media = media_map(&mut archive);
+        let mut written: Vec<(String, Vec<u8>)> = media
+            .iter()
+            .map(|(idx, name)| {
+                let mut bytes = Vec::new();
+                archive.by_name(idx).unwrap().read_to_end(&mut bytes).unwrap();
+                (name.clone(), bytes)
+            })
+            .collect();
+        written.sort();
+        assert_eq!(
+            written,
+            [
+                ("_logo.png".to_string(), b"logo".to_vec()),
+                ("_serif.ttf".to_string(), b"serif".to_vec()),
+                ("my pic.png".to_string(), b"pic".to_vec()),
+                ("paris.jpg".to_string(), b"own paris".to_vec()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn latest_zstd_format() {
+        let mut deck = Deck::new(1234, "Capitals", "");
+        deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "[sound:paris.mp3]"]).unwrap());
+        let mut package = legacy_package(vec![deck]);
+        package.add_media_bytes(b"ID3 paris", "paris.mp3");
+        package.set_media_index_base(5);
+        let mut out = Cursor::new(Vec::new());
+        package.write_with_format(&mut out, PackageFormat::LatestZstd).unwrap();
+        let mut archive = ZipArchive::new(out).unwrap();
+        let mut read_entry = |name: &str| {
+            let mut bytes = Vec::new();
+            archive.by_name(name).unwrap().read_to_end(&mut bytes).unwrap();
+            bytes
+        };
+
+        assert_eq!(read_entry("meta"), [0x08, 0x03]);
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        std::fs::write(&db_file, zstd::decode_all(&read_entry("collection.anki21b")[..]).unwrap()).unwrap();
+        let conn = Connection::open(&db_file).unwrap();
+        let ver: i64 = conn.query_row("SELECT ver FROM col", [], |row| row.get(0)).unwrap();
+        assert_eq!(ver, 18);
+        let flds: String = conn.query_row("SELECT flds FROM notes", [], |row| row.get(0)).unwrap();
+        assert_eq!(flds, "Capital of France?\x1f[sound:paris.mp3]");
+
+        let entries = zstd::decode_all(&read_entry("media")[..]).unwrap();
+        let entries = ProtoReader::new(&entries).unwrap().messages(1);
+        assert_eq!(entries.len(), 1);
+        let entry = ProtoReader::new(entries[0]).unwrap();
+        assert_eq!((entry.string(1).as_str(), entry.int(2), entry.int(255)), ("paris.mp3", 9, 5));
+        assert_eq!(entry.message(3).unwrap(), &Sha1::digest(b"ID3 paris")[..]);
+        assert_eq!(zstd::decode_all(&read_entry("5")[..]).unwrap(), b"ID3 paris");
+
+        let mut out = Cursor::new(Vec::new());
+        package.write_with_format(&mut out, PackageFormat::Legacy).unwrap();
+        let mut archive = ZipArchive::new(out).unwrap();
+        assert!(archive.by_name("collection.anki21b").is_err());
+        assert_eq!(media_map(&mut archive)["5"], "paris.mp3");
+
+        let mut write = || {
+            let mut out = Cursor::new(Vec::new());
+            package.write_with_format_timestamp(&mut out, 1_700_000_000.0, PackageFormat::LatestZstd).unwrap();
+            out.into_inner()
+        };
+        assert_eq!(write(), write());
+    }
+
+    #[test]
+    fn target_anki_version() {
+        let mut deck = Deck::new(1234, "Geography::Capitals", "European capitals");
+        deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["France", "Paris"]).unwrap());
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.set_col_data(None, Some(16), None, None, None, None, None, None, None);
+        package.set_scheduler_version(SchedulerVersion::V3);
+        package.add_filtered_deck(FilteredDeck::new(5678, "Cram").term(FilteredDeckTerm::new("is:due", 100, 6)));
+
+        package.target_anki_version(AnkiVersion::Legacy);
+        let mut archive = write_to_archive(&mut package);
+        assert!(archive.by_name("meta").is_err());
+        let (_db_file, conn) = open_collection(&mut archive);
+        let ver: i64 = conn.query_row("SELECT ver FROM col", [], |row| row.get(0)).unwrap();
+        assert_eq!(ver, 11);
+
+        package.target_anki_version(AnkiVersion::Modern);
+        let mut archive = write_to_archive(&mut package);
+        assert!(archive.by_name("collection.anki2").is_err());
+        let mut meta = Vec::new();
+        archive.by_name("meta").unwrap().read_to_end(&mut meta).unwrap();
+        assert_eq!(meta, [0x08, 0x02]);
+        let (_db_file, conn) = open_collection(&mut archive);
+        let ver: i64 = conn.query_row("SELECT ver FROM col", [], |row| row.get(0)).unwrap();
+        assert_eq!(ver, 18);
+        let (ntid, name, config): (i64, String, Vec<u8>) = conn
+            .query_row("SELECT id, name, config FROM notetypes", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        let model = basic_and_reversed_card_model();
+        assert_eq!((ntid, name.as_str()), (model.id, model.name()));
+        assert!(config.windows(model.get_css().len()).any(|css| css == model.get_css().as_bytes()));
+        let field_names: Vec<String> = conn
+            .prepare("SELECT name FROM fields WHERE ntid = ? ORDER BY ord")
+            .unwrap()
+            .query_map([ntid], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(field_names, ["Front", "Back"]);
+        let template_count: i64 = conn
+            .query_row("SELECT count() FROM templates WHERE ntid = ?", [ntid], |row| row.get(0))
+            .unwrap();
+        assert_eq!(template_count, 2);
+        let decks: Vec<(i64, String, u8)> = conn
+            .prepare("SELECT id, name, kind FROM decks ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, Vec<u8>>(2)?[0])))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            decks,
+            [
+                (1, "Default".to_string(), 0x0a),
+                (1234, "Geography\x1fCapitals".to_string(), 0x0a),
+                (5678, "Cram".to_string(), 0x12),
+                (parent_deck_id("Geography"), "Geography".to_string(), 0x0a),
+            ]
+        );
+    }
+
+    #[test]
+    fn note_types_and_decks_are_written_to_tables_from_version_16() {
+        let mut deck = Deck::new(1234, "Capitals", "");
+        deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["France", "Paris"]).unwrap());
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::Paris}} is in France"]).unwrap());
+        let count = |conn: &Connection, table: &str| -> i64 {
+            conn.query_row(&format!("SELECT count() FROM {}", table), [], |row| row.get(0)).unwrap()
+        };
+
+        let mut package = Package::new_from_memory(vec![deck.clone()], vec![]).unwrap();
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let (ver, models, decks): (i64, String, String) = conn
+            .query_row("SELECT ver, models, decks FROM col", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        assert_eq!((ver, models.as_str(), decks.as_str()), (18, "{}", "{}"));
+        let ntids: Vec<i64> = conn
+            .prepare("SELECT id FROM notetypes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut expected = vec![basic_and_reversed_card_model().id, cloze_model().id];
+        expected.sort();
+        assert_eq!(ntids, expected);
+        assert_eq!(count(&conn, "fields"), 3);
+        assert_eq!(count(&conn, "templates"), 3);
+        assert_eq!(count(&conn, "decks"), 2);
+        let orphans: i64 = conn
+            .query_row("SELECT count() FROM notes WHERE mid NOT IN (SELECT id FROM notetypes)", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(orphans, 0);
+
+        // Before version 16 note types are stored in `col.models`
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.set_col_data(None, Some(15), None, None, None, None, None, None, None);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        assert_eq!(count(&conn, "notetypes"), 0);
+    }
+
+    #[test]
+    fn plain_text_fields() {
+        let model = Model::new(
+            1234,
+            "Code",
+            vec![Field::new("Question"), Field::new("Code").plain_text(true)],
+            vec![Template::new("Card 1").qfmt("{{Question}}").afmt("{{Code}}")],
+        );
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(model, vec!["Hello world in Rust?", "fn main() {}"]).unwrap());
+
+        let mut package = legacy_package(vec![deck.clone()]);
+        let mut apkg = Cursor::new(Vec::new());
+        package.write(&mut apkg).unwrap();
+        let read = Package::read_from_reader(Cursor::new(apkg.into_inner())).unwrap();
+        let fields = read.decks[0].notes()[0].model().fields();
+        assert_eq!((fields[0].plain_text, fields[1].plain_text), (false, true));
+
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.target_anki_version(AnkiVersion::Modern);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let configs: Vec<Vec<u8>> = conn
+            .prepare("SELECT config FROM fields ORDER BY ord")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(!configs[0].ends_with(&[0x30, 0x01]) && configs[1].ends_with(&[0x30, 0x01]));
+    }
+
+    #[test]
+    fn collection_id() {
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        let col_id = |package: &mut Package| -> i64 {
+            let (_db_file, conn) = open_collection(&mut write_to_archive(package));
+            conn.query_row("SELECT id FROM col", [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(col_id(&mut package), 1);
+
+        assert!(matches!(package.set_collection_id(0), Err(Error::InvalidCollectionId(0))));
+        package.set_collection_id(42).unwrap();
+        assert_eq!(col_id(&mut package), 42);
+    }
+
+    #[test]
+    fn cur_model_defaults_to_first_model() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["a", "b"]).unwrap());
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::a}}"]).unwrap());
+        let mut package = legacy_package(vec![deck]);
+        assert_eq!(cur_model_in_col_conf(&mut package), basic_model().id);
+    }
+
+    #[test]
+    fn decks_use_deck_configs_of_the_package() {
+        let mut deck = Deck::new(1234, "Verbs", "");
+        deck.set_config_id(42);
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.set_col_data(None, Some(15), None, None, None, None, None, None, None);
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::UnknownDeckConfig(1234, 42))
+        ));
+
+        package.add_deck_config_entry(DeckConfigEntry {
+            id: 42,
+            name: "Short steps".to_string(),
+            mtime_secs: 0,
+            usn: -1,
+            config_blob: br#"{"id": 42, "name": "Short steps", "new": {"delays": [1, 5]}}"#.to_vec(),
+        });
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let (decks, dconf): (String, String) =
+            conn.query_row("SELECT decks, dconf FROM col", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        let decks: serde_json::Value = serde_json::from_str(&decks).unwrap();
+        let dconf: serde_json::Value = serde_json::from_str(&dconf).unwrap();
+        assert_eq!((decks["1234"]["conf"].as_i64(), decks["1"]["conf"].as_i64()), (Some(42), Some(1)));
+        assert_eq!(dconf["42"]["new"]["delays"], serde_json::json!([1, 5]));
+    }
+
+    #[test]
+    fn fixed_guids_are_written() {
+        let guids = |note: Note, timestamp: f64| -> Vec<String> {
+            let mut deck = Deck::new(1234, "Capitals", "");
+            deck.add_note(note);
+            let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+            let mut apkg = Cursor::new(Vec::new());
+            package.write_timestamp(&mut apkg, timestamp).unwrap();
+            let (_db_file, conn) = open_collection(&mut ZipArchive::new(apkg).unwrap());
+            let mut stmt = conn.prepare("SELECT guid FROM notes").unwrap();
+            stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+        };
+        let note = Note::new(basic_model(), vec!["Capital of France?", "Paris"]).unwrap().guid("capital-fr");
+        assert_eq!(guids(note.clone(), 1_700_000_000.0), ["capital-fr"]);
+        let updated = Note::new(basic_model(), vec!["Capital of France?", "Paris!"]).unwrap().guid("capital-fr");
+        assert_eq!(guids(updated.clone(), 1_800_000_000.0), ["capital-fr"]);
+
+        let mut deck = Deck::new(1234, "Capitals", "");
+        deck.add_notes([note, updated]);
+        assert!(matches!(
+            deck.into_package().write(Cursor::new(Vec::new())),
+            Err(Error::DuplicateNoteGuid(guid)) if guid == "capital-fr"
+        ));
+    }
+
+    #[test]
+    fn cloze_notes_get_a_card_per_deletion_number() {
+        let mut deck = Deck::new(1234, "Cloze", "");
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::Paris}} is the capital of {{c1::France}}"]).unwrap());
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::Lima}}, {{c3::Quito}} and {{c3::Bogotá}}"]).unwrap());
+        let mut package = legacy_package(vec![deck]);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+
+        let models: String = conn.query_row("SELECT models FROM col", [], |row| row.get(0)).unwrap();
+        let models: serde_json::Value = serde_json::from_str(&models).unwrap();
+        assert_eq!(models[cloze_model().id.to_string()]["type"], 1);
+        let ords: Vec<Vec<i64>> = conn
+            .prepare("SELECT group_concat(ord) FROM (SELECT nid, ord FROM cards ORDER BY nid, ord) GROUP BY nid ORDER BY nid")
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .map(|ords| ords.unwrap().split(',').map(|ord| ord.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(ords, [vec![0], vec![0, 2]]);
+    }
+
+    #[test]
+    fn parent_decks_are_added() {
+        let deck_names = |package: &mut Package| -> BTreeMap<String, i64> {
+            let (_db_file, conn) = open_collection(&mut write_to_archive(package));
+            let decks: String = conn.query_row("SELECT decks FROM col", [], |row| row.get(0)).unwrap();
+            let decks: serde_json::Value = serde_json::from_str(&decks).unwrap();
+            decks.as_object()
+                .unwrap()
+                .values()
+                .map(|deck| (deck["name"].as_str().unwrap().to_string(), deck["id"].as_i64().unwrap()))
+                .collect()
+        };
+        let decks = vec![
+            Deck::new(1234, "Spanish::Verbs::Irregular", ""),
+            Deck::new(1235, "Spanish :: Nouns", ""),
+        ];
+        let names = deck_names(&mut legacy_package(decks.clone()));
+        let spanish = parent_deck_id("Spanish");
+        let verbs = parent_deck_id("Spanish::Verbs");
+        assert_eq!(
+            names.into_iter().collect::<Vec<_>>(),
+            [
+                ("Default".to_string(), 1),
+                ("Spanish".to_string(), spanish),
+                ("Spanish :: Nouns".to_string(), 1235),
+                ("Spanish::Verbs".to_string(), verbs),
+                ("Spanish::Verbs::Irregular".to_string(), 1234),
+            ]
+        );
+        assert_ne!(spanish, verbs);
+
+        let mut with_parent = decks.clone();
+        with_parent.push(Deck::new(99, "spanish", ""));
+        let names = deck_names(&mut legacy_package(with_parent));
+        assert_eq!(names.len(), 5);
+        assert_eq!(names["spanish"], 99);
+        assert!(!names.contains_key("Spanish"));
+
+        let names = deck_names(&mut legacy_package(vec![Deck::new(1234, "Capitals", "")]));
+        assert_eq!(names.into_iter().collect::<Vec<_>>(), [("Capitals".to_string(), 1234), ("Default".to_string(), 1)]);
+    }
+
+    #[test]
+    fn cur_deck_is_a_deck_of_the_package() {
+        let cur_deck = |package: &mut Package| {
+            let (_db_file, conn) = open_collection(&mut write_to_archive(package));
+            let conf: String = conn.query_row("SELECT conf FROM col", [], |row| row.get(0)).unwrap();
+            let conf: serde_json::Value = serde_json::from_str(&conf).unwrap();
+            (conf["curDeck"].clone(), conf["activeDecks"].clone())
+        };
+        let decks = vec![Deck::new(1234, "first", ""), Deck::new(1235, "second", "")];
+        let mut package = legacy_package(decks.clone());
+        assert_eq!(cur_deck(&mut package), (1234.into(), serde_json::json!([1234])));
+        package.set_col_data(None, Some(11), None, None, None, Some(r#"{"curDeck": 1235}"#.to_string()), None, None, None);
+        assert_eq!(cur_deck(&mut package).0, 1235);
+        let mut package = legacy_package(vec![Deck::new(1, "Default", ""), Deck::new(1234, "first", "")]);
+        assert_eq!(cur_deck(&mut package).0, 1);
+        let mut package = legacy_package(vec![]);
+        assert_eq!(cur_deck(&mut package).0, 1);
+
+        let mut package = Package::new_from_memory(decks, vec![]).unwrap();
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let cur_deck: Vec<u8> = conn
+            .query_row("SELECT val FROM config WHERE key = 'curDeck'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(cur_deck, b"1234");
+    }
+
+    #[test]
+    fn model_default_deck_is_written() {
+        let model = basic_model().default_deck(1235);
+        let mut first = Deck::new(1234, "first", "");
+        first.add_note(Note::new(model.clone(), vec!["a", "b"]).unwrap());
+        first.add_note(Note::new(cloze_model(), vec!["{{c1::a}}"]).unwrap());
+        let mut second = Deck::new(1235, "second", "");
+        second.add_note(Note::new(model.clone(), vec!["c", "d"]).unwrap());
+
+        let mut package = legacy_package(vec![first.clone(), second.clone()]);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let models: String = conn.query_row("SELECT models FROM col", [], |row| row.get(0)).unwrap();
+        let models: serde_json::Value = serde_json::from_str(&models).unwrap();
+        assert_eq!(models[model.id.to_string()]["did"], 1235);
+        assert_eq!(models[cloze_model().id.to_string()]["did"], 1234);
+
+        let mut package = Package::new_from_memory(vec![first.clone(), second], vec![]).unwrap();
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let last_deck: Vec<u8> = conn
+            .query_row(
+                "SELECT val FROM config WHERE key = ?",
+                [format!("_nt_{}_lastDeck", model.id)],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(last_deck, b"1235");
+        let entries: i64 = conn
+            .query_row("SELECT count(*) FROM config WHERE key LIKE '\\_nt\\_%' ESCAPE '\\'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(entries, 1);
+
+        let mut package = legacy_package(vec![first]);
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::UnknownDefaultDeck(id, 1235)) if id == model.id
+        ));
+    }
+
+    #[test]
+    fn default_note_type_is_written() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["a", "b"]).unwrap());
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::a}}"]).unwrap());
+
+        let mut package = legacy_package(vec![deck.clone()]);
+        package.set_default_note_type(cloze_model().id).unwrap();
+        assert_eq!(cur_model_in_col_conf(&mut package), cloze_model().id);
+
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.set_default_note_type(cloze_model().id).unwrap();
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let cur_model: Vec<u8> = conn
+            .query_row("SELECT val FROM config WHERE key = 'curModel'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(cur_model, cloze_model().id.to_string().into_bytes());
+    }
+
+    #[test]
+    fn collection_conf_is_written() {
+        let collection_conf = CollectionConf {
+            add_to_current_deck: false,
+            bury_new_siblings: false,
+            time_limit_secs: 1800,
+            show_estimated_times: false,
+            extra: BTreeMap::from([
+                ("addonSetting".to_string(), serde_json::json!({"enabled": true})),
+                ("newBury".to_string(), serde_json::json!(true)),
+            ]),
+            ..Default::default()
+        };
+
+        let mut package = legacy_package(vec![]);
+        package.set_collection_conf(collection_conf.clone());
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let conf: String = conn
+            .query_row("SELECT conf FROM col", [], |row| row.get(0))
+            .unwrap();
+        let conf: serde_json::Value = serde_json::from_str(&conf).unwrap();
+        assert_eq!(conf["addToCur"], false);
+        assert_eq!(conf["newBury"], false);
+        assert_eq!(conf["revBury"], true);
+        assert_eq!(conf["timeLim"], 1800);
+        assert_eq!(conf["estTimes"], false);
+        assert_eq!(conf["dueCounts"], true);
+        assert_eq!(conf["collapseTime"], 1200);
+        assert_eq!(conf["addonSetting"], serde_json::json!({"enabled": true}));
+
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        package.set_collection_conf(collection_conf);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let config_val = |key: &str| -> Vec<u8> {
+            conn.query_row("SELECT val FROM config WHERE key = ?", [key], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(config_val("newBury"), b"false");
+        assert_eq!(config_val("addonSetting"), br#"{"enabled":true}"#);
+    }
+
+    #[test]
+    fn duplicates_share_the_written_csum() {
+        let mut europe = Deck::new(1234, "Europe", "");
+        europe.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"]).unwrap());
+        let mut capitals = Deck::new(1235, "Capitals", "");
+        capitals.add_note(Note::new(basic_model(), vec!["<b>Capital of France?</b>", "Paris"]).unwrap());
+        capitals.add_note(Note::new(basic_model(), vec!["Capital of Peru?", "Lima"]).unwrap());
+        let mut package = Package::new_from_memory(vec![europe, capitals], vec![]).unwrap();
+
+        let duplicates: Vec<Vec<&str>> = package
+            .find_duplicates()
+            .iter()
+            .map(|group| group.iter().map(|note| note.fields()[1].as_str()).collect())
+            .collect();
+        assert_eq!(duplicates, [vec!["Paris", "Paris"]]);
+
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let csums: Vec<i64> = conn
+            .prepare("SELECT csum FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(csums[0], csums[1]);
+        assert_ne!(csums[0], csums[2]);
+        assert_eq!(csums[0], i64::from(package.find_duplicates()[0][0].checksum()));
+    }
+
+    #[test]
+    fn collection_conf_json_is_merged() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"]).unwrap());
+        let mut package = legacy_package(vec![deck.clone()]);
+        package.set_collection_conf(CollectionConf {
+            time_limit_secs: 1800,
+            ..Default::default()
+        });
+        package
+            .set_collection_conf_json(serde_json::json!({"sortType": "noteCrt", "timeLim": 600, "curDeck": 1}))
+            .unwrap();
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let conf: String = conn
+            .query_row("SELECT conf FROM col", [], |row| row.get(0))
+            .unwrap();
+        let conf: serde_json::Value = serde_json::from_str(&conf).unwrap();
+        assert_eq!(conf["sortType"], "noteCrt");
+        assert_eq!(conf["timeLim"], 1800);
+        assert_eq!(conf["curDeck"], 1234);
+        assert_eq!(conf["curModel"], basic_model().id);
+        assert_eq!(conf["newSpread"], 0);
+
+        let mut package = legacy_package(vec![deck]);
+        package.set_collection_conf_json(serde_json::json!({"sortType": "noteCrt"})).unwrap();
+        package.set_collection_conf_json(serde_json::json!({"sortBackwards": true})).unwrap();
+        let extra = &package.collection_conf.as_ref().unwrap().extra;
+        assert_eq!((&extra["sortType"], &extra["sortBackwards"]), (&serde_json::json!("noteCrt"), &serde_json::json!(true)));
+        assert!(matches!(
+            package.set_collection_conf_json(serde_json::json!("noteCrt")),
+            Err(Error::InvalidCollectionConf(_))
+        ));
+    }
+
+    #[test]
+    fn filtered_decks_follow_scheduler_version() {
+        let cram = FilteredDeck::new(1234, "Cram")
+            .term(FilteredDeckTerm::new("is:due", 100, 6))
+            .term(FilteredDeckTerm::new("is:new", 20, 5))
+            .reschedule(false)
+            .preview(FilteredDeckPreview::DelaySecs { again: 30, hard: 300, good: 0 });
+
+        let mut package = legacy_package(vec![]);
+        package.set_scheduler_version(SchedulerVersion::V3);
+        package.add_filtered_deck(cram.clone());
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let (conf, decks): (String, String) = conn
+            .query_row("SELECT conf, decks FROM col", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        let conf: serde_json::Value = serde_json::from_str(&conf).unwrap();
+        assert_eq!((conf["schedVer"].clone(), conf["sched2021"].clone()), (2.into(), true.into()));
+        let decks: serde_json::Value = serde_json::from_str(&decks).unwrap();
+        let deck = &decks["1234"];
+        assert_eq!(deck["dyn"], 1);
+        assert_eq!(deck["terms"], serde_json::json!([["is:due", 100, 6], ["is:new", 20, 5]]));
+        assert_eq!(deck["previewAgainSecs"], 30);
+        assert!(deck.get("previewDelay").is_none());
+
+        let mut package = legacy_package(vec![]);
+        package.set_scheduler_version(SchedulerVersion::V2);
+        package.add_filtered_deck(cram.clone());
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::FilteredDeckScheduler(_, SchedulerVersion::V2, _))
+        ));
+
+        // Without a scheduler version the collection uses v1, which supports a single search only
+        let mut package = legacy_package(vec![]);
+        package.add_filtered_deck(cram.preview(FilteredDeckPreview::Default));
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::FilteredDeckScheduler(_, SchedulerVersion::V1, _))
+        ));
+    }
+
+    #[test]
+    fn hierarchical_tags_are_registered() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(
+            Note::new(basic_model(), vec!["a", "b"])
+                .unwrap()
+                .tags(["lang::de::verbs", "misc"]),
+        );
+        deck.add_note(Note::new(basic_model(), vec!["c", "d"]).unwrap().tags(["Lang::en"]));
+
+        // `Lang::en` takes on the case of the `lang` parent registered before it
+        let expected = ["lang", "lang::de", "lang::de::verbs", "lang::en", "misc"];
+        let mut package = Package::new_from_memory(vec![deck.clone()], vec![]).unwrap();
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let mut stmt = conn.prepare("SELECT tag FROM tags ORDER BY tag").unwrap();
+        let tags: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|tag| tag.unwrap())
+            .collect();
+        assert_eq!(tags, expected);
+
+        let mut package = legacy_package(vec![deck]);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let tags: String = conn
+            .query_row("SELECT tags FROM col", [], |row| row.get(0))
+            .unwrap();
+        let tags: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&tags).unwrap();
+        assert_eq!(tags.keys().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn stats_json() {
+        let mut deck = Deck::new(1234, "Capitals", "");
+        deck.add_note(
+            Note::new(basic_and_reversed_card_model(), vec!["France", r#"Paris <img src="paris.jpg">"#])
+                .unwrap()
+                .tags(["geography", "europe"]),
+        );
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::Lima}} is in {{c2::Peru}}"]).unwrap().tags(["geography"]));
+        let mut package =
+            Package::new_from_memory(vec![deck, Deck::new(1235, "Empty", "")], vec![MediaFile::new_from_bytes(b"jpeg", "paris.jpg")])
+                .unwrap();
+        let mut pool = MediaPool::new();
+        pool.add(MediaFile::new_from_bytes(b"unused", "unused.png")).unwrap();
+        package.set_media_pool(Arc::new(pool));
+
+        let stats: serde_json::Value = serde_json::from_str(&package.stats_json().unwrap()).unwrap();
+        let reversed = basic_and_reversed_card_model();
+        let cloze = cloze_model();
+        assert_eq!(
+            stats,
+            serde_json::json!({
+                "decks": [
+                    {
+                        "id": 1234,
+                        "name": "Capitals",
+                        "notes": 2,
+                        "cards": 4,
+                        "models": [
+                            {"id": reversed.id, "name": reversed.name(), "fields": ["Front", "Back"]},
+                            {"id": cloze.id, "name": cloze.name(), "fields": ["Text"]},
+                        ],
+                        "tags": ["europe", "geography"],
+                    },
+                    {"id": 1235, "name": "Empty", "notes": 0, "cards": 0, "models": [], "tags": []},
+                ],
+                "media": {"count": 1, "total_bytes": 4},
+            })
+        );
+    }
+
+    #[test]
+    fn content_fingerprint() {
+        let package = |back: &str, media: &[u8]| {
+            let mut deck = Deck::new(1234, "deck", "");
+            deck.add_note(Note::new(basic_model(), vec!["front", back]).unwrap().tags(["tag"]));
+            Package::new_from_memory(vec![deck], vec![MediaFile::new_from_bytes(media, "a.png")]).unwrap()
+        };
+        let mut written = package("back", b"png");
+        let fingerprint = written.content_fingerprint().unwrap();
+        written.write_timestamp(Cursor::new(Vec::new()), 1_700_000_000.0).unwrap();
+        assert_eq!(written.content_fingerprint().unwrap(), fingerprint);
+        assert_eq!(package("back", b"png").content_fingerprint().unwrap(), fingerprint);
+        assert_ne!(package("other back", b"png").content_fingerprint().unwrap(), fingerprint);
+        assert_ne!(package("back", b"other png").content_fingerprint().unwrap(), fingerprint);
+
+        let mut scheduled = Deck::new(1234, "deck", "");
+        let card = Card::new_with_review_data(0, false, 3, 0, 10, 100, 2500, 2, 2, 0);
+        scheduled.add_note(Note::new(basic_model(), vec!["front", "back"]).unwrap().tags(["tag"]).with_cards(vec![card]));
+        let scheduled = Package::new_from_memory(vec![scheduled], vec![MediaFile::new_from_bytes(b"png", "a.png")]).unwrap();
+        assert_ne!(scheduled.content_fingerprint().unwrap(), fingerprint);
+
+        let mut with_filtered_deck = package("back", b"png");
+        with_filtered_deck.add_filtered_deck(FilteredDeck::new(5678, "Cram").term(FilteredDeckTerm::new("is:due", 100, 6)));
+        assert_ne!(with_filtered_deck.content_fingerprint().unwrap(), fingerprint);
+
+        let mut with_conf = package("back", b"png");
+        with_conf.set_collection_conf(CollectionConf { time_limit_secs: 600, ..Default::default() });
+        assert_ne!(with_conf.content_fingerprint().unwrap(), fingerprint);
+
+        let mut with_col_data = package("back", b"png");
+        with_col_data.set_col_data(Some(1_700_000_000), None, None, None, None, None, None, None, None);
+        assert_ne!(with_col_data.content_fingerprint().unwrap(), fingerprint);
+
+        let mut with_deck_config = package("back", b"png");
+        with_deck_config.add_deck_config_entry(DeckConfigEntry {
+            id: 1,
+            name: "Default".to_string(),
+            mtime_secs: 0,
+            usn: 0,
+            config_blob: vec![1],
+        });
+        assert_ne!(with_deck_config.content_fingerprint().unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn collapsed_tags() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["a", "b"]).unwrap().tags(["lang::de::verbs", "misc"]));
+        let mut package = Package::new_from_memory(vec![deck], vec![]).unwrap();
+        package.set_collapsed_tags(vec!["Lang::DE", "unused::tag"]);
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        let mut stmt = conn.prepare("SELECT tag, collapsed FROM tags ORDER BY tag").unwrap();
+        let tags: Vec<(String, bool)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|tag| tag.unwrap())
+            .collect();
+        let expected = [
+            ("lang", false),
+            ("lang::de", true),
+            ("lang::de::verbs", false),
+            ("misc", false),
+            ("unused", false),
+            ("unused::tag", true),
+        ];
+        assert_eq!(tags, expected.map(|(tag, collapsed)| (tag.to_string(), collapsed)));
+    }
+
+    #[test]
+    fn packages_without_media_are_written_like_with_empty_media() {
+        let mut deck = Deck::new(1234, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["front", "back"]).unwrap());
+        let mut without_media = deck.to_package();
+        let mut general_path = deck.into_package();
+        general_path.set_media_pool(Arc::new(MediaPool::new()));
+        for omit_empty_media_entry in [false, true] {
+            let mut written = Vec::new();
+            for package in [&mut without_media, &mut general_path] {
+                package.set_omit_empty_media_entry(omit_empty_media_entry);
+                let mut apkg = Cursor::new(Vec::new());
+                package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+                written.push(apkg.into_inner());
+            }
+            assert!(written[0] == written[1]);
+        }
+    }
+
+    #[test]
+    fn empty_media_entry() {
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        let mut archive = write_to_archive(&mut package);
+        assert_eq!(archive.len(), 2);
+        assert!(media_map(&mut archive).is_empty());
+
+        package.set_omit_empty_media_entry(true);
+        let archive = write_to_archive(&mut package);
+        assert_eq!(archive.file_names().collect::<Vec<_>>(), ["collection.anki2"]);
+
+        // the option only applies to packages without media
+        package.media_files.push(MediaFile::new_from_bytes(b"x", "x.txt"));
+        assert_eq!(media_map(&mut write_to_archive(&mut package)).len(), 1);
+    }
+
+    #[test]
+    fn try_clone_is_independent() {
+        let mut deck = Deck::new(1, "deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["a", "b"]).unwrap());
+        let mut base = Package::new_from_memory(
+            vec![deck],
+            vec![MediaFile::new_from_bytes(b"x", "x.txt")],
+        )
+        .unwrap();
+        base.set_default_note_type(basic_model().id).unwrap();
+
+        let mut variant = base.try_clone().unwrap();
+        variant.decks[0].name = "variant".to_string();
+        variant.media_files.push(MediaFile::new_from_bytes(b"y", "y.txt"));
+
+        assert_eq!(base.decks[0].name, "deck");
+        assert_eq!(media_map(&mut write_to_archive(&mut base)).len(), 1);
+        assert_eq!(media_map(&mut write_to_archive(&mut variant)).len(), 2);
+        assert_eq!(variant.default_note_type, Some(basic_model().id));
+    }
+
+    #[test]
+    fn duplicate_zip_entry_is_an_error() {
+        let mut outzip = ZipWriter::new(Cursor::new(Vec::new()));
+        let mut entry_names = HashSet::new();
+        start_zip_entry(&mut outzip, &mut entry_names, "media", FileOptions::default()).unwrap();
+        start_zip_entry(&mut outzip, &mut entry_names, "0", FileOptions::default()).unwrap();
+        assert!(matches!(
+            start_zip_entry(&mut outzip, &mut entry_names, "0", FileOptions::default()),
+            Err(Error::DuplicateZipEntry(name)) if name == "0"
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn template_assets_are_underscore_prefixed() {
+        let tmp_dir = TempDir::new().unwrap();
+        let font_path = tmp_dir.path().join("Roboto.ttf");
+        std::fs::write(&font_path, b"font").unwrap();
+
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        assert_eq!(package.add_template_asset(&font_path, "Roboto.ttf"), "_Roboto.ttf");
+        assert_eq!(package.add_template_asset(&font_path, "_Other.ttf"), "_Other.ttf");
+
+        let mut archive = write_to_archive(&mut package);
+        let media = media_map(&mut archive);
+        assert_eq!(media["0"], "_Roboto.ttf");
+        assert_eq!(media["1"], "_Other.ttf");
+        let mut content = Vec::new();
+        archive.by_name("0").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"font");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn template_scripts_must_exist() {
+        let tmp_dir = TempDir::new().unwrap();
+        let script_path = tmp_dir.path().join("drawing.js");
+        std::fs::write(&script_path, b"draw()").unwrap();
+
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        assert_eq!(
+            package.add_template_script(&script_path).unwrap(),
+            r#"<script src="_drawing.js"></script>"#
+        );
+        assert!(matches!(
+            package.add_template_script(tmp_dir.path().join("missing.js")),
+            Err(Error::Io(_))
+        ));
+        assert!(matches!(
+            package.add_template_script(tmp_dir.path()),
+            Err(Error::MediaIsDirectory(_))
+        ));
+        assert_eq!(media_map(&mut write_to_archive(&mut package))["0"], "_drawing.js");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn missing_media_files() {
+        let dir = TempDir::new().unwrap();
+        let present = dir.path().join("present.mp3");
+        std::fs::write(&present, b"ID3").unwrap();
+        let missing = dir.path().join("missing.mp3");
+        let missing_name = missing.to_str().unwrap().to_string();
+
+        let mut package = Package::new(
+            vec![],
+            vec![present.to_str().unwrap().to_string(), missing_name.clone()],
+        )
+        .unwrap();
+        package.add_media_file(MediaFile::new_from_file(dir.path().join("sub").join("..")));
+        package.add_media_with_name(dir.path().join("gone.png"), "gone.png");
+        package.add_media_bytes(b"", "bytes.txt");
+        let expected = [missing, dir.path().join("sub").join(".."), dir.path().join("gone.png")];
+        assert!(matches!(package.validate(), Err(Error::MediaNotFound { ref paths }) if paths == &expected));
+
+        let mut package = Package::new(vec![], vec![missing_name]).unwrap();
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::MediaNotFound { paths }) if paths.len() == 1
+        ));
+
+        let mut package = Package::new(vec![], vec![present.to_str().unwrap().to_string()]).unwrap();
+        package.validate().unwrap();
+        assert_eq!(media_map(&mut write_to_archive(&mut package))["0"], "present.mp3");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn media_directories() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a.png"), b"a").unwrap();
+        std::fs::write(dir.path().join("sub").join("b.png"), b"b").unwrap();
+
+        let media = vec![dir.path().to_str().unwrap().to_string()];
+        assert!(matches!(
+            Package::new(vec![], media),
+            Err(Error::MediaIsDirectory(path)) if path == dir.path()
+        ));
+
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        package.add_media_with_name(dir.path().join("sub"), "sub.png");
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::MediaIsDirectory(_))
+        ));
+
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        package.add_media_directory(dir.path()).unwrap();
+        let mut names: Vec<String> = media_map(&mut write_to_archive(&mut package))
+            .into_values()
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.png", "b.png"]);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn media_with_name_uses_given_name() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("logo.png");
+        std::fs::write(&path, b"png").unwrap();
+
+        let mut package = Package::new_from_memory(vec![], vec![]).unwrap();
+        package.add_media_with_name(&path, "header_logo.png");
+
+        let media = media_map(&mut write_to_archive(&mut package));
+        assert_eq!(media["0"], "header_logo.png");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn collect_media_from_fields() {
+        let tmp_dir = TempDir::new().unwrap();
+        std::fs::create_dir(tmp_dir.path().join("img")).unwrap();
+        for name in ["bonjour.mp3", "my pic.png", "img/logo.png", "paris.jpg", "unused.png", "_serif.ttf"] {
+            std::fs::write(tmp_dir.path().join(name), name).unwrap();
+        }
+        let model = basic_model().css("@font-face { font-family: Serif; src: url('_serif.ttf'); }");
+        let mut deck = Deck::new(1234, "deck", "");
+        deck.add_note(Note::new(model.clone(), vec!["Bonjour [sound:bonjour.mp3]", "<img src=\"my%20pic.png\">"]).unwrap());
+        deck.add_note(Note::new(model.clone(), vec!["<img src='img/logo.png'>", "[sound:missing.mp3]"]).unwrap());
+        deck.add_note(Note::new(model.clone(), vec!["<img src=\"paris.jpg\">", "<img src=\"https://example.com/a.png\">"]).unwrap());
+        // Files outside of the base directory are never added
+        let outside = TempDir::new().unwrap();
+        let secret = outside.path().join("secret.txt");
+        std::fs::write(&secret, "secret").unwrap();
+        let parent_ref = format!("../{}/secret.txt", outside.path().file_name().unwrap().to_str().unwrap());
+        assert!(tmp_dir.path().join(&parent_ref).is_file());
+        let absolute_ref = format!("<img src=\"{}\">", secret.to_str().unwrap());
+        let parent_ref = format!("<img src=\"{}\">", parent_ref);
+        deck.add_note(Note::new(model, vec![&absolute_ref, &parent_ref]).unwrap());
+        let mut package =
+            Package::new_from_memory(vec![deck], vec![MediaFile::new_from_bytes(b"own", "paris.jpg")]).unwrap();
+
+        let added = package.collect_media_from_fields(tmp_dir.path());
+        assert_eq!(added, ["_serif.ttf", "bonjour.mp3", "logo.png", "my pic.png"]);
+        assert!(package.collect_media_from_fields(tmp_dir.path()).is_empty());
+        let names: Vec<&str> = package.media_files.iter().map(MediaFile::name).collect();
+        assert_eq!(names.len(), 5);
+        assert_eq!(names[0], "paris.jpg");
+    }
+
+    #[test]
+    fn media_bytes_are_written_under_their_name() {
+        let mut package = Package::new_from_memory(vec![], vec![MediaFile::new_from_bytes(b"first", "a.mp3")]).unwrap();
+        package.add_media_bytes(b"speech", "tts.mp3");
+        package.add_media_file(MediaFile::new_from_bytes(b"image", "rendered.png"));
+
+        let mut archive = write_to_archive(&mut package);
+        let media = media_map(&mut archive);
+        assert_eq!(media.len(), 3);
+        assert_eq!((media["1"].as_str(), media["2"].as_str()), ("tts.mp3", "rendered.png"));
+        let mut content = Vec::new();
+        archive.by_name("1").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"speech");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn media_with_the_same_name_collide() {
+        let tmp_dir = TempDir::new().unwrap();
+        for dir in ["a", "b"] {
+            std::fs::create_dir(tmp_dir.path().join(dir)).unwrap();
+            std::fs::write(tmp_dir.path().join(dir).join("logo.png"), dir).unwrap();
+        }
+        let a = tmp_dir.path().join("a/logo.png");
+        let b = tmp_dir.path().join("b/logo.png");
+
+        let mut package = Package::new_from_memory(vec![], vec![MediaFile::new_from_file(&a)]).unwrap();
+        package.add_media_with_name(&b, "logo.png");
+        assert!(matches!(
+            package.write(Cursor::new(Vec::new())),
+            Err(Error::MediaCollision(name, first, second)) if name == "logo.png" && first == a && second == b
+        ));
+
+        let mut package = Package::new_from_memory(
+            vec![],
+            vec![MediaFile::new_from_file(&a), MediaFile::new_from_file_with_name(&b, "logo_b.png")],
+        )
+        .unwrap();
+        let media = media_map(&mut write_to_archive(&mut package));
+        assert_eq!((media["0"].as_str(), media["1"].as_str()), ("logo.png", "logo_b.png"));
+
+        // Adding the same file twice is harmless
+        let same = vec![
+            MediaFile::new_from_file(&a),
+            MediaFile::new_from_file(&a),
+            MediaFile::new_from_bytes(b"x", "x.txt"),
+            MediaFile::new_from_bytes(b"x", "x.txt"),
+        ];
+        assert!(Package::new_from_memory(vec![], same).unwrap().write(Cursor::new(Vec::new())).is_ok());
+        let different = vec![MediaFile::new_from_bytes(b"x", "x.txt"), MediaFile::new_from_bytes(b"y", "x.txt")];
+        assert!(matches!(
+            Package::new_from_memory(vec![], different).unwrap().write(Cursor::new(Vec::new())),
+            Err(Error::MediaCollision(..))
+        ));
+    }
+
+    #[test]
+    fn review_source_is_streamed_after_review_history() {
+        let entry = |id: i64| RevlogEntry {
+            id,
+            ease: 3,
+            ivl: 4,
+            last_ivl: 1,
+            factor: 2500,
+            time: 6000,
+            review_type: 1,
+            usn: -1,
+        };
+        let mut next_id = 10;
+        let card = Card::new_with_review_history(0, false, 4, 0, 4, 120, 2500, 2, 2, 0, vec![entry(1)], None)
+            .with_review_source(Box::new(move || {
+                next_id += 1;
+                (next_id <= 13).then(|| entry(next_id))
+            }));
+        let mut deck = Deck::new(1234, "Reviews", "");
+        deck.add_note(
+            Note::new(basic_model(), vec!["front", "back"])
+                .unwrap()
+                .with_cards(vec![card.clone()]),
+        );
+        let mut package = legacy_package(vec![deck]);
+        let mut apkg = Cursor::new(Vec::new());
+        package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+
+        let read = Package::read_from_reader(Cursor::new(apkg.into_inner())).unwrap();
+        let history = &read.decks[0].notes()[0].cards()[0].review_history;
+        assert_eq!(history.iter().map(|entry| entry.id).collect::<Vec<_>>(), [1, 11, 12, 13]);
+        // The source is shared with the clone and has been drained by the write
+        let mut deck = Deck::new(1234, "Reviews", "");
+        deck.add_note(Note::new(basic_model(), vec!["front", "back"]).unwrap().with_cards(vec![card]));
+        let mut package = legacy_package(vec![deck]);
+        let mut apkg = Cursor::new(Vec::new());
+        package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+        let read = Package::read_from_reader(Cursor::new(apkg.into_inner())).unwrap();
+        assert_eq!(read.decks[0].notes()[0].cards()[0].review_history.len(), 1);
+    }
+
+    #[test]
+    fn read_from_reader_keeps_collection_and_deck_options() {
+        let mut deck = Deck::new(1234, "Capitals", "");
+        deck.set_config_id(2);
+        deck.add_note(Note::new(basic_model(), vec!["Capital of France?", "Paris"]).unwrap());
+        let mut package = legacy_package(vec![deck]);
+        let conf = r#"{"nextPos": 12, "newSpread": 2, "schedVer": 2, "sched2021": true}"#;
+        let dconf = r#"{"1": {"id": 1, "name": "Default"}, "2": {"id": 2, "name": "Slow", "new": {"perDay": 7}}}"#;
+        package.set_col_data(None, Some(11), None, None, None, Some(conf.to_string()), None, None, Some(dconf.to_string()));
+        let mut apkg = Cursor::new(Vec::new());
+        package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+
+        let mut read = Package::read_from_reader(Cursor::new(apkg.into_inner())).unwrap();
+        assert_eq!(read.decks[0].config_id(), 2);
+        read.set_collection_conf(CollectionConf {
+            time_limit_secs: 600,
+            ..Default::default()
+        });
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut read));
+        let (conf, dconf, deck_conf): (String, String, String) = conn
+            .query_row("SELECT conf, dconf, decks FROM col", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        let conf: serde_json::Value = serde_json::from_str(&conf).unwrap();
+        assert_eq!((conf["nextPos"].as_i64(), conf["newSpread"].as_i64()), (Some(12), Some(2)));
+        assert_eq!((conf["schedVer"].as_i64(), conf["sched2021"].as_bool()), (Some(2), Some(true)));
+        assert_eq!(conf["timeLim"].as_i64(), Some(600));
+        let dconf: serde_json::Value = serde_json::from_str(&dconf).unwrap();
+        assert_eq!(dconf["2"]["new"]["perDay"].as_i64(), Some(7));
+        let decks: serde_json::Value = serde_json::from_str(&deck_conf).unwrap();
+        assert_eq!(decks["1234"]["conf"].as_i64(), Some(2));
+    }
+
+    #[test]
+    fn read_from_reader_round_trips_legacy_package() {
+        let review = RevlogEntry {
+            id: 1_700_000_000_000,
+            ease: 3,
+            ivl: 4,
+            last_ivl: 1,
+            factor: 2500,
+            time: 6000,
+            review_type: 1,
+            usn: -1,
+        };
+        let reviewed = Card::new_with_review_history(0, false, 1, 0, 4, 120, 2500, 2, 2, 0, vec![review], None)
+            .flags(Flag::Red);
+        let mut capitals = Deck::new(1234, "Capitals", "European capitals");
+        capitals.add_note(
+            Note::new(basic_model(), vec!["Capital of France?", r#"Paris<img src="paris.jpg">"#])
+                .unwrap()
+                .tags(["geo::europe"])
+                .flags(Flag::Blue)
+                .with_cards(vec![reviewed]),
+        );
+        capitals.add_note(
+            Note::new(cloze_model(), vec!["{{c1::Berlin}} is in {{c2::Germany}}"]).unwrap(),
+        );
+        let mut package = legacy_package(vec![capitals]);
+        package.media_files.push(MediaFile::new_from_bytes(b"jpeg", "paris.jpg"));
+        let mut apkg = Cursor::new(Vec::new());
+        package.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+
+        let mut read = Package::read_from_reader(Cursor::new(apkg.into_inner())).unwrap();
+        assert_eq!(read.decks.len(), 1);
+        let deck = &read.decks[0];
+        assert_eq!((deck.id, deck.name.as_str(), deck.description.as_str()), (1234, "Capitals", "European capitals"));
+        let notes = deck.notes();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].fields(), ["Capital of France?", r#"Paris<img src="paris.jpg">"#]);
+        assert_eq!(notes[0].get_tags(), ["geo::europe"]);
+        assert_eq!(notes[0].model().name(), basic_model().name());
+        let card = &notes[0].cards()[0];
+        assert_eq!((card.ivl, card.due, card.card_type), (Some(4), Some(120), Some(2)));
+        assert_eq!((card.flag(), notes[0].flag()), (Flag::Red, Flag::Blue));
+        assert_eq!(card.review_history.len(), 1);
+        assert_eq!(card.review_history[0].time, 6000);
+        assert!(notes[1].model().model_type_val() == ModelType::Cloze);
+        assert_eq!(
+            notes[1].cards().iter().map(|card| card.ord).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(read.media_files.len(), 1);
+        assert_eq!(read.media_files[0].name(), "paris.jpg");
+        assert_eq!(read.media_files[0].read_bytes().unwrap(), b"jpeg");
+
+        // The package that was read can be written again
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut read));
+        let (notes, revlog): (i64, i64) = conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM notes), (SELECT COUNT(*) FROM revlog)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((notes, revlog), (2, 1));
+    }
+
+    #[test]
+    fn read_from_reader_keeps_scheduling_and_review_history() {
+        let history = vec![
+            RevlogEntry::learn(1_700_000_000_000, 3, -600, 0, 0, 4000).unwrap(),
+            RevlogEntry::review(1_700_100_000_000, 1, 1, 4, 2300, 9000).unwrap(),
+            RevlogEntry::manual(1_700_200_000_000, 10, 1, 2300),
+        ];
+        let studied = Card::new_with_review_history(0, false, 5, 1, 1, 130, 2300, 3, 3, 1001, history, None)
+            .flags(Flag::Green);
+        let filtered = Card::new_with_review_history(
+            1,
+            false,
+            2,
+            0,
+            3,
+            -100_000,
+            2500,
+            2,
+            2,
+            0,
+            vec![RevlogEntry::cram(1_700_300_000_000, 3, 3, 1, 2500, 2000).unwrap()],
+            Some(r#"{"s":3.2,"d":5.1}"#.to_string()),
+        )
+        .set_deck(5678)
+        .set_original_deck(1234, 140);
+        let mut deck = Deck::new(1234, "Capitals", "");
+        deck.add_note(
+            Note::new(basic_and_reversed_card_model(), vec!["France", "Paris"])
+                .unwrap()
+                .with_cards(vec![studied, filtered]),
+        );
+        deck.add_note(Note::new(basic_model(), vec!["Peru", "Lima"]).unwrap().with_cards(vec![Card::new(0, true)]));
+        let buried = |state| Card::new(0, false).queue_state(state);
+        deck.add_note(
+            Note::new(basic_model(), vec!["Chile", "Santiago"])
+                .unwrap()
+                .with_cards(vec![buried(CardQueueState::UserBuried)]),
+        );
+        deck.add_note(
+            Note::new(basic_model(), vec!["Cuba", "Havana"])
+                .unwrap()
+                .with_cards(vec![buried(CardQueueState::SchedBuried)]),
+        );
+        let mut package = legacy_package(vec![deck]);
+        package.add_filtered_deck(FilteredDeck::new(5678, "Cram").term(FilteredDeckTerm::new("is:due", 100, 6)));
+
+        let write = |package: &mut Package, timestamp: f64| {
+            let mut apkg = Cursor::new(Vec::new());
+            package.write_timestamp(&mut apkg, timestamp).unwrap();
+            apkg.into_inner()
+        };
+        let rows = |apkg: Vec<u8>, table: &str| -> Vec<Vec<rusqlite::types::Value>> {
+            let (_db_file, conn) = open_collection(&mut ZipArchive::new(Cursor::new(apkg)).unwrap());
+            let mut stmt = conn.prepare(&format!("SELECT * FROM {} ORDER BY id", table)).unwrap();
+            let columns = stmt.column_count();
+            stmt.query_map([], |row| (0..columns).map(|i| row.get(i)).collect())
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let apkg = write(&mut package, 1_700_000_000.0);
+        let mut read = Package::read_from_reader(Cursor::new(apkg.clone())).unwrap();
+        let queue_states: Vec<CardQueueState> =
+            read.decks[0].notes().iter().map(|note| note.cards()[0].get_queue_state()).collect();
+        assert_eq!(
+            queue_states,
+            [
+                CardQueueState::Normal,
+                CardQueueState::Suspended,
+                CardQueueState::UserBuried,
+                CardQueueState::SchedBuried
+            ]
+        );
+        let written_again = write(&mut read, 1_800_000_000.0);
+        for table in ["cards", "revlog"] {
+            assert_eq!(rows(written_again.clone(), table), rows(apkg.clone(), table), "{}", table);
+        }
+        assert_eq!(rows(apkg.clone(), "revlog").len(), 4);
+        let dids: Vec<Vec<rusqlite::types::Value>> = rows(apkg, "cards").into_iter().map(|row| row[2..3].to_vec()).collect();
+        assert!(dids.contains(&vec![rusqlite::types::Value::Integer(5678)]));
+    }
+
+    #[test]
+    fn read_from_reader_reads_note_types_and_decks_from_tables() {
+        let model = basic_and_reversed_card_model().css(".card { color: red; }");
+        let mut verbs = Deck::new(1235, "Spanish::Verbs", "Irregular verbs");
+        verbs.set_config_id(42);
+        verbs.add_note(
+            Note::new(model.clone(), vec!["ser", "to be <img src=\"ser.png\">"])
+                .unwrap()
+                .guid("ser")
+                .with_cards(vec![Card::new_with_review_history(0, false, 3, 0, 4, 120, 2500, 2, 2, 0, vec![], None)]),
+        );
+        verbs.add_note(Note::new(cloze_model(), vec!["{{c1::ir}} is to go"]).unwrap());
+        let mut package = Package::new_from_memory(vec![verbs], vec![MediaFile::new_from_bytes(b"png", "ser.png")]).unwrap();
+        package.set_scheduler_version(SchedulerVersion::V3);
+        package.add_deck_config_entry(DeckConfigEntry {
+            id: 42,
+            name: "Verbs".to_string(),
+            mtime_secs: 0,
+            usn: -1,
+            config_blob: vec![0x08, 0x01],
+        });
+        package.add_filtered_deck(FilteredDeck::new(5678, "Cram").term(FilteredDeckTerm::new("is:due", 100, 6)));
+
+        let write = |package: &mut Package, timestamp: f64| {
+            let mut apkg = Cursor::new(Vec::new());
+            package.write_timestamp(&mut apkg, timestamp).unwrap();
+            apkg.into_inner()
+        };
+        let apkg = write(&mut package, 1_700_000_000.0);
+        let mut read = Package::read_from_reader(Cursor::new(apkg.clone())).unwrap();
+        let decks: Vec<(&str, usize, i64)> = read
+            .decks
+            .iter()
+            .map(|deck| (deck.name.as_str(), deck.notes().len(), deck.config_id()))
+            .collect();
+        assert_eq!(decks, [("Spanish::Verbs", 2, 42), ("Spanish", 0, 1)]);
+        assert_eq!(read.decks[0].description, "Irregular verbs");
+        let notes = read.decks[0].notes();
+        assert_eq!(notes[0].get_guid(), "ser");
+        assert_eq!(notes[0].fields(), ["ser", "to be <img src=\"ser.png\">"]);
+        assert_eq!(notes[0].model().get_css(), model.get_css());
+        assert_eq!(notes[0].model().templates().len(), 2);
+        assert_eq!(notes[0].cards()[0].get_queue_state(), CardQueueState::Normal);
+        assert!(notes[1].model().is_cloze());
+        assert_eq!(read.filtered_decks[0].name, "Cram");
+        assert_eq!(read.scheduler_version, Some(SchedulerVersion::V3));
+        assert_eq!(read.media_files[0].name(), "ser.png");
+
+        let written_again = write(&mut read, 1_800_000_000.0);
+        let rows = |apkg: Vec<u8>, query: &str| -> Vec<Vec<rusqlite::types::Value>> {
+            let (_db_file, conn) = open_collection(&mut ZipArchive::new(Cursor::new(apkg)).unwrap());
+            let mut stmt = conn.prepare(query).unwrap();
+            let columns = stmt.column_count();
+            stmt.query_map([], |row| (0..columns).map(|i| row.get(i)).collect())
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        for query in [
+            "SELECT * FROM notes ORDER BY id",
+            "SELECT * FROM cards ORDER BY id",
+            "SELECT id, name, config FROM notetypes ORDER BY id",
+            "SELECT * FROM fields ORDER BY ntid, ord",
+            "SELECT ntid, ord, name, config FROM templates ORDER BY ntid, ord",
+            "SELECT id, name, kind FROM decks ORDER BY id",
+            "SELECT * FROM deck_config",
+            "SELECT key, val FROM config ORDER BY key",
+        ] {
+            assert_eq!(rows(written_again.clone(), query), rows(apkg.clone(), query), "{}", query);
+        }
+    }
+
+    #[test]
+    fn read_from_reader_keeps_note_rows() {
+        let mut deck = Deck::new(1234, "Capitals", "");
+        deck.add_note(Note::new(basic_model(), vec!["<b>France</b>", "Paris"]).unwrap());
+        deck.add_note(Note::new(basic_model(), vec!["42", "The answer"]).unwrap());
+        let mut package = legacy_package(vec![deck]);
+        let (db_file, conn) = open_collection(&mut write_to_archive(&mut package));
+        conn.execute_batch(
+            "UPDATE notes SET mod = 1600000000, usn = 12, tags = ' europe geo ', flags = 3, data = 'extra', \
+             sfld = 'France (edited)', csum = 1234 WHERE sfld = 'France';
+             UPDATE notes SET usn = 0, flags = 1 WHERE sfld = 42;",
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut apkg = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut apkg);
+        zip.start_file("collection.anki2", FileOptions::default()).unwrap();
+        zip.write_all(&std::fs::read(&db_file).unwrap()).unwrap();
+        zip.start_file("media", FileOptions::default()).unwrap();
+        zip.write_all(b"{}").unwrap();
+        zip.finish().unwrap();
+        drop(zip);
+        let apkg = apkg.into_inner();
+
+        let notes = |apkg: Vec<u8>| -> Vec<Vec<rusqlite::types::Value>> {
+            let (_db_file, conn) = open_collection(&mut ZipArchive::new(Cursor::new(apkg)).unwrap());
+            let mut stmt = conn.prepare("SELECT * FROM notes ORDER BY id").unwrap();
+            let columns = stmt.column_count();
+            stmt.query_map([], |row| (0..columns).map(|i| row.get(i)).collect())
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let mut read = Package::read_from_reader(Cursor::new(apkg.clone())).unwrap();
+        let mut written_again = Cursor::new(Vec::new());
+        read.write_timestamp(&mut written_again, 1_800_000_000.0).unwrap();
+        let original = notes(apkg);
+        assert_eq!(notes(written_again.into_inner()), original);
+        assert_eq!(original[0][10], rusqlite::types::Value::Text("extra".to_string()));
+    }
+
+    #[test]
+    fn diff_against_released_package() {
+        let note = |front: &str, back: &str, guid: &str| {
+            Note::new(basic_model(), vec![front, back]).unwrap().guid(guid)
+        };
+        let mut capitals = Deck::new(1234, "Capitals", "");
+        capitals.add_note(note("France", "Paris", "fr"));
+        capitals.add_note(note("Peru", "Lima", "pe"));
+        capitals.add_note(note("Chile", "<img src='santiago.jpg'>", "cl"));
+        let mut rivers = Deck::new(1235, "Rivers", "");
+        rivers.add_note(note("Longest river?", "Nile", "nile"));
+        let mut released = legacy_package(vec![capitals, rivers]);
+        let mut apkg = Cursor::new(Vec::new());
+        released.write_timestamp(&mut apkg, 1_700_000_000.0).unwrap();
+        let base = Package::read_from_reader(Cursor::new(apkg.into_inner())).unwrap();
+
+        let mut capitals = Deck::new(1234, "Capitals", "");
+        capitals.add_note(note("France", "Paris", "fr"));
+        capitals.add_note(note("Peru", "Lima", "pe").with_tag("south-america"));
+        capitals.add_note(note("Cuba", "<img src='havana.jpg'>", "cu"));
+        let mut rivers = Deck::new(1235, "Rivers", "");
+        rivers.add_note(note("Longest river?", "Nile", "nile"));
+        let media = vec![
+            MediaFile::new_from_bytes(b"havana", "havana.jpg"),
+            MediaFile::new_from_bytes(b"santiago", "santiago.jpg"),
+        ];
+        let current = Package::new_from_memory(vec![capitals, rivers], media).unwrap();
+
+        let mut diff = current.diff_against(&base).unwrap();
+        assert_eq!(diff.decks.len(), 1);
+        let guids: Vec<String> = diff.decks[0].notes().iter().map(Note::get_guid).collect();
+        assert_eq!(guids, ["pe", "cu"]);
+        let media: Vec<&str> = diff.media_files.iter().map(MediaFile::name).collect();
+        assert_eq!(media, ["havana.jpg"]);
+        let chile = base.decks[0].notes().iter().find(|note| note.get_guid() == "cl").unwrap();
+        let graves: Vec<(i64, i32)> = diff.graves.iter().map(|grave| (grave.oid, grave.gtype)).collect();
+        assert_eq!(
+            graves,
+            [(chile.cards()[0].custom_card_id.unwrap(), 0), (chile.pinned_id().unwrap(), 1)]
+        );
+        let (_db_file, conn) = open_collection(&mut write_to_archive(&mut diff));
+        let graves: i64 = conn.query_row("SELECT count(*) FROM graves", [], |row| row.get(0)).unwrap();
+        assert_eq!(graves, 2);
+
+        // Changing the definition of a model changes all of its notes
+        let styled = Note::new(basic_model().css(".card { color: red; }"), vec!["Longest river?", "Nile"])
+            .unwrap()
+            .guid("nile");
+        let mut rivers = Deck::new(1235, "Rivers", "");
+        rivers.add_note(styled);
+        let diff = Package::new_from_memory(vec![rivers], vec![]).unwrap().diff_against(&base).unwrap();
+        assert_eq!(diff.decks[0].notes().len(), 1);
+        assert_eq!(diff.graves.len(), 6);
+    }
+
+    #[test]
+    fn read_from_reader_rejects_archives_without_collection() {
+        let mut out = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut out);
+        zip.start_file("media", FileOptions::default()).unwrap();
+        zip.write_all(b"{}").unwrap();
+        zip.finish().unwrap();
+        drop(zip);
+        assert!(matches!(
+            Package::read_from_reader(out),
+            Err(Error::InvalidPackage(_))
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::Error;
+
+/// Encodes protobuf messages, which Anki uses for the blobs in the `notetypes`, `fields`, `templates`
+/// and `decks` tables since collection version 16
+///
+/// Fields with the default value of their type are left out, like proto3 does.
+#[derive(Default)]
+pub(crate) struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+impl ProtoWriter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint((u64::from(field) << 3) | wire_type);
+    }
+
+    /// Writes an `int32`, `int64`, `uint32`, `uint64` or enum field
+    pub(crate) fn int(mut self, field: u32, value: i64) -> Self {
+        if value != 0 {
+            self.key(field, VARINT);
+            self.varint(value as u64);
+        }
+        self
+    }
+
+    pub(crate) fn bool(self, field: u32, value: bool) -> Self {
+        self.int(field, i64::from(value))
+    }
+
+    pub(crate) fn string(self, field: u32, value: &str) -> Self {
+        if value.is_empty() {
+            return self;
+        }
+        self.message(field, value.as_bytes())
+    }
+
+    /// Writes an embedded message, even an empty one so that it is set in a `oneof`
+    pub(crate) fn message(mut self, field: u32, message: &[u8]) -> Self {
+        self.key(field, LENGTH_DELIMITED);
+        self.varint(message.len() as u64);
+        self.buf.extend_from_slice(message);
+        self
+    }
+
+    /// Writes a packed repeated `uint32` field
+    pub(crate) fn packed_uints(self, field: u32, values: &[u32]) -> Self {
+        if values.is_empty() {
+            return self;
+        }
+        let mut packed = ProtoWriter::new();
+        values.iter().for_each(|&value| packed.varint(u64::from(value)));
+        self.message(field, &packed.buf)
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Value of a field of a decoded protobuf message
+#[derive(Clone, Copy)]
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// A `fixed32`, `fixed64`, `float` or `double`, which Anki doesn't use in the blobs that are read
+    Fixed,
+}
+
+/// Decodes the protobuf messages written by [`ProtoWriter`] and by Anki
+///
+/// Missing fields read as the default value of their type, like proto3 does. For fields that occur
+/// more than once, the last value counts, except with [`ProtoReader::messages`].
+pub(crate) struct ProtoReader<'a> {
+    fields: Vec<(u32, ProtoValue<'a>)>,
+}
+
+impl<'a> ProtoReader<'a> {
+    /// Returns `Err` if `buf` is not a valid protobuf message
+    pub(crate) fn new(mut buf: &'a [u8]) -> Result<Self, Error> {
+        fn invalid() -> Error {
+            Error::InvalidPackage("invalid protobuf message in the collection".to_string())
+        }
+        fn varint(buf: &mut &[u8]) -> Result<u64, Error> {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let (&byte, rest) = buf.split_first().ok_or_else(invalid)?;
+                *buf = rest;
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    return Ok(value);
+                }
+            }
+            Err(invalid())
+        }
+        fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+            if buf.len() < len {
+                return Err(invalid());
+            }
+            let (taken, rest) = buf.split_at(len);
+            *buf = rest;
+            Ok(taken)
+        }
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf)?;
+            let field = u32::try_from(key >> 3).map_err(|_| invalid())?;
+            let value = match key & 0x7 {
+                VARINT => ProtoValue::Varint(varint(&mut buf)?),
+                LENGTH_DELIMITED => {
+                    let len = usize::try_from(varint(&mut buf)?).map_err(|_| invalid())?;
+                    ProtoValue::Bytes(take(&mut buf, len)?)
+                }
+                FIXED64 => take(&mut buf, 8).map(|_| ProtoValue::Fixed)?,
+                FIXED32 => take(&mut buf, 4).map(|_| ProtoValue::Fixed)?,
+                _ => return Err(invalid()),
+            };
+            fields.push((field, value));
+        }
+        Ok(Self { fields })
+    }
+
+    fn last(&self, field: u32) -> Option<ProtoValue<'a>> {
+        self.fields.iter().rev().find(|&&(number, _)| number == field).map(|&(_, value)| value)
+    }
+
+    /// Reads an `int32`, `int64`, `uint32`, `uint64` or enum field
+    pub(crate) fn int(&self, field: u32) -> i64 {
+        match self.last(field) {
+            Some(ProtoValue::Varint(value)) => value as i64,
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn bool(&self, field: u32) -> bool {
+        self.int(field) != 0
+    }
+
+    pub(crate) fn string(&self, field: u32) -> String {
+        self.message(field)
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Reads an embedded message, `None` if it isn't set
+    pub(crate) fn message(&self, field: u32) -> Option<&'a [u8]> {
+        match self.last(field) {
+            Some(ProtoValue::Bytes(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Reads a repeated embedded message field
+    pub(crate) fn messages(&self, field: u32) -> Vec<&'a [u8]> {
+        self.fields
+            .iter()
+            .filter_map(|&(number, value)| match value {
+                ProtoValue::Bytes(bytes) if number == field => Some(bytes),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_like_protobuf() {
+        assert_eq!(ProtoWriter::new().int(1, 150).finish(), [0x08, 0x96, 0x01]);
+        assert_eq!(ProtoWriter::new().string(2, "testing").finish(), b"\x12\x07testing");
+        assert_eq!(ProtoWriter::new().packed_uints(4, &[3, 270]).finish(), [0x22, 0x03, 0x03, 0x8e, 0x02]);
+        assert_eq!(ProtoWriter::new().int(1, -1).finish().len(), 11);
+        assert!(ProtoWriter::new().int(1, 0).bool(2, false).string(3, "").packed_uints(4, &[]).finish().is_empty());
+        assert_eq!(ProtoWriter::new().message(1, &[]).finish(), [0x0a, 0x00]);
+    }
+
+    #[test]
+    fn decodes_what_is_encoded() {
+        let inner = ProtoWriter::new().string(1, "is:due").int(2, 100).finish();
+        let buf = ProtoWriter::new()
+            .int(1, 150)
+            .int(2, -1)
+            .bool(3, true)
+            .string(4, "testing")
+            .message(5, &inner)
+            .message(5, &[])
+            .finish();
+        let reader = ProtoReader::new(&buf).unwrap();
+        assert_eq!((reader.int(1), reader.int(2), reader.bool(3)), (150, -1, true));
+        assert_eq!(reader.string(4), "testing");
+        assert_eq!((reader.int(6), reader.string(6), reader.message(6)), (0, String::new(), None));
+        let messages = reader.messages(5);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(ProtoReader::new(messages[0]).unwrap().string(1), "is:due");
+
+        // A fixed64 field is skipped
+        let reader = ProtoReader::new(&[0x09, 1, 2, 3, 4, 5, 6, 7, 8, 0x10, 0x07]).unwrap();
+        assert_eq!(reader.int(2), 7);
+        assert!(ProtoReader::new(&[0x12, 0x05, b'a']).is_err());
+        assert!(ProtoReader::new(&[0x08]).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use fancy_regex::{Captures, Regex};
+use once_cell::sync::Lazy;
+use std::ops::Range;
+
+static SOUND_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\[sound:([^\]]*)\]").expect("static regex"));
+
+static IMG_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)<img\b[^>]*>").expect("static regex"));
+
+static ALT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)\balt\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).expect("static regex")
+});
+
+// <style> and <script> are removed together with their content
+static HTML_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?si)<style\b.*?</style>|<script\b.*?</script>|<!--.*?-->|<[^>]*>")
+        .expect("static regex")
+});
+
+// Media tags with the file name in `src` (or `data` for `<object>`), like Anki's `HTML_MEDIA_TAGS`
+static MEDIA_TAG_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r#"(?si)<\b(?:img|audio|video|object)\b(?:[^>"']|"[^"]*?"|'[^']*?')*?\b(?:src|data)\b=(?:"([^"]+?)"[^>]*>|'([^']+?)'[^>]*>|([^ >]+?)(?: [^>]*>|>))"#,
+    )
+    .expect("static regex")
+});
+
+static ENTITY_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").expect("static regex"));
+
+static SRC_REF_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)<(?:img|audio|video|source)\b[^>]*?\ssrc\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>"']+))"#)
+        .expect("static regex")
+});
+
+/// Returns the byte ranges of the media references in `field`, the filenames in `[sound:...]` and in
+/// the `src` of `<img>`, `<audio>`, `<video>` and `<source>` tags, in the order they appear
+pub(crate) fn media_ref_ranges(field: &str) -> Vec<Range<usize>> {
+    let sounds = SOUND_REGEX
+        .captures_iter(field)
+        .flatten()
+        .filter_map(|captures| captures.get(1).map(|m| m.range()));
+    let sources = SRC_REF_REGEX.captures_iter(field).flatten().filter_map(|captures| {
+        captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .or_else(|| captures.get(3))
+            .map(|m| m.range())
+    });
+    let mut ranges: Vec<Range<usize>> = sounds.chain(sources).filter(|range| !range.is_empty()).collect();
+    ranges.sort_by_key(|range| range.start);
+    ranges
+}
+
+/// Replaces every media reference in `field` for which `rewrite` returns a new name
+pub(crate) fn rewrite_media_refs(field: &str, mut rewrite: impl FnMut(&str) -> Option<String>) -> String {
+    let mut rewritten = String::with_capacity(field.len());
+    let mut last_end = 0;
+    for range in media_ref_ranges(field) {
+        if let Some(name) = rewrite(&field[range.clone()]) {
+            rewritten.push_str(&field[last_end..range.start]);
+            rewritten.push_str(&name);
+            last_end = range.end;
+        }
+    }
+    rewritten.push_str(&field[last_end..]);
+    rewritten
+}
+
+/// Decodes `%XX` escapes like browsers do for URLs, invalid escapes are kept as they are
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Strips the HTML and media references from a field like Anki does before computing its checksum or
+/// showing it as plain text:
+/// * `[sound:...]` references are dropped
+/// * `<img>` tags are replaced with their `alt` text, or nothing if they don't have one
+/// * all other tags (and the content of `<style>` and `<script>`) are removed
+/// * HTML entities are decoded
+///
+/// ```rust
+/// use genanki_rs::strip_html_media;
+///
+/// assert_eq!(
+///     strip_html_media(r#"<b>Paris</b> <img src="paris.jpg" alt="Eiffel tower">[sound:paris.mp3] &amp; more"#),
+///     "Paris Eiffel tower & more"
+/// );
+/// ```
+pub fn strip_html_media(field: &str) -> String {
+    let without_sounds = SOUND_REGEX.replace_all(field, "");
+    let with_alt_texts = IMG_REGEX.replace_all(&without_sounds, |img: &Captures| {
+        ALT_REGEX
+            .captures(&img[0])
+            .ok()
+            .flatten()
+            .and_then(|alt| alt.get(1).or_else(|| alt.get(2)).or_else(|| alt.get(3)))
+            .map_or_else(String::new, |alt| alt.as_str().to_string())
+    });
+    let without_tags = HTML_REGEX.replace_all(&with_alt_texts, "");
+    decode_entities(&without_tags)
+}
+
+/// Strips the HTML from a field like Anki's `strip_html_preserving_media_filenames`, which gives the sort
+/// field (`sfld`) and the text of the checksum (`csum`) of a note:
+/// * media tags are replaced with their file name surrounded by spaces
+/// * all other tags (and the content of `<style>` and `<script>`) are removed
+/// * HTML entities are decoded
+///
+/// Unlike [`strip_html_media`], `[sound:...]` references and `alt` texts are kept as they are.
+pub(crate) fn strip_html_preserving_media_filenames(field: &str) -> String {
+    let with_file_names = MEDIA_TAG_REGEX.replace_all(field, |tag: &Captures| {
+        let name = tag.get(1).or_else(|| tag.get(2)).or_else(|| tag.get(3)).map_or("", |name| name.as_str());
+        format!(" {name} ")
+    });
+    let without_tags = HTML_REGEX.replace_all(&with_file_names, "");
+    decode_entities(&without_tags)
+}
+
+/// Decodes the common named HTML entities and all numeric ones, unknown entities are kept as they are
+pub(crate) fn decode_entities(text: &str) -> String {
+    ENTITY_REGEX
+        .replace_all(text, |entity: &Captures| {
+            let name = &entity[1];
+            let decoded = match name {
+                "nbsp" => Some(' '),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if name.starts_with("#x") || name.starts_with("#X") => {
+                    u32::from_str_radix(&name[2..], 16).ok().and_then(char::from_u32)
+                }
+                _ if name.starts_with('#') => name[1..].parse().ok().and_then(char::from_u32),
+                _ => None,
+            };
+            decoded.map_or_else(|| entity[0].to_string(), String::from)
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sounds_are_dropped() {
+        assert_eq!(strip_html_media("[sound:a.mp3]word[sound:b.ogg]"), "word");
+    }
+
+    #[test]
+    fn images_are_replaced_with_alt_text() {
+        assert_eq!(strip_html_media(r#"<img src="a.png">"#), "");
+        assert_eq!(strip_html_media(r#"<IMG alt='a cat' src="cat.png"/>"#), "a cat");
+        assert_eq!(strip_html_media(r#"<img src="dog.png" alt=dog>"#), "dog");
+    }
+
+    #[test]
+    fn tags_style_and_script_are_removed() {
+        assert_eq!(
+            strip_html_media("<div>a<br>b</div><style>.x { color: red }</style><!-- c --><script>d()</script>"),
+            "ab"
+        );
+    }
+
+    #[test]
+    fn media_refs_are_found_and_rewritten() {
+        let field = r#"[sound:a.mp3]<img data-src="x" src="b.png"> <IMG SRC='c d.jpg'><audio src=e.ogg></audio>"#;
+        let refs: Vec<&str> = media_ref_ranges(field).into_iter().map(|range| &field[range]).collect();
+        assert_eq!(refs, ["a.mp3", "b.png", "c d.jpg", "e.ogg"]);
+        assert_eq!(
+            rewrite_media_refs(field, |name| (name != "b.png").then(|| name.to_uppercase())),
+            r#"[sound:A.MP3]<img data-src="x" src="b.png"> <IMG SRC='C D.JPG'><audio src=E.OGG></audio>"#
+        );
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("my%20pic%2Epng"), "my pic.png");
+        assert_eq!(percent_decode("caf%C3%A9%zz%2"), "café%zz%2");
+    }
+
+    #[test]
+    fn media_file_names_are_preserved() {
+        assert_eq!(strip_html_preserving_media_filenames(r#"<img src="paris.jpg">"#), " paris.jpg ");
+        assert_eq!(
+            strip_html_preserving_media_filenames(r#"<b>a</b><IMG alt="x>y" src='b c.png'/><audio src=d.ogg>[sound:e.mp3]"#),
+            "a b c.png  d.ogg [sound:e.mp3]"
+        );
+        assert_eq!(strip_html_preserving_media_filenames("<object data=\"f.svg\"></object>&amp;"), " f.svg &");
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(
+            strip_html_media("&lt;a&gt; &amp;&nbsp;&quot;b&quot; &#39;c&#x27; &unknown;"),
+            "<a> & \"b\" 'c' &unknown;"
+        );
+    }
+}
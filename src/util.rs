@@ -1,15 +1,115 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use sha2::{Digest, Sha256};
 
-pub fn guid_for(fields: &Vec<String>) -> String {
-    fields
-        .iter()
-        .map(|f| u64::to_string(&hash_str(&f)))
-        .collect()
+const BASE91_TABLE: &[u8; 91] =
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&()*+,-./:;<=>?@[]^_`{|}~";
+
+/// Computes the GUID genanki gives a note with the given fields, which notes get by default
+///
+/// This is the `guid_for` algorithm of Python genanki: the first 8 bytes of the SHA-256 of the fields
+/// joined with `__`, in the base 91 encoding Anki uses for GUIDs. The same fields get the same GUID with
+/// both libraries.
+///
+/// Example:
+/// ```rust
+/// use genanki_rs::guid_for;
+///
+/// assert_eq!(guid_for(&["What is the capital of France?", "Paris"]), "v#rAo?Y]yM");
+/// ```
+pub fn guid_for<S: AsRef<str>>(fields: &[S]) -> String {
+    let joined = fields.iter().map(AsRef::as_ref).collect::<Vec<_>>().join("__");
+    let digest = Sha256::digest(joined.as_bytes());
+    let mut hash = u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 has 32 bytes"));
+    let mut reversed = Vec::new();
+    while hash > 0 {
+        reversed.push(BASE91_TABLE[(hash % 91) as usize]);
+        hash /= 91;
+    }
+    reversed.iter().rev().map(|&c| char::from(c)).collect()
+}
+
+/// Small seedable pseudo random number generator (SplitMix64), used where results have to be reproducible
+pub(crate) struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Shuffles `items` in place (Fisher-Yates)
+    pub(crate) fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// Converts seconds since the Unix epoch (UTC) to a zip entry timestamp, clamped to the years
+/// 1980 to 2107 a zip timestamp can hold
+pub(crate) fn zip_date_time(unix_secs: i64) -> zip::DateTime {
+    let days = unix_secs.div_euclid(86_400);
+    let secs_of_day = unix_secs.rem_euclid(86_400);
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    match year {
+        ..1980 => zip::DateTime::default(),
+        2108.. => zip::DateTime::from_date_and_time(2107, 12, 31, 23, 59, 58).expect("valid date"),
+        _ => zip::DateTime::from_date_and_time(
+            year as u16,
+            month,
+            day,
+            (secs_of_day / 3600) as u8,
+            (secs_of_day / 60 % 60) as u8,
+            (secs_of_day % 60) as u8,
+        )
+        .expect("valid date"),
+    }
+}
+
+/// Parses an ISO-8601 calendar date (`2025-01-15`) into days since the Unix epoch
+pub(crate) fn parse_iso_date(date: &str) -> Option<i64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    days_from_civil(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)
 }
 
-fn hash_str(to_hash: &str) -> u64 {
-    let mut s = DefaultHasher::new();
-    to_hash.hash(&mut s);
-    s.finish()
+/// Returns the days since the Unix epoch of a calendar date, `None` if there is no such date
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    let is_leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if is_leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    // Days from civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
 }